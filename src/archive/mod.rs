@@ -0,0 +1,485 @@
+use std::fs::{create_dir_all, File, Metadata};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{Datelike, Local, Timelike};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::*;
+use zip::{AesMode, ZipArchive, ZipWriter};
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+
+use crate::compression::{CompressionMethod, FileCompression};
+use crate::error::Error;
+use crate::manifest::MANIFEST_NAME;
+use crate::zip_name::DIRECTORY_EXTENSION;
+
+//How the backups of a system are written
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    #[serde(rename = "zip")]
+    #[default]
+    Zip,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    //A plain copy of the files in a folder
+    #[serde(rename = "directory")]
+    Directory,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 4] = [ArchiveFormat::Zip, ArchiveFormat::TarGz, ArchiveFormat::TarZst, ArchiveFormat::Directory];
+
+    //Extension of the backup files (or folders) in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Directory => DIRECTORY_EXTENSION,
+        }
+    }
+
+    //Finds out the format of a backup by the end of its name. Partial backups have the format of the backup they become
+    pub fn of_path(path: &Path) -> Option<ArchiveFormat> {
+        let file_name = path.file_name()?.to_str()?;
+        let file_name = file_name.strip_suffix(".partial").unwrap_or(file_name);
+        ArchiveFormat::ALL.iter()
+            .find(|format| file_name.strip_suffix(format.extension()).map(|rest| rest.ends_with('.')).unwrap_or(false))
+            .copied()
+    }
+
+    //The method a tar in this format is compressed with as a whole. None for the formats that compress every file on its own or not at all
+    pub fn stream_method(&self) -> Option<CompressionMethod> {
+        match self {
+            ArchiveFormat::TarGz => Some(CompressionMethod::Deflate),
+            ArchiveFormat::TarZst => Some(CompressionMethod::Zstd),
+            ArchiveFormat::Zip | ArchiveFormat::Directory => None,
+        }
+    }
+
+    //Creates a new empty archive in this format at the path. stream_level is the level of the compression of a whole tar, see stream_method
+    //With a passphrase the content of every file but the manifest is encrypted with AES-256, which only zips support
+    pub fn create_writer(&self, path: &Path, stream_level: Option<i64>, passphrase: Option<&str>) -> Result<Box<dyn ArchiveWriter>, Error> {
+        if passphrase.is_some() && *self != ArchiveFormat::Zip {
+            return Err(Error::new_s(format!("{} backups can't be encrypted, only zips can", self.extension())));
+        }
+        Ok(match self {
+            ArchiveFormat::Zip => Box::new(ZipArchiveWriter {
+                zip: ZipWriter::new(File::create(path)?),
+                passphrase: passphrase.map(|p| p.to_string()),
+            }),
+            ArchiveFormat::TarGz => {
+                let level = stream_level.map(|level| flate2::Compression::new(level as u32)).unwrap_or_default();
+                Box::new(TarArchiveWriter {
+                    builder: tar::Builder::new(GzEncoder::new(File::create(path)?, level)),
+                    finish_stream: |encoder| encoder.finish(),
+                })
+            }
+            ArchiveFormat::TarZst => Box::new(TarArchiveWriter {
+                builder: tar::Builder::new(zstd::Encoder::new(File::create(path)?, stream_level.unwrap_or(0) as i32)?),
+                finish_stream: |encoder| encoder.finish(),
+            }),
+            ArchiveFormat::Directory => {
+                create_dir_all(path)?;
+                Box::new(DirectoryArchiveWriter {
+                    root: path.to_path_buf(),
+                })
+            }
+        })
+    }
+}
+
+//What an archive keeps of a file besides its name and content, so it gets them back when it is extracted
+#[derive(Debug, Clone, Copy)]
+pub struct FileAttributes {
+    pub modified: SystemTime,
+    //Unix permissions like 0o644
+    pub mode: u32,
+}
+
+impl FileAttributes {
+    //Permissions of files whose own ones are unknown, like the ones of other systems than unix
+    const DEFAULT_MODE: u32 = 0o644;
+
+    //The attributes of a file in the source
+    pub fn of(metadata: &Metadata) -> FileAttributes {
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777;
+        #[cfg(not(unix))]
+        let mode = Self::DEFAULT_MODE;
+        FileAttributes {
+            modified: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            mode,
+        }
+    }
+
+    //The attributes of a file that was last modified at the given time and has the default permissions, like the manifest or a file of a snapshot
+    pub fn modified_at(modified: SystemTime) -> FileAttributes {
+        FileAttributes {
+            modified,
+            mode: Self::DEFAULT_MODE,
+        }
+    }
+}
+
+//Writes the entries of a backup in one of the archive formats
+pub trait ArchiveWriter {
+    //Adds a folder with the name relative to the archive root
+    fn add_dir(&mut self, name: &str) -> Result<(), Error>;
+    //Adds a file with the given size, whose content is copied through the buffer. Only zips compress every file on its own, the other formats ignore compression
+    fn add_file(&mut self, name: &str, size: u64, attributes: FileAttributes, compression: FileCompression, content: &mut dyn Read, buffer: &mut [u8]) -> Result<(), Error>;
+    //Completes the archive. Nothing can be added afterwards
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+//Copies everything from content to the writer through the buffer, so no more than the buffer size is held in memory
+fn copy_through_buffer(content: &mut dyn Read, writer: &mut dyn Write, buffer: &mut [u8]) -> Result<u64, Error> {
+    let mut copied = 0u64;
+    loop {
+        let read = match content.read(buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+}
+
+struct ZipArchiveWriter {
+    zip: ZipWriter<File>,
+    passphrase: Option<String>,
+}
+
+impl ZipArchiveWriter {
+    //Zips store the local time, to the even second, between 1980 and 2107. Other times are stored as the start of 1980
+    fn zip_time(time: SystemTime) -> zip::DateTime {
+        let time = chrono::DateTime::<Local>::from(time);
+        zip::DateTime::from_date_and_time(time.year().clamp(0, u16::MAX as i32) as u16, time.month() as u8, time.day() as u8, time.hour() as u8, time.minute() as u8, time.second() as u8)
+            .unwrap_or_default()
+    }
+
+    fn options(compression: FileCompression) -> SimpleFileOptions {
+        let method = match compression.method {
+            CompressionMethod::Stored => zip::CompressionMethod::Stored,
+            CompressionMethod::Deflate => zip::CompressionMethod::Deflated,
+            CompressionMethod::Bzip2 => zip::CompressionMethod::Bzip2,
+            CompressionMethod::Zstd => zip::CompressionMethod::Zstd,
+        };
+        SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(compression.level)
+            .unix_permissions(0o755)
+    }
+}
+
+impl ArchiveWriter for ZipArchiveWriter {
+    fn add_dir(&mut self, name: &str) -> Result<(), Error> {
+        self.zip.add_directory(name, Self::options(FileCompression::default()))?;
+        Ok(())
+    }
+    fn add_file(&mut self, name: &str, size: u64, attributes: FileAttributes, compression: FileCompression, content: &mut dyn Read, buffer: &mut [u8]) -> Result<(), Error> {
+        //Files of 4 GB and more need the zip64 extension
+        let options = Self::options(compression)
+            .large_file(size >= u32::MAX as u64)
+            .unix_permissions(attributes.mode)
+            .last_modified_time(Self::zip_time(attributes.modified));
+        //The manifest stays readable, so backups can be listed and chained without the passphrase. File names are never encrypted in a zip anyway
+        //This leaks the hashes, the src and who made the backup, which the help and the README tell the users about
+        let options = match self.passphrase.as_deref() {
+            Some(passphrase) if name != MANIFEST_NAME => options.with_aes_encryption(AesMode::Aes256, passphrase),
+            _ => options,
+        };
+        self.zip.start_file(name, options)?;
+        copy_through_buffer(content, &mut self.zip, buffer)?;
+        Ok(())
+    }
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        self.zip.finish()?;
+        Ok(())
+    }
+}
+
+//A tar archive compressed by the stream W
+struct TarArchiveWriter<W: Write> {
+    builder: tar::Builder<W>,
+    //Writes the end of the compressed stream
+    finish_stream: fn(W) -> std::io::Result<File>,
+}
+
+impl<W: Write> TarArchiveWriter<W> {
+    fn header(entry_type: tar::EntryType, size: u64, attributes: FileAttributes) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(attributes.mode);
+        header.set_mtime(attributes.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        header
+    }
+}
+
+impl<W: Write> ArchiveWriter for TarArchiveWriter<W> {
+    fn add_dir(&mut self, name: &str) -> Result<(), Error> {
+        let mut header = Self::header(tar::EntryType::Directory, 0, FileAttributes { modified: SystemTime::now(), mode: 0o755 });
+        self.builder.append_data(&mut header, name, std::io::empty())?;
+        Ok(())
+    }
+    //The size is written before the content, so a file that grows while it is read is cut at the size it had before
+    fn add_file(&mut self, name: &str, size: u64, attributes: FileAttributes, _compression: FileCompression, content: &mut dyn Read, _buffer: &mut [u8]) -> Result<(), Error> {
+        let mut header = Self::header(tar::EntryType::Regular, size, attributes);
+        let mut limited = content.take(size);
+        self.builder.append_data(&mut header, name, &mut limited)?;
+        if limited.limit() > 0 {
+            return Err(Error::new_s(format!("{} got smaller while it was added to the archive", name)));
+        }
+        Ok(())
+    }
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        let stream = self.builder.into_inner()?;
+        (self.finish_stream)(stream)?;
+        Ok(())
+    }
+}
+
+//A plain copy of all files below a root folder
+struct DirectoryArchiveWriter {
+    root: PathBuf,
+}
+
+impl ArchiveWriter for DirectoryArchiveWriter {
+    fn add_dir(&mut self, name: &str) -> Result<(), Error> {
+        create_dir_all(self.root.join(name))?;
+        Ok(())
+    }
+    //The copies keep the modification time of the files. Their permissions are the default ones, so the backup can always be read and deleted again
+    fn add_file(&mut self, name: &str, _size: u64, attributes: FileAttributes, _compression: FileCompression, content: &mut dyn Read, buffer: &mut [u8]) -> Result<(), Error> {
+        let target = self.root.join(name);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut file = File::create(target)?;
+        copy_through_buffer(content, &mut file, buffer)?;
+        file.set_modified(attributes.modified)?;
+        Ok(())
+    }
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+//One entry of a backup while it is read
+pub struct ArchiveEntry<'a> {
+    //Name relative to the archive root
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub content: &'a mut dyn Read,
+}
+
+//Calls on_entry for every entry of a backup in any of the archive formats, in the order they were written
+//Encrypted files are decrypted with the passphrase. Without it only their content can't be read
+pub fn read_entries<F: FnMut(ArchiveEntry) -> Result<(), Error>>(path: &Path, passphrase: Option<&str>, on_entry: F) -> Result<(), Error> {
+    match ArchiveFormat::of_path(path).unwrap_or_default() {
+        ArchiveFormat::Zip => read_zip_entries(path, passphrase, on_entry),
+        ArchiveFormat::TarGz => read_tar_entries(tar::Archive::new(GzDecoder::new(File::open(path)?)), on_entry),
+        ArchiveFormat::TarZst => read_tar_entries(tar::Archive::new(zstd::Decoder::new(File::open(path)?)?), on_entry),
+        ArchiveFormat::Directory => read_directory_entries(path, path, &mut { on_entry }),
+    }
+}
+
+//Content of an encrypted file that is read without passphrase
+struct PassphraseMissing<'a> {
+    name: &'a str,
+}
+
+impl<'a> Read for PassphraseMissing<'a> {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other(format!("{} is encrypted and can't be read without the passphrase", self.name)))
+    }
+}
+
+fn read_zip_entries<F: FnMut(ArchiveEntry) -> Result<(), Error>>(path: &Path, passphrase: Option<&str>, mut on_entry: F) -> Result<(), Error> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    for i in 0..zip.len() {
+        let is_encrypted = zip.by_index_raw(i)?.encrypted();
+        if is_encrypted && passphrase.is_none() {
+            let entry = zip.by_index_raw(i)?;
+            on_entry(ArchiveEntry {
+                name: entry.name().to_string(),
+                is_dir: entry.is_dir(),
+                size: entry.size(),
+                content: &mut PassphraseMissing { name: entry.name() },
+            })?;
+            continue;
+        }
+        let entry = match passphrase {
+            Some(passphrase) => zip.by_index_decrypt(i, passphrase.as_bytes()),
+            None => zip.by_index(i),
+        };
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(ZipError::InvalidPassword) => return Err(Error::new_s(format!("Wrong passphrase for {}", path.display()))),
+            Err(err) => return Err(err.into()),
+        };
+        on_entry(ArchiveEntry {
+            name: entry.name().to_string(),
+            is_dir: entry.is_dir(),
+            size: entry.size(),
+            content: &mut entry,
+        })?;
+    }
+    Ok(())
+}
+
+fn read_tar_entries<R: Read, F: FnMut(ArchiveEntry) -> Result<(), Error>>(mut archive: tar::Archive<R>, mut on_entry: F) -> Result<(), Error> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let is_dir = entry.header().entry_type().is_dir();
+        let name = dir_name(entry.path()?.to_string_lossy().to_string(), is_dir);
+        let size = entry.size();
+        on_entry(ArchiveEntry {
+            name,
+            is_dir,
+            size,
+            content: &mut entry,
+        })?;
+    }
+    Ok(())
+}
+
+//Folders end with a / like in zips, so the names of all formats look the same
+fn dir_name(mut name: String, is_dir: bool) -> String {
+    if is_dir && !name.ends_with('/') {
+        name.push('/');
+    }
+    name
+}
+
+//Folders are read in alphabetical order, each folder before its content
+fn read_directory_entries<F: FnMut(ArchiveEntry) -> Result<(), Error>>(root: &Path, dir: &Path, on_entry: &mut F) -> Result<(), Error> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+    for path in paths.into_iter() {
+        let name = match path.strip_prefix(root)?.to_str() {
+            Some(name) => dir_name(name.to_string(), path.is_dir()),
+            None => return Err(Error::new_s(format!("{} is no valid name", path.display()))),
+        };
+        if path.is_dir() {
+            on_entry(ArchiveEntry {
+                name,
+                is_dir: true,
+                size: 0,
+                content: &mut std::io::empty(),
+            })?;
+            read_directory_entries(root, &path, on_entry)?;
+        } else {
+            let mut file = File::open(&path)?;
+            on_entry(ArchiveEntry {
+                name,
+                is_dir: false,
+                size: file.metadata()?.len(),
+                content: &mut file,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+//Size of a backup on disk, which is the size of all files for a directory backup
+pub fn backup_size(path: &Path) -> Result<u64, Error> {
+    if !path.is_dir() {
+        return Ok(std::fs::metadata(path)?.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += backup_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+//Deletes a backup, which may be a file or a directory backup
+pub fn remove_backup(path: &Path) -> Result<(), Error> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use flate2::read::GzDecoder;
+    use zip::ZipArchive;
+
+    use crate::compression::FileCompression;
+    use crate::test_util::TempDir;
+
+    use super::{ArchiveFormat, FileAttributes};
+
+    //Writes a.txt with the attributes into a new archive in the format
+    fn archive_with_file(dir: &TempDir, format: ArchiveFormat, attributes: FileAttributes) -> std::path::PathBuf {
+        let path = dir.path().join(format!("backup.{}", format.extension()));
+        let mut writer = format.create_writer(&path, None, None).unwrap();
+        writer.add_file("a.txt", 1, attributes, FileCompression::default(), &mut &b"a"[..], &mut [0u8; 16]).unwrap();
+        writer.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn files_keep_their_modification_time_and_permissions() {
+        let dir = TempDir::new("archive_attributes");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let attributes = FileAttributes { modified, mode: 0o640 };
+
+        let tar_gz = archive_with_file(&dir, ArchiveFormat::TarGz, attributes);
+        let tar_zst = archive_with_file(&dir, ArchiveFormat::TarZst, attributes);
+        let mut tar_gz = tar::Archive::new(GzDecoder::new(File::open(tar_gz).unwrap()));
+        let mut tar_zst = tar::Archive::new(zstd::Decoder::new(File::open(tar_zst).unwrap()).unwrap());
+        let tar_gz_headers = tar_gz.entries().unwrap().map(|entry| entry.unwrap().header().clone());
+        let tar_zst_headers = tar_zst.entries().unwrap().map(|entry| entry.unwrap().header().clone());
+        for header in tar_gz_headers.chain(tar_zst_headers) {
+            assert_eq!(header.mtime().unwrap(), 1_600_000_000);
+            assert_eq!(header.mode().unwrap(), 0o640);
+        }
+
+        let zip = archive_with_file(&dir, ArchiveFormat::Zip, attributes);
+        let mut zip = ZipArchive::new(File::open(zip).unwrap()).unwrap();
+        let entry = zip.by_name("a.txt").unwrap();
+        assert_eq!(entry.unix_mode().unwrap() & 0o7777, 0o640);
+        assert_eq!(entry.last_modified().unwrap(), super::ZipArchiveWriter::zip_time(modified));
+
+        let directory = archive_with_file(&dir, ArchiveFormat::Directory, attributes);
+        assert_eq!(std::fs::metadata(directory.join("a.txt")).unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn files_of_the_source_are_written_with_their_own_attributes() {
+        let dir = TempDir::new("archive_source_attributes");
+        let path = dir.write("a.txt", b"a");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        let attributes = FileAttributes::of(&std::fs::metadata(&path).unwrap());
+        assert_eq!(attributes.modified, modified);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o750)).unwrap();
+            assert_eq!(FileAttributes::of(&std::fs::metadata(&path).unwrap()).mode, 0o750);
+        }
+        assert_eq!(FileAttributes::modified_at(SystemTime::now()).mode, 0o644);
+    }
+}
@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use serde::*;
+
+use crate::catalog::format_size;
+use crate::error::Error;
+use crate::zip::SkippedFile;
+
+//One file that would go into the backup
+#[derive(Debug, Serialize)]
+pub struct PlannedFile {
+    //Path relative to the source root, the same as the name of the zip entry
+    pub path: String,
+    pub size: u64,
+}
+
+//What a backup of a system would contain, found by walking the source without creating any archive
+#[derive(Debug, Serialize)]
+pub struct BackupPlan {
+    pub system: String,
+    pub src: String,
+    pub files: Vec<PlannedFile>,
+    pub skipped: Vec<SkippedFile>,
+    pub total_size: u64,
+}
+
+impl BackupPlan {
+    //Human readable description of the plan with every file and every skipped path
+    pub fn texts(&self) -> Vec<String> {
+        let mut texts = vec![format!("{} ({}):", self.system, self.src)];
+        for file in self.files.iter() {
+            texts.push(format!("  Backs up {}  ({})", file.path, format_size(file.size)));
+        }
+        for skipped in self.skipped.iter() {
+            texts.push(format!("  Skips {} ({})", skipped.path.display(), skipped.reason));
+        }
+        texts.push(self.summary());
+        texts
+    }
+
+    //One line with the totals of the plan
+    pub fn summary(&self) -> String {
+        format!("{}: {} files with {} in total, {} skipped", self.system, self.files.len(), format_size(self.total_size), self.skipped.len())
+    }
+}
+
+//Writes the plans to a file, as JSON if the file name ends with .json and as text otherwise
+pub fn export_plans(plans: &[BackupPlan], path: &Path) -> Result<(), Error> {
+    let content = if path.extension().map(|e| e.eq_ignore_ascii_case("json")).unwrap_or(false) {
+        serde_json::to_string_pretty(plans)?
+    } else {
+        plans.iter().flat_map(|plan| plan.texts()).collect::<Vec<_>>().join("\n")
+    };
+    File::create(path)?.write_all(content.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::cmp::Reverse;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+use zip::ZipArchive;
+
+use crate::archive::{ArchiveFormat, backup_size, read_entries};
+use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::repository::{is_snapshot, read_snapshot, Repository};
+use crate::zip_name::list_zip_paths;
+
+//One backup archive found in the destination folder of a system
+#[derive(Debug, Clone)]
+pub struct BackupEntry {
+    pub system: String,
+    pub created: NaiveDateTime,
+    pub path: PathBuf,
+    pub size: u64,
+    //None if the archive can't be read
+    pub entry_count: Option<usize>,
+    //The backup an incremental backup builds on. None for full backups
+    pub base: Option<PathBuf>,
+    //Set for backups in a remote storage. path is where they are downloaded to then
+    pub stored: Option<StoredBackup>,
+}
+
+//Where a backup in a storage is
+#[derive(Debug, Clone)]
+pub struct StoredBackup {
+    //The description of the storage
+    pub storage: String,
+    pub key: String,
+}
+
+impl BackupEntry {
+    //One line summary to show in lists
+    pub fn summary(&self) -> String {
+        let entries = match self.entry_count {
+            Some(count) => format!("{} entries", count),
+            None => "unreadable".to_string(),
+        };
+        let kind = if self.base.is_some() {
+            "incremental"
+        } else {
+            "full"
+        };
+        format!("{}  {}  {:>10}  {}  {}", self.created.format("%Y-%m-%d %H:%M:%S"), self.system, format_size(self.size), entries, kind)
+    }
+
+    //Where the backup is, which is the storage and key for a backup in a storage
+    pub fn location(&self) -> String {
+        match self.stored.as_ref() {
+            Some(stored) => format!("{}/{}", stored.storage, stored.key),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+//One file or folder inside of a backup archive
+pub struct ArchiveContent {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+//Scans the destination folder of a system for its backups (archives in all formats as well as snapshots in its repository) and returns them newest first
+pub fn load_catalog(system_name: &str, dest_dir: &Path) -> Result<Vec<BackupEntry>, Error> {
+    let mut catalog = Vec::new();
+    for (path, created) in list_zip_paths(system_name, dest_dir)?.into_iter() {
+        let size = backup_size(&path)?;
+        let manifest = Manifest::read_from_backup(&path).ok().flatten();
+        //Only a zip can be counted without reading it, for the other formats the manifest tells the count
+        let entry_count = if ArchiveFormat::of_path(&path) == Some(ArchiveFormat::Zip) {
+            File::open(&path).ok()
+                .and_then(|f| ZipArchive::new(f).ok())
+                .map(|zip| zip.len())
+        } else {
+            manifest.as_ref().map(|m| m.dirs.len() + m.files.len() + 1)
+        };
+        let base = manifest
+            .and_then(|manifest| manifest.base)
+            .map(|base| path.with_file_name(base));
+        catalog.push(BackupEntry {
+            system: system_name.to_string(),
+            created,
+            path,
+            size,
+            entry_count,
+            base,
+            stored: None,
+        });
+    }
+    for (path, created) in Repository::in_dest(dest_dir).list_snapshots(system_name)?.into_iter() {
+        let manifest = read_snapshot(&path).ok();
+        catalog.push(BackupEntry {
+            system: system_name.to_string(),
+            created,
+            size: manifest.as_ref().map(|m| m.files.iter().map(|f| f.size).sum()).unwrap_or(0),
+            entry_count: manifest.as_ref().map(|m| m.dirs.len() + m.files.len()),
+            path,
+            base: None,
+            stored: None,
+        });
+    }
+    catalog.sort_by_key(|b| Reverse(b.created));
+    Ok(catalog)
+}
+
+//Merges the catalogs of several systems, newest first
+pub fn merge_catalogs(catalogs: Vec<Vec<BackupEntry>>) -> Vec<BackupEntry> {
+    let mut merged: Vec<BackupEntry> = catalogs.into_iter().flatten().collect();
+    merged.sort_by_key(|b| Reverse(b.created));
+    merged
+}
+
+//Lists all files and folders inside of a backup archive or snapshot
+pub fn list_contents(archive: &Path) -> Result<Vec<ArchiveContent>, Error> {
+    if is_snapshot(archive) {
+        let manifest = read_snapshot(archive)?;
+        let dirs = manifest.dirs.into_iter().map(|dir| ArchiveContent {
+            name: format!("{}/", dir),
+            size: 0,
+            is_dir: true,
+        });
+        let files = manifest.files.into_iter().map(|file| ArchiveContent {
+            name: file.path,
+            size: file.size,
+            is_dir: false,
+        });
+        return Ok(dirs.chain(files).collect());
+    }
+    let mut contents = Vec::new();
+    read_entries(archive, None, |entry| {
+        contents.push(ArchiveContent {
+            name: entry.name,
+            size: entry.size,
+            is_dir: entry.is_dir,
+        });
+        Ok(())
+    })?;
+    Ok(contents)
+}
+
+//Formats a number of bytes human readable
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
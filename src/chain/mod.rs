@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::manifest::{Manifest, ManifestFile};
+
+//One archive of a chain of incremental backups
+pub struct ChainLink {
+    pub archive: PathBuf,
+    pub manifest: Manifest,
+}
+
+//A file as it is at the end of a chain, together with the index of the link that holds its newest version
+pub struct ChainFile {
+    pub link: usize,
+    pub file: ManifestFile,
+}
+
+//Follows the base of every manifest back to the full backup and returns the chain with the full backup first
+//Fails if an archive of the chain has no manifest or a base is missing, as the chain can't be replayed then
+pub fn load_chain(archive: &Path) -> Result<Vec<ChainLink>, Error> {
+    let mut chain = Vec::new();
+    let mut visited = HashSet::new();
+    let mut next = Some(archive.to_path_buf());
+    while let Some(archive) = next {
+        if !visited.insert(archive.clone()) {
+            return Err(Error::new_s(format!("The backups based on {} form a loop", archive.display())));
+        }
+        if !archive.exists() {
+            return Err(Error::new_s(format!("{} is missing, but other backups are based on it", archive.display())));
+        }
+        let manifest = match Manifest::read_from_backup(&archive)? {
+            Some(manifest) => manifest,
+            None => return Err(Error::new_s(format!("{} has no manifest", archive.display()))),
+        };
+        next = manifest.base.as_ref().map(|base| archive.with_file_name(base));
+        chain.push(ChainLink { archive, manifest });
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+//Says if the content of the archive or of a backup its chain builds on is encrypted
+pub fn is_encrypted(archive: &Path) -> bool {
+    match load_chain(archive) {
+        Ok(chain) => chain.iter().any(|link| link.manifest.encrypted),
+        Err(_) => Manifest::read_from_backup(archive).ok().flatten().map(|manifest| manifest.encrypted).unwrap_or(false),
+    }
+}
+
+//Replays the chain from the full backup on and returns all files as they are after the last link, sorted by path
+pub fn files_at(chain: &[ChainLink]) -> BTreeMap<String, ChainFile> {
+    let mut files = BTreeMap::new();
+    for (link, chain_link) in chain.iter().enumerate() {
+        for deleted in chain_link.manifest.deleted.iter() {
+            files.remove(deleted);
+        }
+        for file in chain_link.manifest.files.iter() {
+            files.insert(file.path.clone(), ChainFile { link, file: file.clone() });
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::manifest::{Manifest, ManifestFile};
+
+    use super::{ChainLink, files_at};
+
+    fn file(path: &str, size: u64) -> ManifestFile {
+        ManifestFile {
+            path: path.to_string(),
+            size,
+            modified: 0,
+            sha256: None,
+        }
+    }
+
+    fn link(name: &str, base: Option<&str>, files: Vec<ManifestFile>, deleted: &[&str]) -> ChainLink {
+        let mut manifest = Manifest::new("MQ", "src", files);
+        manifest.base = base.map(|base| base.to_string());
+        manifest.deleted = deleted.iter().map(|d| d.to_string()).collect();
+        ChainLink {
+            archive: PathBuf::from(name),
+            manifest,
+        }
+    }
+
+    #[test]
+    fn replays_changes_and_deletions_in_order() {
+        let chain = vec![
+            link("full.zip", None, vec![file("a", 1), file("b", 1), file("c", 1)], &[]),
+            link("delta1.zip", Some("full.zip"), vec![file("b", 2)], &[]),
+            link("delta2.zip", Some("delta1.zip"), vec![file("d", 3)], &["c"]),
+        ];
+        let files: Vec<(String, usize, u64)> = files_at(&chain).into_iter().map(|(path, f)| (path, f.link, f.file.size)).collect();
+        assert_eq!(files, vec![("a".to_string(), 0, 1), ("b".to_string(), 1, 2), ("d".to_string(), 2, 3)]);
+    }
+
+    #[test]
+    fn a_file_deleted_and_added_again_comes_from_the_newer_backup() {
+        let chain = vec![
+            link("full.zip", None, vec![file("a", 1)], &[]),
+            link("delta1.zip", Some("full.zip"), vec![], &["a"]),
+            link("delta2.zip", Some("delta1.zip"), vec![file("a", 5)], &[]),
+        ];
+        let files = files_at(&chain);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files["a"].link, 2);
+        assert_eq!(files["a"].file.size, 5);
+    }
+}
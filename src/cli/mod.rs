@@ -0,0 +1,653 @@
+use std::path::{Path, PathBuf};
+
+use mq_backuper::backup_plan::export_plans;
+use mq_backuper::catalog::{format_size, list_contents, merge_catalogs};
+use mq_backuper::chain::is_encrypted;
+use mq_backuper::error::Error;
+use mq_backuper::local_installation::{backup_all, BackupAllResult, LocalInstallation};
+use mq_backuper::manifest::Manifest;
+use mq_backuper::repository::{export_to_zip, is_snapshot};
+use mq_backuper::restore::RestoreOptions;
+use mq_backuper::systems::{CONFIG_FILE_NAME, load_validated_consoles_and_local_installations, ValidConsolesAndLocalInstallations};
+use mq_backuper::verify::verify_backup;
+
+use crate::tui::TUI;
+
+//Everything worked
+pub const EXIT_SUCCESS: i32 = 0;
+//Nothing worked, e.g. no system could be backed up or the config could not be read
+pub const EXIT_TOTAL_FAILURE: i32 = 1;
+//Some systems worked and some did not (or were skipped because they are invalid)
+pub const EXIT_PARTIAL_FAILURE: i32 = 2;
+//The command line arguments could not be understood
+pub const EXIT_INVALID_USAGE: i32 = 3;
+
+//Headless commands that can be run without any user interaction
+pub enum CliCommand {
+    //Backs up all systems, on the given number of workers instead of the one of the config
+    BackupAll(Option<usize>),
+    BackupSystem(String),
+    //Shows what a backup of one system or of all systems if None would contain, optionally exported to a file
+    PlanBackup(Option<String>, Option<PathBuf>),
+    ListSystems,
+    ValidateConfig,
+    Restore(RestoreCommand),
+    //Lists the backups of one system or of all systems if None
+    ListBackups(Option<String>),
+    ShowBackup(PathBuf),
+    //Prunes the backups of one system or of all systems if None
+    Prune(Option<String>, bool),
+    //Verifies one archive or all backups of one system or of all systems
+    VerifyArchive(PathBuf),
+    VerifySystems(Option<String>),
+    //Writes a snapshot of a repository into a zip
+    Export(PathBuf, PathBuf),
+    Help,
+}
+
+//Restores an archive of a system. archive may be "latest" to restore the newest backup or the location of a backup in any dest as shown by list-backups
+pub struct RestoreCommand {
+    system: String,
+    archive: String,
+    options: RestoreOptions,
+    preview_only: bool,
+}
+
+//Options given after the command name
+#[derive(Default)]
+struct CliOptions {
+    all: bool,
+    system: Option<String>,
+    archive: Option<String>,
+    move_aside: bool,
+    preview: bool,
+    dry_run: bool,
+    export: Option<String>,
+    to: Option<String>,
+    workers: Option<String>,
+    //All options in the order they were given, to refuse the ones a command doesn't use
+    given: Vec<String>,
+}
+
+impl CliOptions {
+    fn parse(args: &[&str]) -> Result<CliOptions, Error> {
+        let mut options = CliOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            options.given.push(arg.to_string());
+            match *arg {
+                "--all" => options.all = true,
+                "--move-aside" => options.move_aside = true,
+                "--preview" => options.preview = true,
+                "--dry-run" => options.dry_run = true,
+                "--system" => options.system = Some(Self::value(arg, args.next())?),
+                "--archive" => options.archive = Some(Self::value(arg, args.next())?),
+                "--export" => options.export = Some(Self::value(arg, args.next())?),
+                "--to" => options.to = Some(Self::value(arg, args.next())?),
+                "--workers" => options.workers = Some(Self::value(arg, args.next())?),
+                arg => return Err(Error::new_s(format!("Unknown option: {}", arg))),
+            }
+        }
+        Ok(options)
+    }
+    //Fails for the first option that the command doesn't use, so a typo or a misunderstood option never runs something else than what was asked
+    fn check_allowed(&self, command: &str, allowed: &[&str]) -> Result<(), Error> {
+        match self.given.iter().find(|option| !allowed.contains(&option.as_str())) {
+            Some(option) => Err(Error::new_s(format!("{} can't be used with {}", option, command))),
+            None => Ok(()),
+        }
+    }
+    fn value(option: &str, value: Option<&&str>) -> Result<String, Error> {
+        match value {
+            Some(value) => Ok(value.to_string()),
+            None => Err(Error::new_s(format!("{} needs a value", option))),
+        }
+    }
+}
+
+impl CliCommand {
+    //Reads the command from the program arguments (without the program name itself)
+    pub fn parse(args: &[String]) -> Result<CliCommand, Error> {
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        let (command, options) = match args.split_first() {
+            Some((command, options)) => (*command, CliOptions::parse(options)?),
+            None => return Ok(CliCommand::Help),
+        };
+        let allowed: &[&str] = match command {
+            "backup" => &["--all", "--system", "--dry-run", "--export", "--workers"],
+            "restore" => &["--system", "--archive", "--move-aside", "--preview"],
+            "list-backups" => &["--system"],
+            "show-backup" => &["--archive"],
+            "prune" => &["--all", "--system", "--dry-run"],
+            "verify" => &["--archive", "--all", "--system"],
+            "export" => &["--archive", "--to"],
+            "list-systems" | "validate-config" | "help" | "--help" | "-h" => &[],
+            command => return Err(Error::new_s(format!("Unknown command: {}", command))),
+        };
+        options.check_allowed(command, allowed)?;
+        match command {
+            "backup" => match options {
+                CliOptions { workers: Some(_), dry_run: true, .. } => Err(Error::new_s("--workers doesn't work together with --dry-run")),
+                CliOptions { all: true, system: None, dry_run: true, export, .. } => Ok(CliCommand::PlanBackup(None, export.map(PathBuf::from))),
+                CliOptions { all: false, system: Some(system), dry_run: true, export, .. } => Ok(CliCommand::PlanBackup(Some(system), export.map(PathBuf::from))),
+                CliOptions { export: Some(_), .. } => Err(Error::new_s("--export only works together with --dry-run")),
+                CliOptions { workers: Some(_), all: false, .. } => Err(Error::new_s("--workers only works together with --all")),
+                CliOptions { all: true, system: None, workers, .. } => match workers.map(|workers| workers.parse::<usize>()) {
+                    None => Ok(CliCommand::BackupAll(None)),
+                    Some(Ok(workers)) if workers > 0 => Ok(CliCommand::BackupAll(Some(workers))),
+                    Some(_) => Err(Error::new_s("--workers needs a number of at least 1")),
+                },
+                CliOptions { all: false, system: Some(system), .. } => Ok(CliCommand::BackupSystem(system)),
+                _ => Err(Error::new_s("backup needs either --all or --system")),
+            },
+            "restore" => match options {
+                CliOptions { system: Some(system), archive: Some(archive), move_aside, preview, .. } => Ok(CliCommand::Restore(RestoreCommand {
+                    system,
+                    archive,
+                    options: RestoreOptions { move_aside },
+                    preview_only: preview,
+                })),
+                _ => Err(Error::new_s("restore needs --system and --archive")),
+            },
+            "list-backups" => Ok(CliCommand::ListBackups(options.system)),
+            "show-backup" => match options.archive {
+                Some(archive) => Ok(CliCommand::ShowBackup(PathBuf::from(archive))),
+                None => Err(Error::new_s("show-backup needs --archive")),
+            },
+            "prune" => match options {
+                CliOptions { all: true, system: None, dry_run, .. } => Ok(CliCommand::Prune(None, dry_run)),
+                CliOptions { all: false, system: Some(system), dry_run, .. } => Ok(CliCommand::Prune(Some(system), dry_run)),
+                _ => Err(Error::new_s("prune needs either --all or --system")),
+            },
+            "verify" => match options {
+                CliOptions { archive: Some(archive), all: false, system: None, .. } => Ok(CliCommand::VerifyArchive(PathBuf::from(archive))),
+                CliOptions { archive: None, all: true, system: None, .. } => Ok(CliCommand::VerifySystems(None)),
+                CliOptions { archive: None, all: false, system: Some(system), .. } => Ok(CliCommand::VerifySystems(Some(system))),
+                _ => Err(Error::new_s("verify needs either --archive, --all or --system")),
+            },
+            "export" => match options {
+                CliOptions { archive: Some(archive), to: Some(to), .. } => Ok(CliCommand::Export(PathBuf::from(archive), PathBuf::from(to))),
+                _ => Err(Error::new_s("export needs --archive and --to")),
+            },
+            "list-systems" => Ok(CliCommand::ListSystems),
+            "validate-config" => Ok(CliCommand::ValidateConfig),
+            "help" | "--help" | "-h" => Ok(CliCommand::Help),
+            command => Err(Error::new_s(format!("Unknown command: {}", command))),
+        }
+    }
+}
+
+pub const USAGE: &str = r#"Usage:
+  mq_backuper                            Starts the interactive menu
+  mq_backuper backup --all [--workers <n>]
+                                         Backs up all valid systems, n of them at once
+                                         (default: workers of the config or 1)
+  mq_backuper backup --system "<name>"   Backs up the system with the given name
+  mq_backuper backup (--all | --system "<name>") --dry-run [--export <file>]
+                                         Lists the files a backup would contain, the skipped ones
+                                         and the total size without creating any archive.
+                                         --export writes this plan to a file, as JSON for .json files
+  mq_backuper restore --system "<name>" --archive <backup|latest> [--move-aside] [--preview]
+                                         Extracts a backup back into the src of the system.
+                                         --move-aside renames files before they get overwritten,
+                                         --preview only lists what would be overwritten
+  mq_backuper list-backups [--system "<name>"]
+                                         Lists the backups of all systems (or of one), newest first
+  mq_backuper show-backup --archive <backup>
+                                         Lists the content of a backup
+  mq_backuper prune (--all | --system "<name>") [--dry-run]
+                                         Deletes old backups according to the retention rules.
+                                         --dry-run only lists what would be deleted
+  mq_backuper verify (--archive <backup> | --all | --system "<name>")
+                                         Checks if backups can still be read and match their manifest
+  mq_backuper export --archive <snapshot> --to <zip>
+                                         Writes a snapshot of a repository into a standalone zip
+  mq_backuper list-systems               Lists all systems of the config file
+  mq_backuper validate-config            Checks the config file and all its systems
+  mq_backuper help                       Shows this help
+
+Exit codes:
+  0  Success
+  1  Total failure (nothing could be done)
+  2  Partial failure (some systems failed or were invalid, some entries were not restored)
+  3  Invalid usage"#;
+
+//Runs the command given by the program arguments and returns the exit code for the process
+pub fn run(args: Vec<String>) -> i32 {
+    let mut tui = TUI::new_headless();
+    let command = match CliCommand::parse(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            for e in err.texts().into_iter() {
+                tui.write_errorln(e);
+            }
+            tui.writeln(USAGE);
+            return EXIT_INVALID_USAGE;
+        }
+    };
+    match command {
+        CliCommand::Help => {
+            tui.writeln(USAGE);
+            EXIT_SUCCESS
+        }
+        CliCommand::ListSystems => list_systems(&mut tui),
+        CliCommand::ValidateConfig => validate_config(&mut tui),
+        CliCommand::BackupAll(workers) => backup_all_systems(&mut tui, workers),
+        CliCommand::BackupSystem(name) => backup_system(&mut tui, &name),
+        CliCommand::PlanBackup(system, export) => plan_backup(&mut tui, system, export),
+        CliCommand::Restore(restore_command) => restore(&mut tui, restore_command),
+        CliCommand::ListBackups(system) => list_backups(&mut tui, system),
+        CliCommand::ShowBackup(archive) => show_backup(&mut tui, &archive),
+        CliCommand::Prune(system, dry_run) => prune(&mut tui, system, dry_run),
+        CliCommand::VerifyArchive(archive) => verify_archive(&mut tui, archive),
+        CliCommand::VerifySystems(system) => verify_systems(&mut tui, system),
+        CliCommand::Export(snapshot, dest_zip) => export(&mut tui, &snapshot, &dest_zip),
+    }
+}
+
+//Loads the config and prints all warnings about invalid systems and notices about the config. Returns None (after printing the error) if the config can't be read at all
+//Only the invalid systems count as failed, the notices don't change the exit code
+fn load_and_print_warnings(tui: &mut TUI) -> Option<ValidConsolesAndLocalInstallations> {
+    match load_validated_consoles_and_local_installations() {
+        Ok(valid_items) => {
+            for warning in valid_items.notices.iter().chain(valid_items.warnings.iter()) {
+                tui.write_warnln(format!("Warning: {}", warning.to_string().trim()));
+            }
+            Some(valid_items)
+        }
+        Err(err) => {
+            for e in err.texts().into_iter() {
+                tui.write_errorln(e);
+            }
+            None
+        }
+    }
+}
+
+//Exit code for a run where some parts may have failed
+fn exit_code(success_count: usize, failure_count: usize) -> i32 {
+    if failure_count == 0 {
+        EXIT_SUCCESS
+    } else if success_count == 0 {
+        EXIT_TOTAL_FAILURE
+    } else {
+        EXIT_PARTIAL_FAILURE
+    }
+}
+
+fn list_systems(tui: &mut TUI) -> i32 {
+    let valid_items = match load_and_print_warnings(tui) {
+        Some(valid_items) => valid_items,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    for local_installation in valid_items.systems.iter() {
+        print_system(tui, local_installation);
+    }
+    exit_code(valid_items.systems.len(), valid_items.warnings.len())
+}
+
+fn print_system(tui: &mut TUI, local_installation: &LocalInstallation) {
+    tui.writeln(format!("{}\n  src:  {}\n  dest: {}", local_installation.name, local_installation.src(), local_installation.dests().iter().map(|dest| dest.describe()).collect::<Vec<String>>().join(", ")));
+}
+
+fn validate_config(tui: &mut TUI) -> i32 {
+    let valid_items = match load_and_print_warnings(tui) {
+        Some(valid_items) => valid_items,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    if valid_items.is_empty() {
+        tui.write_errorln(format!("No valid systems found in {}", CONFIG_FILE_NAME));
+        return EXIT_TOTAL_FAILURE;
+    }
+    for local_installation in valid_items.systems.iter() {
+        tui.write_successln(format!("{} is valid", local_installation.name));
+    }
+    exit_code(valid_items.systems.len(), valid_items.warnings.len())
+}
+
+fn backup_all_systems(tui: &mut TUI, workers: Option<usize>) -> i32 {
+    let valid_items = match load_and_print_warnings(tui) {
+        Some(valid_items) => valid_items,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    let invalid_count = valid_items.warnings.len();
+    let result = backup_all(valid_items.systems, workers.unwrap_or(valid_items.workers), tui);
+    let breakdown = result.breakdown();
+    let BackupAllResult { successes, errors, .. } = result;
+    print_results(tui, &successes, &errors);
+    tui.writeln(breakdown);
+    exit_code(successes.len(), errors.len() + invalid_count)
+}
+
+//Loads the valid system with the given name. Returns None (after printing the error) if there is none
+fn load_system(tui: &mut TUI, name: &str) -> Option<LocalInstallation> {
+    let valid_items = load_and_print_warnings(tui)?;
+    let local_installation = valid_items.systems.into_iter().find(|l| l.name == name);
+    if local_installation.is_none() {
+        tui.write_errorln(format!("No valid system named {} found in {}", name, CONFIG_FILE_NAME));
+    }
+    local_installation
+}
+
+fn backup_system(tui: &mut TUI, name: &str) -> i32 {
+    let local_installation = match load_system(tui, name) {
+        Some(local_installation) => local_installation,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    match local_installation.backup(tui) {
+        Ok(report) => {
+            print_results(tui, &[report.message], &report.failed_copies);
+            exit_code(1, report.failed_copies.len())
+        }
+        Err(err) => {
+            print_results(tui, &[], &err.texts());
+            EXIT_TOTAL_FAILURE
+        }
+    }
+}
+
+fn plan_backup(tui: &mut TUI, system: Option<String>, export: Option<PathBuf>) -> i32 {
+    let local_installations = match load_system_or_all(tui, system) {
+        Some(local_installations) => local_installations,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    let mut plans = Vec::new();
+    let mut errors = Vec::new();
+    for local_installation in local_installations.iter() {
+        match local_installation.plan_backup(tui) {
+            Ok(plan) => plans.push(plan),
+            Err(err) => errors.push(format!("Could not plan the backup of {}: {}", local_installation.name, err.to_string().trim())),
+        }
+    }
+    for plan in plans.iter() {
+        for text in plan.texts().into_iter() {
+            tui.writeln(text);
+        }
+    }
+    let mut successes: Vec<String> = plans.iter().map(|plan| plan.summary()).collect();
+    if let Some(export) = export {
+        match export_plans(&plans, &export) {
+            Ok(()) => successes.push(format!("Exported the plan to {}", export.display())),
+            Err(err) => errors.push(format!("Could not export the plan to {}: {}", export.display(), err.to_string().trim())),
+        }
+    }
+    print_results(tui, &successes, &errors);
+    exit_code(plans.len(), errors.len())
+}
+
+fn restore(tui: &mut TUI, restore_command: RestoreCommand) -> i32 {
+    let local_installation = match load_system(tui, &restore_command.system) {
+        Some(local_installation) => local_installation,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    let (backups, errors) = local_installation.all_backups();
+    print_results(tui, &[], &errors);
+    let entry = if restore_command.archive == "latest" {
+        match backups.into_iter().next() {
+            Some(entry) => Some(entry),
+            None => {
+                tui.write_errorln(format!("No backups of {} found", local_installation.name));
+                return EXIT_TOTAL_FAILURE;
+            }
+        }
+    } else {
+        backups.into_iter().find(|entry| entry.location() == restore_command.archive)
+    };
+    let archive = match entry {
+        Some(entry) => match local_installation.fetch(tui, &entry) {
+            Ok(archive) => archive,
+            Err(err) => {
+                print_results(tui, &[], &err.texts());
+                return EXIT_TOTAL_FAILURE;
+            }
+        },
+        None => PathBuf::from(&restore_command.archive),
+    };
+    let preview = match local_installation.preview_restore(&archive) {
+        Ok(preview) => preview,
+        Err(err) => {
+            print_results(tui, &[], &err.texts());
+            return EXIT_TOTAL_FAILURE;
+        }
+    };
+    for file in preview.overwritten_files.iter() {
+        tui.writeln(format!("Overwrites {}", file.display()));
+    }
+    for file in preview.new_files.iter() {
+        tui.writeln(format!("Creates {}", file.display()));
+    }
+    for entry in preview.refused_entries.iter() {
+        tui.write_errorln(format!("Refuses {} because it points outside of {}", entry, local_installation.src()));
+    }
+    if restore_command.preview_only {
+        return EXIT_SUCCESS;
+    }
+    match local_installation.restore(tui, &archive, restore_command.options) {
+        Ok(success_message) => {
+            print_results(tui, &[success_message], &[]);
+            if preview.refused_entries.is_empty() {
+                EXIT_SUCCESS
+            } else {
+                EXIT_PARTIAL_FAILURE
+            }
+        }
+        Err(err) => {
+            print_results(tui, &[], &err.texts());
+            EXIT_TOTAL_FAILURE
+        }
+    }
+}
+
+//Loads the system with the given name or all valid systems if None. Returns None (after printing the error) if they can't be loaded
+fn load_system_or_all(tui: &mut TUI, system: Option<String>) -> Option<Vec<LocalInstallation>> {
+    match system {
+        Some(name) => load_system(tui, &name).map(|local_installation| vec![local_installation]),
+        None => load_and_print_warnings(tui).map(|valid_items| valid_items.systems),
+    }
+}
+
+fn list_backups(tui: &mut TUI, system: Option<String>) -> i32 {
+    let local_installations = match load_system_or_all(tui, system) {
+        Some(local_installations) => local_installations,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    let mut catalogs = Vec::new();
+    let mut errors = Vec::new();
+    //A system has an error for every dest that can't be read, but fails only once
+    let mut failed_systems = 0;
+    for local_installation in local_installations.iter() {
+        let (catalog, catalog_errors) = local_installation.all_backups();
+        catalogs.push(catalog);
+        if !catalog_errors.is_empty() {
+            failed_systems += 1;
+        }
+        errors.extend(catalog_errors);
+        if let Ok(partial_zips) = local_installation.partial_backups() {
+            for partial_zip in partial_zips.iter() {
+                tui.write_warnln(format!("Warning: {} is left over from an interrupted backup", partial_zip.display()));
+            }
+        }
+    }
+    for entry in merge_catalogs(catalogs).iter() {
+        tui.writeln(format!("{}  {}", entry.summary(), entry.location()));
+    }
+    print_results(tui, &[], &errors);
+    exit_code(local_installations.len() - failed_systems, failed_systems)
+}
+
+fn prune(tui: &mut TUI, system: Option<String>, dry_run: bool) -> i32 {
+    let local_installations = match load_system_or_all(tui, system) {
+        Some(local_installations) => local_installations,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    let mut successes = Vec::new();
+    let mut errors = Vec::new();
+    for local_installation in local_installations.iter() {
+        match local_installation.prune(tui, dry_run) {
+            Ok(message) => successes.push(format!("{}:\n{}", local_installation.name, message)),
+            Err(err) => errors.push(format!("Could not prune {}: {}", local_installation.name, err.to_string().trim())),
+        }
+    }
+    print_results(tui, &successes, &errors);
+    exit_code(successes.len(), errors.len())
+}
+
+fn verify_systems(tui: &mut TUI, system: Option<String>) -> i32 {
+    let local_installations = match load_system_or_all(tui, system) {
+        Some(local_installations) => local_installations,
+        None => return EXIT_TOTAL_FAILURE,
+    };
+    let mut catalogs = Vec::new();
+    for local_installation in local_installations.iter() {
+        match local_installation.backups() {
+            Ok(catalog) => catalogs.push(catalog),
+            Err(err) => {
+                print_results(tui, &[], &err.texts());
+                return EXIT_TOTAL_FAILURE;
+            }
+        }
+    }
+    //A backup in a remote first dest is downloaded first
+    let mut archives = Vec::new();
+    let mut errors = Vec::new();
+    for entry in merge_catalogs(catalogs).into_iter() {
+        let local_installation = local_installations.iter().find(|l| l.name == entry.system).cloned();
+        let fetched = match local_installation.as_ref() {
+            Some(local_installation) => local_installation.fetch(tui, &entry),
+            None => Ok(entry.path.clone()),
+        };
+        match fetched {
+            Ok(archive) => archives.push((archive, local_installation)),
+            Err(err) => errors.push(format!("{} can't be downloaded: {}", entry.location(), err.to_string().trim())),
+        }
+    }
+    verify(tui, archives, errors)
+}
+
+fn verify_archive(tui: &mut TUI, archive: PathBuf) -> i32 {
+    //Only an encrypted backup needs its system, which knows where to find the passphrase
+    let mut local_installation = None;
+    if is_encrypted(&archive) {
+        let system = Manifest::read_from_backup(&archive).ok().flatten().map(|manifest| manifest.system).unwrap_or_default();
+        local_installation = match load_system(tui, &system) {
+            Some(local_installation) => Some(local_installation),
+            None => return EXIT_TOTAL_FAILURE,
+        };
+    }
+    verify(tui, vec![(archive, local_installation)], Vec::new())
+}
+
+//Verifies every archive, encrypted ones with the passphrase of their system
+//Archives that could not be found count as failed, errors says why
+fn verify(tui: &mut TUI, archives: Vec<(PathBuf, Option<LocalInstallation>)>, errors: Vec<String>) -> i32 {
+    if !errors.is_empty() {
+        print_results(tui, &[], &errors);
+    }
+    let mut ok_count = 0;
+    let mut failed_count = errors.len();
+    for (archive, local_installation) in archives.iter() {
+        let verified = match local_installation {
+            Some(local_installation) => local_installation.verify(tui, archive),
+            None => verify_backup(tui, archive, None),
+        };
+        match verified {
+            Ok(report) if report.is_ok() => {
+                ok_count += 1;
+                print_results(tui, &report.texts(), &[]);
+            }
+            Ok(report) => {
+                failed_count += 1;
+                print_results(tui, &[], &report.texts());
+            }
+            Err(err) => {
+                failed_count += 1;
+                print_results(tui, &[], &[format!("{} can't be read: {}", archive.display(), err.to_string().trim())]);
+            }
+        }
+    }
+    exit_code(ok_count, failed_count)
+}
+
+fn show_backup(tui: &mut TUI, archive: &Path) -> i32 {
+    if let Ok(Some(manifest)) = Manifest::read_from_backup(archive) {
+        tui.writeln(manifest.origin());
+        if let Some(delta_description) = manifest.delta_description() {
+            tui.writeln(delta_description);
+        }
+    }
+    match list_contents(archive) {
+        Ok(contents) => {
+            for content in contents.iter() {
+                if content.is_dir {
+                    tui.writeln(&content.name);
+                } else {
+                    tui.writeln(format!("{}  ({})", content.name, format_size(content.size)));
+                }
+            }
+            EXIT_SUCCESS
+        }
+        Err(err) => {
+            print_results(tui, &[], &err.texts());
+            EXIT_TOTAL_FAILURE
+        }
+    }
+}
+
+fn export(tui: &mut TUI, snapshot: &Path, dest_zip: &Path) -> i32 {
+    if !is_snapshot(snapshot) {
+        tui.write_errorln(format!("{} is no snapshot of a repository", snapshot.display()));
+        return EXIT_INVALID_USAGE;
+    }
+    match export_to_zip(tui, snapshot, dest_zip) {
+        Ok(()) => {
+            print_results(tui, &[format!("Exported {} to {}", snapshot.display(), dest_zip.display())], &[]);
+            EXIT_SUCCESS
+        }
+        Err(err) => {
+            print_results(tui, &[], &err.texts());
+            EXIT_TOTAL_FAILURE
+        }
+    }
+}
+
+fn print_results(tui: &mut TUI, successes: &[String], errors: &[String]) {
+    tui.writeln("");
+    for error in errors.iter() {
+        tui.write_errorln(error);
+    }
+    for success in successes.iter() {
+        tui.write_successln(success);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CliCommand;
+
+    fn parse(args: &[&str]) -> Result<CliCommand, mq_backuper::error::Error> {
+        CliCommand::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn options_of_the_command_are_accepted() {
+        assert!(matches!(parse(&["backup", "--all", "--workers", "2"]), Ok(CliCommand::BackupAll(Some(2)))));
+        assert!(matches!(parse(&["backup", "--system", "X", "--dry-run", "--export", "plan.json"]), Ok(CliCommand::PlanBackup(Some(_), Some(_)))));
+        assert!(matches!(parse(&["restore", "--system", "X", "--archive", "latest", "--move-aside", "--preview"]), Ok(CliCommand::Restore(_))));
+        assert!(matches!(parse(&["list-backups", "--system", "X"]), Ok(CliCommand::ListBackups(Some(_)))));
+        assert!(matches!(parse(&["prune", "--all", "--dry-run"]), Ok(CliCommand::Prune(None, true))));
+        assert!(matches!(parse(&["list-systems"]), Ok(CliCommand::ListSystems)));
+    }
+
+    #[test]
+    fn options_the_command_does_not_use_are_refused() {
+        assert!(parse(&["backup", "--system", "X", "--move-aside"]).is_err());
+        assert!(parse(&["backup", "--all", "--dry-run", "--workers", "2"]).is_err());
+        assert!(parse(&["list-backups", "--all"]).is_err());
+        assert!(parse(&["show-backup", "--archive", "a.zip", "--system", "X"]).is_err());
+        assert!(parse(&["prune", "--all", "--preview"]).is_err());
+        assert!(parse(&["verify", "--all", "--dry-run"]).is_err());
+        assert!(parse(&["export", "--archive", "a", "--to", "b", "--all"]).is_err());
+        assert!(parse(&["validate-config", "--system", "X"]).is_err());
+        assert!(parse(&["backup", "--all", "--bogus"]).is_err());
+    }
+}
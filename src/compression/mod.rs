@@ -0,0 +1,115 @@
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use serde::*;
+
+use crate::error::Error;
+
+//How the content of a file in a zip is compressed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionMethod {
+    //No compression at all, the fastest for files that don't get smaller anyway
+    #[serde(rename = "stored")]
+    Stored,
+    #[serde(rename = "deflate")]
+    #[default]
+    Deflate,
+    #[serde(rename = "bzip2")]
+    Bzip2,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+impl CompressionMethod {
+    //Levels the method accepts. None if it has no levels
+    pub fn level_range(&self) -> Option<RangeInclusive<i64>> {
+        match self {
+            CompressionMethod::Stored => None,
+            CompressionMethod::Deflate => Some(1..=9),
+            CompressionMethod::Bzip2 => Some(1..=9),
+            CompressionMethod::Zstd => Some(1..=22),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionMethod::Stored => "stored",
+            CompressionMethod::Deflate => "deflate",
+            CompressionMethod::Bzip2 => "bzip2",
+            CompressionMethod::Zstd => "zstd",
+        }
+    }
+
+    //Checks if the level can be used with this method. A method without levels ignores it
+    pub fn validate_level(&self, level: i64) -> Result<(), Error> {
+        match self.level_range() {
+            None => Ok(()),
+            Some(range) if !range.contains(&level) => Err(Error::new_s(format!("Compression level {} is not between {} and {} as {} needs it", level, range.start(), range.end(), self.name()))),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+//Extensions of files that are compressed already, like images, videos and archives. Compressing them again costs time and saves next to nothing
+const COMPRESSED_EXTENSIONS: [&str; 24] = [
+    "jpg", "jpeg", "png", "gif", "webp", "heic",
+    "mp4", "mov", "avi", "mkv", "m4v", "webm",
+    "mp3", "m4a", "aac", "ogg", "flac",
+    "zip", "7z", "rar", "gz", "bz2", "xz", "zst",
+];
+
+//How the files of a system or of a backup_rel_path are compressed. Every field that is not set for a backup_rel_path is taken from its system
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Compression {
+    //"stored", "deflate", "bzip2" or "zstd". Defaults to deflate
+    pub method: Option<CompressionMethod>,
+    //Higher levels give smaller backups but take longer. Defaults to the default level of the method
+    pub level: Option<i64>,
+    //Stores images, videos and archives as they are instead of compressing them again. Defaults to false
+    pub store_compressed: Option<bool>,
+}
+
+//The compression a single file gets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileCompression {
+    pub method: CompressionMethod,
+    pub level: Option<i64>,
+}
+
+impl Compression {
+    //These settings with every field that is not set taken from fallback
+    pub fn or(&self, fallback: &Compression) -> Compression {
+        Compression {
+            method: self.method.or(fallback.method),
+            level: self.level.or(fallback.level),
+            store_compressed: self.store_compressed.or(fallback.store_compressed),
+        }
+    }
+
+    //Checks if the level fits the method
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.level {
+            Some(level) => self.method.unwrap_or_default().validate_level(level),
+            None => Ok(()),
+        }
+    }
+
+    //Finds out how a file with this name is compressed
+    pub fn for_file(&self, name: &str) -> FileCompression {
+        let is_compressed = Path::new(name).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| COMPRESSED_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+            .unwrap_or(false);
+        if is_compressed && self.store_compressed.unwrap_or(false) {
+            return FileCompression {
+                method: CompressionMethod::Stored,
+                level: None,
+            };
+        }
+        let method = self.method.unwrap_or_default();
+        FileCompression {
+            method,
+            level: method.level_range().and(self.level),
+        }
+    }
+}
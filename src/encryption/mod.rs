@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use serde::*;
+
+use crate::error::Error;
+use crate::progress::Progress;
+
+//Where the passphrase of encrypted backups comes from. The config only names where to find it, never the passphrase itself
+//The sources are tried in the order passphrase_env, key_file, prompt
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Encryption {
+    //Name of an environment variable that holds the passphrase
+    pub passphrase_env: Option<String>,
+    //Path of a file whose first line is the passphrase
+    pub key_file: Option<String>,
+    //Asks for the passphrase in the menu. Not possible in the command line mode, which has to run without anybody at the console
+    pub prompt: Option<bool>,
+}
+
+//Passphrases shorter than this are refused, as the content of the backups is only as safe as the passphrase
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+
+impl Encryption {
+    //Checks if at least one source of the passphrase is set
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.passphrase_env.is_none() && self.key_file.is_none() && !self.prompt.unwrap_or(false) {
+            return Err(Error::new_s("encryption needs passphrase_env, key_file or \"prompt\": true"));
+        }
+        Ok(())
+    }
+
+    //Gets the passphrase from the first source that has one. With confirm a prompted passphrase has to be entered twice, so a typo can't make new backups unreadable
+    pub fn passphrase(&self, progress: &mut dyn Progress, system_name: &str, confirm: bool) -> Result<String, Error> {
+        if let Some(variable) = self.passphrase_env.as_ref() {
+            if let Ok(passphrase) = std::env::var(variable) {
+                return checked_passphrase(passphrase, &format!("Environment variable {}", variable));
+            }
+        }
+        if let Some(key_file) = self.key_file.as_ref() {
+            if Path::new(key_file).exists() {
+                let content = std::fs::read_to_string(key_file)?;
+                let passphrase = content.lines().next().unwrap_or_default().to_string();
+                return checked_passphrase(passphrase, &format!("Key file {}", key_file));
+            }
+        }
+        if self.prompt.unwrap_or(false) {
+            let passphrase = match progress.ask_passphrase(&format!("Passphrase for the backups of {}: ", system_name)) {
+                Some(passphrase) => passphrase,
+                None => return Err(Error::new_s(format!("The passphrase for {} can only be entered in the menu. Set passphrase_env or key_file to run without it", system_name))),
+            };
+            if confirm && progress.ask_passphrase("Repeat the passphrase: ").as_ref() != Some(&passphrase) {
+                return Err(Error::new_s("The passphrases do not match"));
+            }
+            return checked_passphrase(passphrase, "The entered passphrase");
+        }
+        Err(Error::new_s(format!("No passphrase found for {}. Check passphrase_env and key_file of its encryption", system_name)))
+    }
+}
+
+fn checked_passphrase(passphrase: String, source: &str) -> Result<String, Error> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LENGTH {
+        return Err(Error::new_s(format!("{} holds a passphrase with less than {} characters", source, MIN_PASSPHRASE_LENGTH)));
+    }
+    Ok(passphrase)
+}
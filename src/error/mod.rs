@@ -4,11 +4,14 @@ use std::path::StripPrefixError;
 use zip::result::ZipError;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     IOError(std::io::Error),
     SerdeJsonError(serde_json::Error),
     StripPrefixError(StripPrefixError),
     ZipError(ZipError),
+    SshError(ssh2::Error),
+    HttpError(Box<ureq::Error>),
     Custom(Vec<String>),
 }
 
@@ -19,6 +22,8 @@ impl Display for Error {
             Error::SerdeJsonError(e) => e.fmt(f),
             Error::StripPrefixError(e) => e.fmt(f),
             Error::ZipError(e) => e.fmt(f),
+            Error::SshError(e) => e.fmt(f),
+            Error::HttpError(e) => e.fmt(f),
             Error::Custom(e) => {
                 let mut err = "".to_string();
                 for e in e.iter() {
@@ -37,6 +42,8 @@ impl Error {
             Error::SerdeJsonError(e) => vec![e.to_string()],
             Error::StripPrefixError(e) => vec![e.to_string()],
             Error::ZipError(e) => vec![e.to_string()],
+            Error::SshError(e) => vec![e.to_string()],
+            Error::HttpError(e) => vec![e.to_string()],
             Error::Custom(e) => e
         }
     }
@@ -66,6 +73,18 @@ impl From<ZipError> for Error {
     }
 }
 
+impl From<ssh2::Error> for Error {
+    fn from(e: ssh2::Error) -> Self {
+        Error::SshError(e)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        Error::HttpError(Box::new(e))
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl Error {
@@ -0,0 +1,244 @@
+use std::convert::TryFrom;
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime};
+
+use serde::*;
+
+use crate::catalog::format_size;
+
+//A number or a text in the config file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigValue {
+    Number(u64),
+    Text(String),
+}
+
+//Splits a text like 500MB or 7d into the number and the unit
+fn split_unit(text: &str) -> Result<(f64, String), String> {
+    let text = text.trim();
+    let unit_start = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let number = text[..unit_start].parse::<f64>().map_err(|_| format!("{} does not start with a number", text))?;
+    Ok((number, text[unit_start..].trim().to_lowercase()))
+}
+
+//Multiplies the number of a text with the factor of its unit, as long as the result fits
+fn scale(number: f64, factor: u64, text: &str) -> Result<u64, String> {
+    let value = number * factor as f64;
+    if value >= u64::MAX as f64 {
+        return Err(format!("{} is too large", text));
+    }
+    Ok(value as u64)
+}
+
+//A file size in the config, either a number of bytes or a text like 500KB, 20MB or 2GB. KB is 1024 bytes, the same as KiB
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "ConfigValue")]
+pub struct ByteSize(pub u64);
+
+impl TryFrom<ConfigValue> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: ConfigValue) -> Result<Self, Self::Error> {
+        let text = match value {
+            ConfigValue::Number(bytes) => return Ok(ByteSize(bytes)),
+            ConfigValue::Text(text) => text,
+        };
+        let (number, unit) = split_unit(&text)?;
+        let factor: u64 = match unit.as_str() {
+            "" | "b" => 1,
+            "kb" | "kib" => 1024,
+            "mb" | "mib" => 1024 * 1024,
+            "gb" | "gib" => 1024 * 1024 * 1024,
+            "tb" | "tib" => 1024 * 1024 * 1024 * 1024,
+            unit => return Err(format!("Unknown size unit {} in {}. Use B, KB, MB, GB or TB", unit, text)),
+        };
+        Ok(ByteSize(scale(number, factor, &text)?))
+    }
+}
+
+//A file age in the config, either a number of seconds or a text like 30m, 12h, 7d or 2w
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "ConfigValue")]
+pub struct Age(pub Duration);
+
+impl TryFrom<ConfigValue> for Age {
+    type Error = String;
+
+    fn try_from(value: ConfigValue) -> Result<Self, Self::Error> {
+        let text = match value {
+            ConfigValue::Number(seconds) => return Ok(Age(Duration::from_secs(seconds))),
+            ConfigValue::Text(text) => text,
+        };
+        let (number, unit) = split_unit(&text)?;
+        let factor: u64 = match unit.as_str() {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            unit => return Err(format!("Unknown time unit {} in {}. Use s, m, h, d or w", unit, text)),
+        };
+        Ok(Age(Duration::from_secs(scale(number, factor, &text)?)))
+    }
+}
+
+//Size and age limits for the files of a backup path
+pub struct FileFilter {
+    pub max_file_size: Option<ByteSize>,
+    pub min_file_size: Option<ByteSize>,
+    //Only files changed within this time are backed up
+    pub modified_within: Option<Age>,
+    //Only files that haven't changed for this time are backed up
+    pub modified_before: Option<Age>,
+}
+
+impl FileFilter {
+    //Returns why a file has to be skipped or None if it passes all limits
+    pub fn skip_reason(&self, metadata: &Metadata, now: SystemTime) -> Option<String> {
+        let size = metadata.len();
+        if let Some(max_file_size) = self.max_file_size {
+            if size > max_file_size.0 {
+                return Some(format!("{} is larger than max_file_size {}", format_size(size), format_size(max_file_size.0)));
+            }
+        }
+        if let Some(min_file_size) = self.min_file_size {
+            if size < min_file_size.0 {
+                return Some(format!("{} is smaller than min_file_size {}", format_size(size), format_size(min_file_size.0)));
+            }
+        }
+        if self.modified_within.is_none() && self.modified_before.is_none() {
+            return None;
+        }
+        //Files modified in the future count as just modified
+        let age = metadata.modified().ok()
+            .map(|modified| now.duration_since(modified).unwrap_or_default());
+        let age = match age {
+            Some(age) => age,
+            None => return Some("modification time can't be read".to_string()),
+        };
+        if let Some(modified_within) = self.modified_within {
+            if age > modified_within.0 {
+                return Some(format!("last modified {} ago, not within modified_within", format_age(age)));
+            }
+        }
+        if let Some(modified_before) = self.modified_before {
+            if age < modified_before.0 {
+                return Some(format!("last modified {} ago, not before modified_before", format_age(age)));
+            }
+        }
+        None
+    }
+}
+
+//Formats a duration in the largest fitting unit
+fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    if seconds >= 24 * 60 * 60 {
+        format!("{} days", seconds / (24 * 60 * 60))
+    } else if seconds >= 60 * 60 {
+        format!("{} hours", seconds / (60 * 60))
+    } else if seconds >= 60 {
+        format!("{} minutes", seconds / 60)
+    } else {
+        format!("{} seconds", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime};
+
+    use crate::test_util::TempDir;
+
+    use super::{Age, ByteSize, ConfigValue, FileFilter};
+
+    fn size(text: &str) -> Result<u64, String> {
+        ByteSize::try_from(ConfigValue::Text(text.to_string())).map(|size| size.0)
+    }
+
+    fn age(text: &str) -> Result<u64, String> {
+        Age::try_from(ConfigValue::Text(text.to_string())).map(|age| age.0.as_secs())
+    }
+
+    #[test]
+    fn sizes_are_read_with_and_without_units() {
+        assert_eq!(ByteSize::try_from(ConfigValue::Number(123)).unwrap().0, 123);
+        assert_eq!(size("123").unwrap(), 123);
+        assert_eq!(size("123B").unwrap(), 123);
+        assert_eq!(size("10 MB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(size("500kb").unwrap(), 500 * 1024);
+        assert_eq!(size("1.5GiB").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(size(" 2 TB ").unwrap(), 2 * 1024 * 1024 * 1024 * 1024);
+        assert_eq!(size("20MiB").unwrap(), size("20MB").unwrap());
+    }
+
+    #[test]
+    fn sizes_with_bad_units_or_numbers_are_refused() {
+        assert!(size("10 XB").is_err());
+        assert!(size("10 MBs").is_err());
+        assert!(size("MB").is_err());
+        assert!(size("").is_err());
+        assert!(size("1.2.3MB").is_err());
+        assert!(size("-5MB").is_err());
+        assert!(size("99999999999TB").is_err());
+        assert!(size("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn ages_are_read_with_and_without_units() {
+        assert_eq!(Age::try_from(ConfigValue::Number(90)).unwrap().0, Duration::from_secs(90));
+        assert_eq!(age("90").unwrap(), 90);
+        assert_eq!(age("90s").unwrap(), 90);
+        assert_eq!(age("10m").unwrap(), 10 * 60);
+        assert_eq!(age("1.5h").unwrap(), 90 * 60);
+        assert_eq!(age("30d").unwrap(), 30 * 24 * 60 * 60);
+        assert_eq!(age("2 W").unwrap(), 14 * 24 * 60 * 60);
+    }
+
+    #[test]
+    fn ages_with_bad_units_or_numbers_are_refused() {
+        assert!(age("30 days").is_err());
+        assert!(age("30y").is_err());
+        assert!(age("d").is_err());
+        assert!(age("-1d").is_err());
+        assert!(age("99999999999999999999w").is_err());
+    }
+
+    fn filter() -> FileFilter {
+        FileFilter { max_file_size: None, min_file_size: None, modified_within: None, modified_before: None }
+    }
+
+    #[test]
+    fn sizes_on_the_limits_pass() {
+        let folder = TempDir::new("filter_sizes");
+        let metadata = std::fs::metadata(folder.write("a.txt", &[0; 100])).unwrap();
+        let now = SystemTime::now();
+        let limits = |min: u64, max: u64| FileFilter { min_file_size: Some(ByteSize(min)), max_file_size: Some(ByteSize(max)), ..filter() };
+
+        assert_eq!(filter().skip_reason(&metadata, now), None);
+        assert_eq!(limits(100, 100).skip_reason(&metadata, now), None);
+        assert!(limits(0, 99).skip_reason(&metadata, now).unwrap().contains("larger than max_file_size"));
+        assert!(limits(101, 200).skip_reason(&metadata, now).unwrap().contains("smaller than min_file_size"));
+    }
+
+    #[test]
+    fn ages_on_the_limits_pass() {
+        let folder = TempDir::new("filter_ages");
+        let metadata = std::fs::metadata(folder.write("a.txt", b"a")).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        let an_hour_later = metadata.modified().unwrap() + hour;
+        let within = |age: Duration| FileFilter { modified_within: Some(Age(age)), ..filter() };
+        let before = |age: Duration| FileFilter { modified_before: Some(Age(age)), ..filter() };
+
+        assert_eq!(within(hour).skip_reason(&metadata, an_hour_later), None);
+        assert!(within(hour - Duration::from_secs(1)).skip_reason(&metadata, an_hour_later).unwrap().contains("not within modified_within"));
+        assert_eq!(before(hour).skip_reason(&metadata, an_hour_later), None);
+        assert!(before(hour + Duration::from_secs(1)).skip_reason(&metadata, an_hour_later).unwrap().contains("not before modified_before"));
+        //Files modified in the future count as just modified
+        let an_hour_earlier = metadata.modified().unwrap() - hour;
+        assert_eq!(within(Duration::from_secs(1)).skip_reason(&metadata, an_hour_earlier), None);
+        assert!(before(Duration::from_secs(1)).skip_reason(&metadata, an_hour_earlier).is_some());
+    }
+}
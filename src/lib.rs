@@ -0,0 +1,27 @@
+//Backs up, restores and verifies MagicQ consoles, pc installations and other show folders
+//The systems are loaded with systems::load_validated_consoles_and_local_installations and backed up, restored and verified through LocalInstallation
+//Progress is reported through the progress::Progress trait, so the backups can be embedded in other programs. The mq_backuper binary is one front end to them
+
+pub mod archive;
+pub mod backup_plan;
+pub mod catalog;
+pub mod chain;
+pub mod compression;
+pub mod encryption;
+pub mod error;
+pub mod local_installation;
+pub mod manifest;
+pub mod progress;
+pub mod repository;
+pub mod restore;
+pub mod retention;
+pub mod storage;
+pub mod systems;
+pub mod verify;
+mod file_filter;
+mod mirror;
+mod patterns;
+#[cfg(test)]
+mod test_util;
+mod zip;
+mod zip_name;
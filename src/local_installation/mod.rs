@@ -1,23 +1,88 @@
-use std::fs::create_dir_all;
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use serde::*;
 
+use crate::archive::ArchiveFormat;
+use crate::backup_plan::BackupPlan;
+use crate::catalog::{BackupEntry, format_size, merge_catalogs};
+use crate::chain::{ChainLink, files_at, is_encrypted};
+use crate::compression::Compression;
+use crate::encryption::Encryption;
 use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::mirror::{fetch_backup, load_stored_catalog, load_stored_chain, mirror_backup, stored_blobs};
+use crate::progress::{CombinedProgress, Counts, format_duration, Progress, throughput};
+use crate::repository::{copy_to_repository, Repository};
+use crate::retention::{prune, Retention};
+use crate::restore::{preview_restore, restore_from_zip, RestoreOptions, RestorePreview};
+use crate::storage::{DestConfig, Storage, TempFolder};
 use crate::systems::BackupRelPath;
-use crate::tui::TUI;
-use crate::zip::copy_to_zip;
-use crate::zip_name::get_zip_path;
+use crate::verify::{verify_backup, VerifyReport};
+use crate::zip::{copy_to_zip, CopySettings, DEFAULT_BUFFER_SIZE, DeltaBase, plan_zip, UnchangedCheck};
+use crate::zip_name::{get_zip_path, list_partial_paths};
+
+//One dest or a list of dests in the config
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum Dest {
+    One(DestConfig),
+    Many(Vec<DestConfig>),
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct LocalInstallation {
     pub name: String,
     src: String,
-    dest: String,
+    //One dest or a list of dests. The backup is made in a temporary folder and then copied to all of them. New backups build on the latest one in the first dest
+    //A dest is the path of a folder or a storage like {"type": "sftp", ...} or {"type": "s3", ...}
+    dest: Dest,
     pub backup_rel_paths: Vec<BackupRelPath>,
+    retention: Option<Retention>,
+    //Doesn't create a new backup if nothing changed since the latest one. Defaults to true
+    skip_unchanged: Option<bool>,
+    //Also compares the content of the files to find out if something changed, not only size and modification time
+    compare_hashes: Option<bool>,
+    //Reads the new backup back and compares it with the source before reporting success
+    verify_after_backup: Option<bool>,
+    //Size in bytes of the buffer files are streamed through while zipping. Defaults to 64 KB
+    buffer_size: Option<usize>,
+    //Backups only contain the files added or changed since the previous backup and a list of the deleted ones
+    incremental: Option<bool>,
+    //Number of incremental backups after a full backup before the next full backup is made. Defaults to 6
+    max_deltas: Option<usize>,
+    //Stores the backups as snapshots in a deduplicating repository in dest instead of zips. incremental has no effect then
+    repository: Option<bool>,
+    //Format of the backups: "zip", "tar.gz", "tar.zst" or "directory" for a plain copy of the files. Defaults to zip
+    archive_format: Option<ArchiveFormat>,
+    //How the files are compressed. Every backup_rel_path can override it. For tar.gz and tar.zst only the level is used, for the whole archive
+    compression: Option<Compression>,
+    //Encrypts the content of the backups with AES-256. Only names where the passphrase comes from. Needs archive_format zip
+    encryption: Option<Encryption>,
 }
 
+//Default for max_deltas
+const DEFAULT_MAX_DELTAS: usize = 6;
+
 impl LocalInstallation {
+    //Path of the system that gets backed up
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+    //All dests the backups of the system are stored in, the one new backups build on first
+    pub fn dests(&self) -> Vec<&DestConfig> {
+        match &self.dest {
+            Dest::One(dest) => vec![dest],
+            Dest::Many(dests) => dests.iter().collect(),
+        }
+    }
+    //Compression of the system, with nothing set if the config has none
+    fn compression(&self) -> Compression {
+        self.compression.clone().unwrap_or_default()
+    }
     //Validates if the specified path and its specified paths exist. Otherwise it returns an error with information to show to the user
     pub fn validate(&self) -> Result<(), Error> {
         let main_path = Path::new(&self.src);
@@ -27,26 +92,554 @@ impl LocalInstallation {
         if self.backup_rel_paths.is_empty() {
             return Err(Error::new_s(format!("No backup folders specified for {} system", self.name)));
         }
+        let dests = self.dests();
+        if dests.is_empty() {
+            return Err(Error::new_s(format!("No dest specified for {} system", self.name)));
+        }
+        for (i, dest) in dests.iter().enumerate() {
+            if let Err(err) = dest.validate() {
+                return Err(Error::new_j(format!("dest of {} is invalid", self.name), err));
+            }
+            if dests[..i].iter().any(|other| Path::new(&other.describe()) == Path::new(&dest.describe())) {
+                return Err(Error::new_s(format!("{} is specified more than once as dest of {}", dest.describe(), self.name)));
+            }
+        }
+        let archive_format = self.archive_format.unwrap_or_default();
         for folder in self.backup_rel_paths.iter() {
             let sub_path = main_path.join(Path::new(&folder.rel_path));
             if !sub_path.exists() {
                 return Err(Error::new_s(format!("{} for {} does not exist", sub_path.display(), self.name)));
             }
+            if let Err(err) = folder.included_patterns() {
+                return Err(Error::new_j(format!("included_files of {} for {} are invalid", folder.rel_path, self.name), err));
+            }
+            if let Err(err) = folder.excluded_dir_patterns() {
+                return Err(Error::new_j(format!("excluded_dirs of {} for {} are invalid", folder.rel_path, self.name), err));
+            }
+            if let Err(err) = folder.excluded_patterns() {
+                return Err(Error::new_j(format!("excluded_files of {} for {} are invalid", folder.rel_path, self.name), err));
+            }
+            //Only zips compress every file on its own, the other formats don't use the compression of a backup_rel_path
+            if archive_format == ArchiveFormat::Zip {
+                if let Err(err) = folder.compression.clone().unwrap_or_default().or(&self.compression()).validate() {
+                    return Err(Error::new_j(format!("compression of {} for {} is invalid", folder.rel_path, self.name), err));
+                }
+            }
+        }
+        if let Some(encryption) = self.encryption.as_ref() {
+            if let Err(err) = encryption.validate() {
+                return Err(Error::new_j(format!("encryption of {} is invalid", self.name), err));
+            }
+            if archive_format != ArchiveFormat::Zip || self.repository.unwrap_or(false) {
+                return Err(Error::new_s(format!("encryption of {} needs archive_format zip and no repository", self.name)));
+            }
+        }
+        if let (Some(method), Some(level)) = (archive_format.stream_method(), self.compression().level) {
+            if let Err(err) = method.validate_level(level) {
+                return Err(Error::new_j(format!("compression of {} is invalid for {}", self.name, archive_format.extension()), err));
+            }
         }
         Ok(())
     }
-    //Returns ture if program can continue
-    pub fn backup(self, tui: &mut TUI) -> Result<String, Error> {
-        tui.write_title(format!("Backing up {}", self.name));
+    //Makes a backup in a temporary folder and copies it to every dest. New backups are compared with and build on the latest backup in the first dest
+    //Only fails if the backup itself fails or no dest got it, failed copies are listed in the report
+    pub fn backup(self, progress: &mut dyn Progress) -> Result<BackupReport, Error> {
+        let started = Instant::now();
+        progress.title(&format!("Backing up {}", self.name));
 
-        let dest = Path::new(&self.dest);
-        if !dest.exists() {
-            create_dir_all(dest)?;
+        let first_dest = self.dests()[0];
+        let source = match first_dest.open() {
+            Ok(source) => source,
+            Err(err) => return Err(Error::new_j(format!("Backup of {} failed, as {} can't be opened", self.name, first_dest.describe()), err)),
+        };
+        let staging = TempFolder::new("staging")?;
+        let archive_format = self.archive_format.unwrap_or_default();
+        let is_repository = self.repository.unwrap_or(false);
+        let mut message = String::new();
+        for partial_zip in self.partial_backups()?.iter() {
+            progress.warn(&format!("Found {} from an interrupted backup", partial_zip.display()));
+            message.push_str(&format!("\nWarning: {} is left over from an interrupted backup and is no valid backup\n", partial_zip.display()));
+        }
+        //The latest backup with all of its files. A latest backup that can't be read is no reason to skip a new one, it just can't be a base
+        let catalog = match load_stored_catalog(&self.name, source.as_ref()) {
+            Ok(catalog) => catalog,
+            Err(err) => return Err(Error::new_j(format!("Backup of {} failed, as the backups in {} can't be read", self.name, first_dest.describe()), err)),
+        };
+        let latest = match catalog.into_iter().next() {
+            Some(latest) => load_stored_chain(source.as_ref(), &latest).ok().map(|chain| (PathBuf::from(latest.location()), chain)),
+            None => None,
+        };
+        let unchanged_check = match &latest {
+            Some((latest_zip, chain)) if self.skip_unchanged.unwrap_or(true) => Some(UnchangedCheck {
+                latest_zip: latest_zip.clone(),
+                latest_files: files_at(chain).into_values().map(|f| f.file).collect(),
+            }),
+            _ => None,
+        };
+        //The chain already has the full backup and its deltas, so a full backup is made once it has max_deltas deltas
+        //A repository uses the latest backup to find the files it doesn't have to read again
+        let builds_on_latest = |chain_length: usize| is_repository || (self.incremental.unwrap_or(false) && chain_length <= self.max_deltas.unwrap_or(DEFAULT_MAX_DELTAS));
+        let delta_base = match &latest {
+            Some((latest_zip, chain)) if builds_on_latest(chain.len()) => Some(DeltaBase {
+                base_zip: latest_zip.clone(),
+                base_files: files_at(chain).into_values().map(|f| f.file).collect(),
+            }),
+            _ => None,
+        };
+        let passphrase = match self.encryption.as_ref() {
+            Some(encryption) => match encryption.passphrase(progress, &self.name, true) {
+                Ok(passphrase) => Some(passphrase),
+                Err(err) => return Err(Error::new_j(format!("Backup of {} failed", self.name), err)),
+            },
+            None => None,
+        };
+        let settings = CopySettings {
+            unchanged_check,
+            delta_base,
+            compare_hashes: self.compare_hashes.unwrap_or(false),
+            verify_after_backup: self.verify_after_backup.unwrap_or(false),
+            buffer_size: self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            compression: self.compression(),
+            passphrase,
+        };
+        //A snapshot only stores the blobs the first dest doesn't have yet
+        let (staged, copied) = if is_repository {
+            let repository = Repository::staged(staging.path(), stored_blobs(source.as_ref())?);
+            let snapshot = repository.new_snapshot_path(&self.name);
+            progress.info(&format!("Creating {}\n", snapshot.display()));
+            let copied = copy_to_repository(progress, &self.name, &self.src, self.backup_rel_paths.clone(), &repository, &snapshot, settings);
+            (snapshot, copied)
+        } else {
+            let zip = get_zip_path(&self.name, staging.path(), archive_format);
+            progress.info(&format!("Creating {}\n", zip.display()));
+            let copied = copy_to_zip(progress, &self.name, &self.src, self.backup_rel_paths.clone(), &zip, archive_format, settings);
+            (zip, copied)
+        };
+        let copy_result = match copied {
+            Ok(copy_result) => copy_result,
+            Err(err) => return Err(Error::new_j(format!("Backup of {} failed", self.name), err)),
+        };
+        if !copy_result.skipped.is_empty() {
+            message.push_str(&format!("\nSkipped {} files or folders:\n", copy_result.skipped.len()));
+            for skipped in copy_result.skipped.iter() {
+                message.push_str(&format!("  {} ({})\n", skipped.path.display(), skipped.reason));
+            }
+        }
+        let mut failed_copies = Vec::new();
+        if let (Some(latest_zip), Some((_, chain))) = (copy_result.unchanged_since, &latest) {
+            message.push_str(&format!("\n{} unchanged, no archive created. Latest backup is still:\n{}\n", self.name, latest_zip.display()));
+            //A dest that was not reachable during the latest backup still gets it
+            self.copy_to_dests(progress, chain, source.as_ref(), staging.path(), &mut message, &mut failed_copies);
+            message.push('\n');
+            return Ok(BackupReport { message, failed_copies, written: Counts::default(), duration: started.elapsed() });
+        }
+        let manifest = match Manifest::read_from_backup(&staged) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => return Err(Error::new_s(format!("Backup of {} failed, as {} has no manifest", self.name, staged.display()))),
+            Err(err) => return Err(Error::new_j(format!("Backup of {} failed", self.name), err)),
+        };
+        message.push_str(&format!("\nCreated backup file for {}:\n{}\n", self.name, staged.file_name().unwrap_or_default().to_string_lossy()));
+        if let Some(delta) = copy_result.delta {
+            message.push_str(&format!("Incremental backup with {} changed and {} deleted files since {}\n", delta.changed_files, delta.deleted_files, delta.base_zip.display()));
+        }
+        if let Some(verified_files) = copy_result.verified_files {
+            message.push_str(&format!("Verified {} files against the source\n", verified_files));
+        }
+        //An incremental backup is copied with the chain it builds on
+        let mut chain = match (&manifest.base, latest) {
+            (Some(_), Some((_, chain))) => chain,
+            _ => Vec::new(),
+        };
+        chain.push(ChainLink { archive: staged, manifest });
+        let holding_dests = self.copy_to_dests(progress, &chain, source.as_ref(), staging.path(), &mut message, &mut failed_copies);
+        if holding_dests.is_empty() {
+            return Err(Error::new_j(format!("Backup of {} failed, as it could not be copied to any dest", self.name), Error::new(failed_copies)));
+        }
+        if self.retention.is_some() {
+            //The backup itself worked, so a failing prune is only reported. A dest the copy failed for is left alone
+            for dest in holding_dests.into_iter() {
+                match self.prune_dest(progress, dest, false) {
+                    Ok(prune_message) => message.push_str(&prune_message),
+                    Err(err) => message.push_str(&format!("Pruning old backups in {} failed: {}\n", dest.describe(), err.to_string().trim())),
+                }
+            }
+        }
+        message.push('\n');
+        Ok(BackupReport { message, failed_copies, written: copy_result.written, duration: started.elapsed() })
+    }
+    //Copies the backup with its chain to every dest and returns the dests that have it now. A failing copy does not stop the others, its error is added to failed_copies instead
+    //The chain was read from source, the first dest, which is where links that are not in the staging folder are taken from
+    fn copy_to_dests(&self, progress: &mut dyn Progress, chain: &[ChainLink], source: &dyn Storage, staging: &Path, message: &mut String, failed_copies: &mut Vec<String>) -> Vec<&DestConfig> {
+        let mut holding_dests = Vec::new();
+        for (i, dest) in self.dests().into_iter().enumerate() {
+            let copied = if i == 0 {
+                mirror_backup(progress, &self.name, chain, source, source, staging)
+            } else {
+                dest.open().and_then(|storage| mirror_backup(progress, &self.name, chain, storage.as_ref(), source, staging))
+            };
+            match copied {
+                Ok(copied) => {
+                    message.push_str(&format!("Copied to {}: {}\n", dest.describe(), copied));
+                    holding_dests.push(dest);
+                }
+                Err(err) => failed_copies.push(format!("Copying the backup of {} to {} failed: {}", self.name, dest.describe(), err.to_string().trim())),
+            }
+        }
+        holding_dests
+    }
+    //Dry run of backup: lists the files that would be zipped, the ones that would be skipped and why, and the total size without creating any archive
+    pub fn plan_backup(&self, progress: &mut dyn Progress) -> Result<BackupPlan, Error> {
+        plan_zip(progress, &self.name, &self.src, &self.backup_rel_paths)
+    }
+    //Deletes old backups in every dest according to the retention rules. With dry_run it only reports what would be deleted
+    pub fn prune(&self, progress: &mut dyn Progress, dry_run: bool) -> Result<String, Error> {
+        let mut message = String::new();
+        for dest in self.dests().into_iter() {
+            match self.prune_dest(progress, dest, dry_run) {
+                Ok(prune_message) => message.push_str(&prune_message),
+                Err(err) => return Err(Error::new_j(format!("Pruning old backups in {} failed", dest.describe()), err)),
+            }
+        }
+        Ok(message)
+    }
+    fn prune_dest(&self, progress: &mut dyn Progress, dest: &DestConfig, dry_run: bool) -> Result<String, Error> {
+        let storage = dest.open()?;
+        prune(progress, load_stored_catalog(&self.name, storage.as_ref())?, &self.retention.clone().unwrap_or_default(), dry_run, storage.as_ref())
+    }
+    //All backups of this system in the first dest, newest first
+    pub fn backups(&self) -> Result<Vec<BackupEntry>, Error> {
+        load_stored_catalog(&self.name, self.dests()[0].open()?.as_ref())
+    }
+    //The backups of this system in all dests, newest first and those of the first dest first if they were made at the same time
+    //A dest that can't be read does not hide the backups of the others, its error is returned with them
+    pub fn all_backups(&self) -> (Vec<BackupEntry>, Vec<String>) {
+        let mut catalogs = Vec::new();
+        let mut errors = Vec::new();
+        for dest in self.dests().into_iter() {
+            match dest.open().and_then(|storage| load_stored_catalog(&self.name, storage.as_ref())) {
+                Ok(catalog) => catalogs.push(catalog),
+                Err(err) => errors.push(format!("Could not read backups of {} in {}: {}", self.name, dest.describe(), err.to_string().trim())),
+            }
+        }
+        (merge_catalogs(catalogs), errors)
+    }
+    //Path of the backup on this computer. A backup in a remote storage is downloaded first, with all backups it needs to be restored
+    pub fn fetch(&self, progress: &mut dyn Progress, entry: &BackupEntry) -> Result<PathBuf, Error> {
+        let stored = match entry.stored.as_ref() {
+            Some(stored) => stored,
+            None => return Ok(entry.path.clone()),
+        };
+        match self.dests().into_iter().find(|dest| dest.describe() == stored.storage) {
+            Some(dest) => fetch_backup(progress, dest.open()?.as_ref(), entry),
+            None => Err(Error::new_s(format!("{} is no dest of {} anymore", stored.storage, self.name))),
+        }
+    }
+    //Files in the local dests left over from copies of backups of this system that were interrupted
+    pub fn partial_backups(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut partials = Vec::new();
+        for path in self.dests().into_iter().filter_map(|dest| dest.local_path()) {
+            partials.extend(list_partial_paths(&self.name, Path::new(path))?);
+        }
+        Ok(partials)
+    }
+    //Shows what restoring the archive would overwrite without touching any file
+    pub fn preview_restore(&self, archive: &Path) -> Result<RestorePreview, Error> {
+        preview_restore(Path::new(&self.src), archive)
+    }
+    //Extracts the archive back into src
+    pub fn restore(&self, progress: &mut dyn Progress, archive: &Path, options: RestoreOptions) -> Result<String, Error> {
+        progress.title(&format!("Restoring {}", self.name));
+        progress.info(&format!("Extracting {} to {}\n", archive.display(), self.src));
+        let passphrase = self.passphrase_for(progress, archive)?;
+        restore_from_zip(progress, Path::new(&self.src), archive, options, passphrase.as_deref())
+    }
+    //Checks if every file of the backup can still be read and matches its manifest
+    pub fn verify(&self, progress: &mut dyn Progress, archive: &Path) -> Result<VerifyReport, Error> {
+        let passphrase = self.passphrase_for(progress, archive)?;
+        verify_backup(progress, archive, passphrase.as_deref())
+    }
+    //Gets the passphrase if the archive or a backup its chain builds on is encrypted
+    fn passphrase_for(&self, progress: &mut dyn Progress, archive: &Path) -> Result<Option<String>, Error> {
+        if !is_encrypted(archive) {
+            return Ok(None);
+        }
+        match self.encryption.as_ref() {
+            Some(encryption) => encryption.passphrase(progress, &self.name, false).map(Some),
+            None => Err(Error::new_s(format!("{} is encrypted, but {} has no encryption to get the passphrase from", archive.display(), self.name))),
         }
-        let dest_zip = get_zip_path(&self.name, dest);
-        tui.writeln(format!("Creating {}\n", dest_zip.display()));
-        copy_to_zip(tui, self.src, self.backup_rel_paths, &dest_zip)?;
-        Ok(format!("\nCreated backup file for {}:\n{}\n\n", self.name, dest_zip.display()))
     }
 }
 
+//What the backup of a system did
+pub struct BackupReport {
+    pub message: String,
+    //Errors of the copies to further dests that failed. The backup itself is fine then
+    pub failed_copies: Vec<String>,
+    //Files and bytes read from the source, nothing if the system was unchanged
+    pub written: Counts,
+    //How long the backup took, with the copies to further dests and pruning
+    pub duration: Duration,
+}
+
+//How the backup of one of several systems went, for the breakdown after backing up all of them
+pub struct SystemSummary {
+    pub system: String,
+    //None if the backup failed
+    pub written: Option<Counts>,
+    pub duration: Duration,
+}
+
+//Collected results of backing up several systems
+pub struct BackupAllResult {
+    pub successes: Vec<String>,
+    pub errors: Vec<String>,
+    pub summaries: Vec<SystemSummary>,
+    //From the first to the last system, which is less than the time of all systems if they ran at once
+    pub duration: Duration,
+}
+
+impl BackupAllResult {
+    //A table with the files, size, time and throughput of every system and of all of them together
+    pub fn breakdown(&self) -> String {
+        let name_width = self.summaries.iter().map(|summary| summary.system.len()).chain(std::iter::once(5)).max().unwrap_or(5);
+        let line = |name: &str, written: Option<Counts>, duration: Duration| match written {
+            Some(written) => format!("{:<width$}  {:>7} files  {:>10}  {:>7}  {:>10}/s\n", name, written.files, format_size(written.bytes), format_duration(duration), format_size(throughput(written.bytes, duration)), width = name_width),
+            None => format!("{:<width$}  failed after {}\n", name, format_duration(duration), width = name_width),
+        };
+        let mut breakdown = String::new();
+        let mut total = Counts::default();
+        for summary in self.summaries.iter() {
+            breakdown.push_str(&line(&summary.system, summary.written, summary.duration));
+            total.add(summary.written.unwrap_or_default());
+        }
+        breakdown.push_str(&line("Total", Some(total), self.duration));
+        breakdown
+    }
+}
+
+//The system, its result and how long it took
+type TimedBackup = (String, Result<BackupReport, Error>, Duration);
+
+//Systems that are backed up one after another, with their index in the list of all systems
+type Group = Vec<(usize, LocalInstallation)>;
+
+//Backs up one system and measures how long it took, even if it failed
+fn timed_backup(local_installation: LocalInstallation, progress: &mut dyn Progress) -> TimedBackup {
+    let system = local_installation.name.clone();
+    let started = Instant::now();
+    let result = local_installation.backup(progress);
+    (system, result, started.elapsed())
+}
+
+//Splits the systems into groups that can be backed up at the same time. Systems sharing a dest end up in the same group and are backed up one after another,
+//as pruning one of them could otherwise delete blobs of a repository the other one is still writing to
+fn independent_groups(local_installations: Vec<LocalInstallation>) -> Vec<Group> {
+    let mut groups: Vec<(HashSet<String>, Group)> = Vec::new();
+    for (index, local_installation) in local_installations.into_iter().enumerate() {
+        let mut dests: HashSet<String> = local_installation.dests().iter()
+            .map(|dest| dest.describe().trim_end_matches(['/', '\\']).to_string())
+            .collect();
+        let mut members = vec![(index, local_installation)];
+        let mut i = 0;
+        while i < groups.len() {
+            if groups[i].0.is_disjoint(&dests) {
+                i += 1;
+                continue;
+            }
+            let (group_dests, group_members) = groups.remove(i);
+            dests.extend(group_dests);
+            members.extend(group_members);
+        }
+        members.sort_by_key(|(index, _)| *index);
+        groups.push((dests, members));
+    }
+    groups.into_iter().map(|(_, members)| members).collect()
+}
+
+//Backs up all given systems, on up to workers threads at once. A failing system does not stop the others, its error texts are collected instead
+//The results are collected in the order of the given systems, no matter which one finished first
+pub fn backup_all(local_installations: Vec<LocalInstallation>, workers: usize, progress: &mut (dyn Progress + Send)) -> BackupAllResult {
+    let started = Instant::now();
+    let results: Vec<TimedBackup> = if workers <= 1 || local_installations.len() <= 1 {
+        local_installations.into_iter().map(|local_installation| timed_backup(local_installation, progress)).collect()
+    } else {
+        let groups = independent_groups(local_installations);
+        let workers = workers.min(groups.len());
+        let queue = Mutex::new(groups.into_iter());
+        let combined = CombinedProgress::new(progress);
+        let mut results: Vec<(usize, TimedBackup)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers).map(|_| scope.spawn(|| {
+                let mut results = Vec::new();
+                loop {
+                    let group = match queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).next() {
+                        Some(group) => group,
+                        None => return results,
+                    };
+                    for (index, local_installation) in group.into_iter() {
+                        let mut system_progress = combined.system(&local_installation.name);
+                        results.push((index, timed_backup(local_installation, &mut system_progress)));
+                    }
+                }
+            })).collect();
+            //A panicking worker panics the whole backup, like it would without workers
+            handles.into_iter().flat_map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))).collect()
+        });
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    };
+    let mut successes = Vec::new();
+    let mut errors = Vec::new();
+    let mut summaries = Vec::new();
+    for (system, result, duration) in results.into_iter() {
+        match result {
+            Ok(report) => {
+                successes.push(report.message);
+                errors.extend(report.failed_copies);
+                summaries.push(SystemSummary { system, written: Some(report.written), duration: report.duration });
+            }
+            Err(err) => {
+                for e in err.texts().into_iter() {
+                    errors.push(e);
+                }
+                summaries.push(SystemSummary { system, written: None, duration });
+            }
+        }
+    }
+    BackupAllResult {
+        successes,
+        errors,
+        summaries,
+        duration: started.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use crate::progress::NoProgress;
+    use crate::restore::RestoreOptions;
+    use crate::test_util::TempDir;
+
+    use super::LocalInstallation;
+
+    //A system backing up everything below src, with the settings of config added
+    fn system(src: &Path, dest: &Path, config: serde_json::Value) -> LocalInstallation {
+        let mut system = json!({
+            "name": "Test_system-1",
+            "src": src,
+            "dest": dest,
+            "backup_rel_paths": [{"rel_path": "", "include_subfolders": true}],
+        });
+        system.as_object_mut().unwrap().extend(config.as_object().unwrap().clone());
+        serde_json::from_value(system).unwrap()
+    }
+
+    #[test]
+    fn files_named_like_the_manifest_are_backed_up_and_restored() {
+        for format in ["zip", "tar.gz", "tar.zst", "directory"] {
+            let src = TempDir::new("reserved_src");
+            let dest = TempDir::new("reserved_dest");
+            let restored = TempDir::new("reserved_restored");
+            src.write("manifest.json", b"{\"my\": \"own manifest\"}");
+            src.write("show/a.shw", b"show");
+            src.write(".magic_q_backuper/manifest.json", b"not part of the backup");
+
+            system(src.path(), dest.path(), json!({"archive_format": format})).backup(&mut NoProgress).unwrap();
+            let backup = system(src.path(), dest.path(), json!({})).backups().unwrap().remove(0).path;
+            assert!(system(src.path(), dest.path(), json!({})).verify(&mut NoProgress, &backup).unwrap().is_ok(), "{}", format);
+
+            system(restored.path(), dest.path(), json!({})).restore(&mut NoProgress, &backup, RestoreOptions { move_aside: false }).unwrap();
+            assert_eq!(std::fs::read(restored.path().join("manifest.json")).unwrap(), b"{\"my\": \"own manifest\"}", "{}", format);
+            assert_eq!(std::fs::read(restored.path().join("show").join("a.shw")).unwrap(), b"show", "{}", format);
+            assert!(!restored.path().join(".magic_q_backuper").exists(), "{}", format);
+        }
+    }
+
+    #[test]
+    fn incremental_chains_restore_the_latest_state() {
+        for format in ["zip", "tar.zst", "directory"] {
+            let src = TempDir::new("chain_src");
+            let dest = TempDir::new("chain_dest");
+            let config = json!({"archive_format": format, "incremental": true});
+            src.write("a.txt", b"a");
+            src.write("show/b.txt", b"b");
+            src.write("show/c.txt", b"c");
+            system(src.path(), dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+            src.write("show/b.txt", b"b changed");
+            system(src.path(), dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+            std::fs::remove_file(src.path().join("show").join("c.txt")).unwrap();
+            system(src.path(), dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+
+            let backups = system(src.path(), dest.path(), config.clone()).backups().unwrap();
+            assert_eq!(backups.len(), 3, "{}", format);
+            assert!(backups[0].base.is_some() && backups[1].base.is_some() && backups[2].base.is_none(), "{}", format);
+
+            let restored = TempDir::new("chain_restored");
+            system(restored.path(), dest.path(), config.clone()).restore(&mut NoProgress, &backups[0].path, RestoreOptions { move_aside: false }).unwrap();
+            assert_eq!(std::fs::read(restored.path().join("a.txt")).unwrap(), b"a", "{}", format);
+            assert_eq!(std::fs::read(restored.path().join("show").join("b.txt")).unwrap(), b"b changed", "{}", format);
+            assert!(!restored.path().join("show").join("c.txt").exists(), "{}", format);
+
+            //Restoring the middle of the chain gives the state of its time
+            let restored = TempDir::new("chain_restored_middle");
+            system(restored.path(), dest.path(), config.clone()).restore(&mut NoProgress, &backups[1].path, RestoreOptions { move_aside: false }).unwrap();
+            assert_eq!(std::fs::read(restored.path().join("show").join("b.txt")).unwrap(), b"b changed", "{}", format);
+            assert_eq!(std::fs::read(restored.path().join("show").join("c.txt")).unwrap(), b"c", "{}", format);
+        }
+    }
+
+    #[test]
+    fn backups_are_copied_to_every_dest_with_their_chain() {
+        for format in ["zip", "directory"] {
+            let src = TempDir::new("dests_src");
+            let first_dest = TempDir::new("dests_first");
+            let second_dest = TempDir::new("dests_second");
+            let config = json!({"archive_format": format, "incremental": true, "dest": [first_dest.path(), second_dest.path()]});
+            src.write("a.txt", b"a");
+            src.write("show/b.txt", b"b");
+            system(src.path(), first_dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+            //The second dest misses the first backup, like a USB stick that was unplugged
+            std::fs::remove_dir_all(second_dest.path()).unwrap();
+            src.write("show/b.txt", b"b changed");
+            let report = system(src.path(), first_dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+            assert!(report.failed_copies.is_empty(), "{}", format);
+            assert!(report.message.contains("2 backups of its incremental chain"), "{}", format);
+
+            for dest in [&first_dest, &second_dest] {
+                let backups = system(src.path(), dest.path(), json!({})).backups().unwrap();
+                assert_eq!(backups.len(), 2, "{}", format);
+                assert!(backups[0].base.is_some(), "{}", format);
+                let restored = TempDir::new("dests_restored");
+                system(restored.path(), dest.path(), json!({})).restore(&mut NoProgress, &backups[0].path, RestoreOptions { move_aside: false }).unwrap();
+                assert_eq!(std::fs::read(restored.path().join("a.txt")).unwrap(), b"a", "{}", format);
+                assert_eq!(std::fs::read(restored.path().join("show").join("b.txt")).unwrap(), b"b changed", "{}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_store_new_blobs_only_and_prune_unused_ones() {
+        let src = TempDir::new("snapshots_src");
+        let dest = TempDir::new("snapshots_dest");
+        let config = json!({"repository": true, "retention": {"keep_last": 1}});
+        let blob_count = || std::fs::read_dir(dest.path().join("repository").join("blobs")).unwrap()
+            .map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap().count())
+            .sum::<usize>();
+        src.write("a.txt", b"a");
+        src.write("b.txt", b"b");
+        system(src.path(), dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+        assert_eq!(blob_count(), 2);
+        src.write("b.txt", b"b changed");
+        let report = system(src.path(), dest.path(), config.clone()).backup(&mut NoProgress).unwrap();
+        assert_eq!(report.written.files, 1);
+        assert!(report.message.contains("snapshot with 1 new blobs"));
+        //The older snapshot is pruned with the blob only it used
+        let backups = system(src.path(), dest.path(), config.clone()).backups().unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(blob_count(), 2);
+        assert!(system(src.path(), dest.path(), config.clone()).verify(&mut NoProgress, &backups[0].path).unwrap().is_ok());
+    }
+}
@@ -1,14 +1,21 @@
+use mq_backuper::local_installation::{backup_all, BackupAllResult};
+use mq_backuper::storage::remove_download_cache;
+
 use crate::tui::{MenuItem, TUI};
 
+mod cli;
 mod tui;
-mod systems;
-mod local_installation;
-mod error;
-mod zip;
-mod zip_name;
 
 
 fn main() {
+    //Any argument switches to the headless mode that runs exactly one command without prompts
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        let exit_code = cli::run(args);
+        remove_download_cache();
+        std::process::exit(exit_code);
+    }
+
     let mut tui = TUI::new();
     let mut current_menu_item = MenuItem::Home;
     loop {
@@ -19,20 +26,13 @@ fn main() {
             MenuItem::ShowConfigExample => tui.show_config_example(),
             MenuItem::CreateConfigExample => tui.create_config_example(),
             MenuItem::ChooseBackupSystem => tui.show_choose_system_to_backup(),
-            MenuItem::BackupAllSystems(local_installations) => {
-                let mut successes = Vec::new();
-                let mut errors = Vec::new();
-                for local_installation in local_installations.into_iter() {
-                    match local_installation.backup(&mut tui) {
-                        Ok(success_message) => {
-                            successes.push(success_message);
-                        }
-                        Err(err) => {
-                            for e in err.texts().into_iter() {
-                                errors.push(e);
-                            }
-                        }
-                    }
+            MenuItem::BackupAllSystems(local_installations, workers) => {
+                let result = backup_all(local_installations, workers, &mut tui);
+                let breakdown = result.breakdown();
+                let BackupAllResult { mut successes, errors, .. } = result;
+                //The breakdown is only worth showing if something was backed up
+                if !successes.is_empty() {
+                    successes.push(breakdown);
                 }
                 if successes.is_empty() {
                     tui.show_and_confirm_error(errors, MenuItem::ChooseBackupSystem, true)
@@ -45,15 +45,34 @@ fn main() {
             }
             MenuItem::BackupLocalInstallation(local_installation) => {
                 match local_installation.backup(&mut tui) {
-                    Ok(success_message) => {
-                        tui.show_and_confirm_success(vec![success_message], MenuItem::ChooseBackupSystem)
+                    Ok(report) => {
+                        if !report.failed_copies.is_empty() {
+                            let _ = tui.show_and_confirm_error(report.failed_copies, MenuItem::ChooseBackupSystem, true);
+                        }
+                        tui.show_and_confirm_success(vec![report.message], MenuItem::ChooseBackupSystem)
                     }
                     Err(err) => {
                         tui.show_and_confirm_error(err.texts(), MenuItem::ChooseBackupSystem, true)
                     }
                 }
             }
-            MenuItem::ExitProgram() => std::process::exit(0),
+            MenuItem::PreviewBackup(local_installation) => tui.preview_backup(local_installation),
+            MenuItem::ChooseSystemToShowBackups => tui.show_choose_system_to_show_backups(),
+            MenuItem::ShowAllBackups(local_installations) => tui.show_backups(local_installations),
+            MenuItem::ShowBackups(local_installation) => tui.show_backups(vec![local_installation]),
+            MenuItem::ShowBackup(local_installation, entry) => tui.show_backup(local_installation, entry),
+            MenuItem::VerifyBackup(local_installation, archive) => tui.verify_backup(local_installation, archive),
+            MenuItem::ExportBackup(snapshot) => tui.export_backup(snapshot),
+            MenuItem::ChooseSystemToPrune => tui.show_choose_system_to_prune(),
+            MenuItem::PruneAllBackups(local_installations) => tui.prune_backups(local_installations),
+            MenuItem::PruneBackups(local_installation) => tui.prune_backups(vec![local_installation]),
+            MenuItem::ChooseRestoreSystem => tui.show_choose_system_to_restore(),
+            MenuItem::ChooseRestoreArchive(local_installation) => tui.show_choose_archive_to_restore(local_installation),
+            MenuItem::RestoreBackup(local_installation, entry) => tui.restore_backup(local_installation, entry),
+            MenuItem::ExitProgram() => {
+                remove_download_cache();
+                std::process::exit(0)
+            }
         }
     }
 }
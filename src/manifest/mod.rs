@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde::*;
+use sha2::{Digest, Sha256};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::archive::{ArchiveFormat, read_entries};
+use crate::error::Error;
+use crate::repository::{is_snapshot, read_snapshot};
+
+//Folder in the root of every backup with the files of the backup itself. Files of the source in a folder with this name are not backed up, so they can't collide
+pub const RESERVED_DIR: &str = ".magic_q_backuper";
+
+//Name of the entry in every backup that describes the backed up files
+pub const MANIFEST_NAME: &str = ".magic_q_backuper/manifest.json";
+
+//Where backups of earlier versions have their manifest, among the backed up files
+pub const LEGACY_MANIFEST_NAME: &str = "manifest.json";
+
+//Says if an entry of a backup belongs to the backup itself instead of the backed up files
+pub fn is_reserved_entry(name: &str) -> bool {
+    name.split(['/', '\\']).next() == Some(RESERVED_DIR)
+}
+
+//One backed up file as it was in the source when the backup was made
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ManifestFile {
+    //Path relative to the source root, the same as the name of the zip entry
+    pub path: String,
+    pub size: u64,
+    //Seconds since 1970-01-01
+    pub modified: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl ManifestFile {
+    //Reads size and modification time (and optionally the hash) of a file in the source
+    pub fn from_file(path: &Path, relative_name: &str, with_hash: bool) -> Result<ManifestFile, Error> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let sha256 = if with_hash {
+            Some(hash_file(path)?)
+        } else {
+            None
+        };
+        Ok(ManifestFile {
+            path: relative_name.to_string(),
+            size: metadata.len(),
+            modified,
+            sha256,
+        })
+    }
+
+    //Says if both describe the same content. Hashes are only compared with compare_hashes, and a missing hash then counts as a difference
+    pub fn is_same_as(&self, other: &ManifestFile, compare_hashes: bool) -> bool {
+        self.path == other.path && self.size == other.size && self.modified == other.modified
+            && (!compare_hashes || (self.sha256.is_some() && self.sha256 == other.sha256))
+    }
+}
+
+//Describes a backup and all of its files, so the archive can be checked and restored without any other information
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    #[serde(default)]
+    pub system: String,
+    //Absolute source root the backup was made from
+    #[serde(default)]
+    pub src: String,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub username: String,
+    //Version of this program that created the backup
+    #[serde(default)]
+    pub tool_version: String,
+    //Local time in RFC 3339 format
+    #[serde(default)]
+    pub created: String,
+    //File name of the backup in the same folder this incremental backup builds on. None for full backups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    //Files of the base that were deleted since, so a restore does not bring them back
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<String>,
+    //Set if the content of the files is encrypted. The manifest itself never is
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+    //Folders of the backup. Snapshots of a repository need them to restore the folders, archives only list them to count their entries
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<String>,
+    //For incremental backups only the files that were added or changed since the base
+    pub files: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    //Manifest of a backup created now on this computer
+    pub fn new(system: &str, src: &str, files: Vec<ManifestFile>) -> Manifest {
+        Manifest {
+            system: system.to_string(),
+            src: src.to_string(),
+            hostname: whoami::fallible::hostname().unwrap_or_default(),
+            username: whoami::username(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: chrono::offset::Local::now().to_rfc3339(),
+            base: None,
+            deleted: Vec::new(),
+            encrypted: false,
+            dirs: Vec::new(),
+            files,
+        }
+    }
+
+    //Reads the manifest out of a backup zip. Returns None for backups that were made without a manifest
+    pub fn read_from_zip(zip_path: &Path) -> Result<Option<Manifest>, Error> {
+        let mut zip = ZipArchive::new(File::open(zip_path)?)?;
+        let name = if zip.index_for_name(MANIFEST_NAME).is_some() { MANIFEST_NAME } else { LEGACY_MANIFEST_NAME };
+        let mut entry = match zip.by_name(name) {
+            Ok(entry) => entry,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut json = String::new();
+        entry.read_to_string(&mut json)?;
+        Ok(Some(serde_json::from_str(&json)?))
+    }
+
+    //Reads the manifest of a backup in any archive format or of a snapshot in a repository, see read_from_zip
+    pub fn read_from_backup(backup_path: &Path) -> Result<Option<Manifest>, Error> {
+        if is_snapshot(backup_path) {
+            return read_snapshot(backup_path).map(Some);
+        }
+        match ArchiveFormat::of_path(backup_path).unwrap_or_default() {
+            ArchiveFormat::Zip => Manifest::read_from_zip(backup_path),
+            ArchiveFormat::Directory => {
+                let manifest_path = [MANIFEST_NAME, LEGACY_MANIFEST_NAME].iter().map(|name| backup_path.join(name)).find(|path| path.exists());
+                match manifest_path {
+                    Some(manifest_path) => Ok(Some(serde_json::from_reader(File::open(manifest_path)?)?)),
+                    None => Ok(None),
+                }
+            }
+            //A tar has no index, so it is read until the manifest, which is the last entry
+            ArchiveFormat::TarGz | ArchiveFormat::TarZst => {
+                let mut manifest = None;
+                let mut legacy_manifest = None;
+                read_entries(backup_path, None, |entry| {
+                    if entry.name == MANIFEST_NAME {
+                        manifest = Some(serde_json::from_reader(entry.content)?);
+                    } else if entry.name == LEGACY_MANIFEST_NAME {
+                        legacy_manifest = serde_json::from_reader(entry.content).ok();
+                    }
+                    Ok(())
+                })?;
+                Ok(manifest.or(legacy_manifest))
+            }
+        }
+    }
+
+    //One line description of where and by whom the backup was made
+    pub fn origin(&self) -> String {
+        format!("Backup of {} ({}) made by {} on {} at {} with version {}", self.system, self.src, self.username, self.hostname, self.created, self.tool_version)
+    }
+
+    //One line description of the base of an incremental backup. None for full backups
+    pub fn delta_description(&self) -> Option<String> {
+        self.base.as_ref().map(|base| format!("Incremental backup based on {} with {} changed and {} deleted files", base, self.files.len(), self.deleted.len()))
+    }
+
+    //Says if the manifest describes the same set of files as the given list. See ManifestFile::is_same_as
+    pub fn has_same_files(&self, other_files: &[ManifestFile], compare_hashes: bool) -> bool {
+        if self.files.len() != other_files.len() {
+            return false;
+        }
+        let mut own_files: Vec<&ManifestFile> = self.files.iter().collect();
+        let mut other_files: Vec<&ManifestFile> = other_files.iter().collect();
+        own_files.sort_by(|a, b| a.path.cmp(&b.path));
+        other_files.sort_by(|a, b| a.path.cmp(&b.path));
+        own_files.iter().zip(other_files.iter()).all(|(a, b)| a.is_same_as(b, compare_hashes))
+    }
+}
+
+//Calculates the SHA-256 of a file as lowercase hex
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
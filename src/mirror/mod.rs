@@ -0,0 +1,436 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::catalog::{BackupEntry, load_catalog, StoredBackup};
+use crate::chain::{ChainLink, load_chain};
+use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::progress::Progress;
+use crate::repository::{blob_key, blobs_key, is_snapshot, read_snapshot, SNAPSHOT_EXTENSION, snapshots_key};
+use crate::storage::{download_completely, Storage, StoredFile};
+use crate::zip_name::{backup_name_prefix, list_zip_paths, parse_backup_name, parse_zip_name};
+
+//A copy of the manifest is stored next to every archive in a storage, so its backups can be listed without downloading them
+//It is uploaded after the archive, so an archive without it is an interrupted copy
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+//Path of a stored file on this computer. A local storage is read where it is, the files of a remote one are downloaded once per run
+//Stored files are never changed, only deleted, so a file that was downloaded before is still the same
+fn fetch_file(storage: &dyn Storage, key: &str) -> Result<PathBuf, Error> {
+    if let Some(root) = storage.local_root() {
+        return Ok(root.join(key));
+    }
+    let path = storage.cache_dir().join(key);
+    if !path.exists() {
+        download_completely(storage, key, &path)?;
+    }
+    Ok(path)
+}
+
+//The files of a stored backup, which are several for a directory backup
+fn backup_files<'a>(files: &'a [StoredFile], name: &str) -> impl Iterator<Item=&'a StoredFile> {
+    let dir_prefix = format!("{}/", name);
+    let name = name.to_string();
+    files.iter().filter(move |file| file.key == name || file.key.starts_with(&dir_prefix))
+}
+
+fn file_name(path: &Path) -> Result<String, Error> {
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => Ok(name.to_string()),
+        None => Err(Error::new_s(format!("{} is no backup", path.display()))),
+    }
+}
+
+//Key of a backup of a storage, which is where it is relative to the folder of a local storage
+pub fn stored_key(storage: &dyn Storage, entry: &BackupEntry) -> Result<String, Error> {
+    if let Some(stored) = entry.stored.as_ref() {
+        return Ok(stored.key.clone());
+    }
+    match storage.local_root().and_then(|root| entry.path.strip_prefix(root).ok()) {
+        Some(rel_path) => Ok(rel_path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")),
+        None => Err(Error::new_s(format!("{} is not in {}", entry.path.display(), storage.describe()))),
+    }
+}
+
+//Names of the backups of a system a storage has completely
+//A local storage has a backup once it shows up, as backups are copied under a partial name first. In a remote storage the copy of the manifest marks it as complete
+fn complete_backups(storage: &dyn Storage, system_name: &str) -> Result<HashSet<String>, Error> {
+    if let Some(root) = storage.local_root() {
+        return Ok(list_zip_paths(system_name, root)?.into_iter().filter_map(|(path, _)| file_name(&path).ok()).collect());
+    }
+    Ok(storage.list(&backup_name_prefix(system_name))?.into_iter()
+        .filter_map(|file| file.key.strip_suffix(MANIFEST_SUFFIX).map(String::from))
+        .collect())
+}
+
+//Path of a file of a backup on this computer, either in the staging folder the backup was made in or in source, the dest its chain was read from
+fn source_file(source: &dyn Storage, staging: &Path, key: &str) -> Result<PathBuf, Error> {
+    let staged = staging.join(key);
+    if staged.exists() {
+        return Ok(staged);
+    }
+    fetch_file(source, key)
+}
+
+//Like source_file for a whole backup, which is a folder for a directory backup
+fn source_backup(source: &dyn Storage, staging: &Path, name: &str) -> Result<PathBuf, Error> {
+    let staged = staging.join(name);
+    if staged.exists() {
+        return Ok(staged);
+    }
+    if let Some(root) = source.local_root() {
+        return Ok(root.join(name));
+    }
+    for file in backup_files(&source.list(name)?, name) {
+        fetch_file(source, &file.key)?;
+    }
+    Ok(source.cache_dir().join(name))
+}
+
+//Uploads a manifest through a temporary file
+fn upload_manifest(storage: &dyn Storage, manifest: &Manifest, key: &str) -> Result<(), Error> {
+    let temp_path = std::env::temp_dir().join(format!("mq_backuper_{}_{}", std::process::id(), key.replace('/', "_")));
+    let uploaded = File::create(&temp_path)
+        .map_err(Error::from)
+        .and_then(|mut f| Ok(f.write_all(serde_json::to_string_pretty(manifest)?.as_bytes())?))
+        .and_then(|_| storage.upload(&temp_path, key));
+    let _ = std::fs::remove_file(&temp_path);
+    uploaded
+}
+
+//Copies a backup into a storage and returns a short description of what was copied
+//The chain is the one of the backup in source. Its links are taken from the staging folder if they were just made there and from source otherwise
+//An incremental backup is useless without its chain, so the backups it builds on are copied too if the storage doesn't have them yet, e.g. because it was unplugged during earlier backups
+pub fn mirror_backup(progress: &mut dyn Progress, system_name: &str, chain: &[ChainLink], storage: &dyn Storage, source: &dyn Storage, staging: &Path) -> Result<String, Error> {
+    if let Some(snapshot) = chain.last().filter(|link| is_snapshot(&link.archive)) {
+        return mirror_snapshot(progress, snapshot, storage, source, staging);
+    }
+    let stored = complete_backups(storage, system_name)?;
+    let mut copied_backups = 0;
+    for link in chain.iter() {
+        let name = file_name(&link.archive)?;
+        if stored.contains(&name) {
+            continue;
+        }
+        let backup = source_backup(source, staging, &name)?;
+        progress.task(&format!("Copying {} to {}", name, storage.describe()));
+        if backup.is_dir() {
+            storage.upload_dir(&backup, &name)?;
+        } else {
+            storage.upload(&backup, &name)?;
+        }
+        upload_manifest(storage, &link.manifest, &format!("{}{}", name, MANIFEST_SUFFIX))?;
+        copied_backups += 1;
+    }
+    Ok(match copied_backups {
+        0 => "it had the backup already".to_string(),
+        1 => "1 backup".to_string(),
+        count => format!("{} backups of its incremental chain", count),
+    })
+}
+
+//Copies a snapshot with the blobs the storage doesn't have yet. The snapshot goes last, so it never points to missing blobs
+fn mirror_snapshot(progress: &mut dyn Progress, snapshot: &ChainLink, storage: &dyn Storage, source: &dyn Storage, staging: &Path) -> Result<String, Error> {
+    let snapshot_key = format!("{}{}", snapshots_key(), file_name(&snapshot.archive)?);
+    if storage.list(&snapshot_key)?.iter().any(|file| file.key == snapshot_key) {
+        return Ok("it had the backup already".to_string());
+    }
+    let stored: HashSet<String> = storage.list(&blobs_key())?.into_iter().map(|file| file.key).collect();
+    let mut copied_blobs = 0;
+    for sha256 in snapshot.manifest.files.iter().filter_map(|file| file.sha256.as_ref()).collect::<BTreeSet<&String>>() {
+        let key = blob_key(sha256);
+        if stored.contains(&key) {
+            continue;
+        }
+        progress.task(&format!("Copying {} to {}", key, storage.describe()));
+        storage.upload(&source_file(source, staging, &key)?, &key)?;
+        copied_blobs += 1;
+    }
+    storage.upload(&source_file(source, staging, &snapshot_key)?, &snapshot_key)?;
+    Ok(format!("snapshot with {} new blobs", copied_blobs))
+}
+
+//Hashes of the blobs a storage has, which a snapshot made for it doesn't have to store again
+pub fn stored_blobs(storage: &dyn Storage) -> Result<HashSet<String>, Error> {
+    Ok(storage.list(&blobs_key())?.into_iter().filter_map(|blob| blob.key.rsplit('/').next().map(String::from)).collect())
+}
+
+//Lists the backups of a system in a storage, newest first
+//A local storage is scanned like a folder. The backups of a remote one are found by the copies of their manifests and their paths are where fetch_backup puts them
+pub fn load_stored_catalog(system_name: &str, storage: &dyn Storage) -> Result<Vec<BackupEntry>, Error> {
+    if let Some(root) = storage.local_root() {
+        return load_catalog(system_name, root);
+    }
+    let root = storage.cache_dir();
+    let mut catalog = Vec::new();
+    let files = storage.list(&backup_name_prefix(system_name))?;
+    for manifest_file in files.iter() {
+        let name = match manifest_file.key.strip_suffix(MANIFEST_SUFFIX) {
+            Some(name) => name,
+            None => continue,
+        };
+        let created = match parse_zip_name(system_name, name) {
+            Some(created) => created,
+            None => continue,
+        };
+        let path = root.join(name);
+        let manifest: Option<Manifest> = fetch_file(storage, &manifest_file.key).ok()
+            .and_then(|manifest_path| File::open(manifest_path).ok())
+            .and_then(|f| serde_json::from_reader(f).ok());
+        catalog.push(BackupEntry {
+            system: system_name.to_string(),
+            created,
+            size: backup_files(&files, name).map(|file| file.size).sum(),
+            entry_count: manifest.as_ref().map(|m| m.dirs.len() + m.files.len() + 1),
+            base: manifest.and_then(|m| m.base).map(|base| path.with_file_name(base)),
+            path,
+            stored: Some(StoredBackup {
+                storage: storage.describe(),
+                key: name.to_string(),
+            }),
+        });
+    }
+    for snapshot in storage.list(&format!("{}{}", snapshots_key(), backup_name_prefix(system_name)))?.into_iter() {
+        let created = match snapshot.key.rsplit('/').next().and_then(|name| parse_backup_name(system_name, name, SNAPSHOT_EXTENSION)) {
+            Some(created) => created,
+            None => continue,
+        };
+        let manifest = fetch_file(storage, &snapshot.key).and_then(|path| read_snapshot(&path)).ok();
+        catalog.push(BackupEntry {
+            system: system_name.to_string(),
+            created,
+            path: root.join(&snapshot.key),
+            size: manifest.as_ref().map(|m| m.files.iter().map(|f| f.size).sum()).unwrap_or(0),
+            entry_count: manifest.as_ref().map(|m| m.dirs.len() + m.files.len()),
+            base: None,
+            stored: Some(StoredBackup {
+                storage: storage.describe(),
+                key: snapshot.key,
+            }),
+        });
+    }
+    catalog.sort_by_key(|b| Reverse(b.created));
+    Ok(catalog)
+}
+
+//The chain of a backup of a storage like load_chain returns it. Of a remote storage only the copies of the manifests are downloaded
+pub fn load_stored_chain(storage: &dyn Storage, entry: &BackupEntry) -> Result<Vec<ChainLink>, Error> {
+    let key = match entry.stored.as_ref() {
+        Some(stored) if storage.local_root().is_none() => &stored.key,
+        _ => return load_chain(&entry.path),
+    };
+    if is_snapshot(&entry.path) {
+        let manifest = read_snapshot(&fetch_file(storage, key)?)?;
+        return Ok(vec![ChainLink { archive: entry.path.clone(), manifest }]);
+    }
+    //The list tells which backups are still there, a copy of a manifest downloaded before may be of a backup deleted since
+    let complete = complete_backups(storage, &entry.system)?;
+    let mut chain = Vec::new();
+    let mut visited = HashSet::new();
+    let mut next = Some(key.clone());
+    while let Some(name) = next {
+        if !visited.insert(name.clone()) {
+            return Err(Error::new_s(format!("The backups based on {} form a loop", name)));
+        }
+        if !complete.contains(&name) {
+            return Err(Error::new_s(format!("{} is missing in {}, but other backups are based on it", name, storage.describe())));
+        }
+        let manifest: Manifest = serde_json::from_reader(File::open(fetch_file(storage, &format!("{}{}", name, MANIFEST_SUFFIX))?)?)?;
+        next = manifest.base.clone();
+        chain.push(ChainLink { archive: entry.path.with_file_name(&name), manifest });
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+//Downloads a backup of a storage with everything needed to restore it: the whole chain of an incremental backup or the blobs of a snapshot
+//Returns the path of the downloaded backup, which works like one in a local dest then
+pub fn fetch_backup(progress: &mut dyn Progress, storage: &dyn Storage, entry: &BackupEntry) -> Result<PathBuf, Error> {
+    let key = match entry.stored.as_ref() {
+        Some(stored) if storage.local_root().is_none() => &stored.key,
+        _ => return Ok(entry.path.clone()),
+    };
+    if is_snapshot(&entry.path) {
+        progress.task(&format!("Downloading {} from {}", key, storage.describe()));
+        let snapshot = fetch_file(storage, key)?;
+        for sha256 in read_snapshot(&snapshot)?.files.iter().filter_map(|file| file.sha256.as_ref()) {
+            progress.task(&format!("Downloading {} from {}", blob_key(sha256), storage.describe()));
+            fetch_file(storage, &blob_key(sha256))?;
+        }
+        return Ok(snapshot);
+    }
+    let files = storage.list(&backup_name_prefix(&entry.system))?;
+    for link in load_stored_chain(storage, entry)?.iter() {
+        for file in backup_files(&files, &file_name(&link.archive)?) {
+            progress.task(&format!("Downloading {} from {}", file.key, storage.describe()));
+            fetch_file(storage, &file.key)?;
+        }
+    }
+    Ok(entry.path.clone())
+}
+
+//Deletes a backup from a storage. The copy of the manifest goes first, so a backup that is only deleted in part is no backup anymore
+pub fn delete_stored_backup(storage: &dyn Storage, key: &str) -> Result<(), Error> {
+    if is_snapshot(Path::new(key)) {
+        return storage.delete(key);
+    }
+    let files = storage.list(key)?;
+    let manifest_key = format!("{}{}", key, MANIFEST_SUFFIX);
+    if files.iter().any(|file| file.key == manifest_key) {
+        storage.delete(&manifest_key)?;
+    }
+    if files.iter().any(|file| file.key == key) {
+        storage.delete(key)
+    } else {
+        storage.delete_dir(key)
+    }
+}
+
+//Deletes all blobs of the storage that no snapshot of any system uses anymore and returns their number and size
+//The snapshots in ignored count as already deleted, which is how a dry run finds out what pruning would free
+pub fn collect_stored_garbage(progress: &mut dyn Progress, storage: &dyn Storage, ignored: &[String], dry_run: bool) -> Result<(usize, u64), Error> {
+    let mut used = HashSet::new();
+    for snapshot in storage.list(&snapshots_key())?.into_iter().filter(|s| !ignored.contains(&s.key)) {
+        if !snapshot.key.ends_with(&format!(".{}", SNAPSHOT_EXTENSION)) {
+            continue;
+        }
+        //A snapshot that can't be read could still use any blob, so nothing may be deleted then
+        let manifest = fetch_file(storage, &snapshot.key)
+            .and_then(|path| read_snapshot(&path))
+            .map_err(|err| Error::new_j(format!("Can't collect garbage because {} can't be read", snapshot.key), err))?;
+        used.extend(manifest.files.into_iter().filter_map(|file| file.sha256));
+    }
+    let mut unused_count = 0;
+    let mut unused_size = 0;
+    for blob in storage.list(&blobs_key())?.into_iter() {
+        if blob.key.rsplit('/').next().map(|name| used.contains(name)).unwrap_or(true) {
+            continue;
+        }
+        unused_count += 1;
+        unused_size += blob.size;
+        if !dry_run {
+            progress.task(&format!("Deleting unused {}", blob.key));
+            storage.delete(&blob.key)?;
+        }
+    }
+    Ok((unused_count, unused_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use serde_json::json;
+
+    use crate::catalog::load_catalog;
+    use crate::error::Error;
+    use crate::local_installation::LocalInstallation;
+    use crate::progress::NoProgress;
+    use crate::storage::{DestConfig, Storage, StoredFile};
+    use crate::test_util::TempDir;
+    use crate::verify::verify_backup;
+
+    use super::{delete_stored_backup, fetch_backup, load_stored_catalog, load_stored_chain, mirror_backup};
+
+    //A local folder that is used like a remote storage, so backups are found by the copies of their manifests and downloaded to be read
+    //Its downloads go to a cache of its own, so tests running at the same time don't share one
+    struct RemoteFolder(Box<dyn Storage>, TempDir);
+
+    impl RemoteFolder {
+        fn new(dir: &TempDir) -> RemoteFolder {
+            RemoteFolder(open(dir), TempDir::new("remote_cache"))
+        }
+    }
+
+    impl Storage for RemoteFolder {
+        fn describe(&self) -> String {
+            format!("remote:{}", self.0.describe())
+        }
+
+        fn local_root(&self) -> Option<&Path> {
+            None
+        }
+
+        fn upload(&self, local: &Path, key: &str) -> Result<(), Error> {
+            self.0.upload(local, key)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<StoredFile>, Error> {
+            self.0.list(prefix)
+        }
+
+        fn download(&self, key: &str, local: &Path) -> Result<(), Error> {
+            self.0.download(key, local)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), Error> {
+            self.0.delete(key)
+        }
+
+        fn cache_dir(&self) -> PathBuf {
+            self.1.path().to_path_buf()
+        }
+    }
+
+    fn open(dir: &TempDir) -> Box<dyn Storage> {
+        DestConfig::Path(dir.path().display().to_string()).open().unwrap()
+    }
+
+    #[test]
+    fn remote_storages_get_whole_chains_and_give_them_back() {
+        for format in ["zip", "directory"] {
+            let src = TempDir::new("remote_src");
+            let dest = TempDir::new("remote_dest");
+            let staging = TempDir::new("remote_staging");
+            let system: LocalInstallation = serde_json::from_value(json!({
+                "name": "Test_system-1",
+                "src": src.path(),
+                "dest": dest.path(),
+                "backup_rel_paths": [{"rel_path": "", "include_subfolders": true}],
+                "archive_format": format,
+                "incremental": true,
+            })).unwrap();
+            src.write("show/a.txt", b"a");
+            system.clone().backup(&mut NoProgress).unwrap();
+            src.write("show/a.txt", b"a changed");
+            system.clone().backup(&mut NoProgress).unwrap();
+
+            let source = open(&dest);
+            let latest = load_stored_catalog(&system.name, source.as_ref()).unwrap().remove(0);
+            let chain = load_stored_chain(source.as_ref(), &latest).unwrap();
+            assert_eq!(chain.len(), 2, "{}", format);
+
+            let remote_dir = TempDir::new("remote_storage");
+            let remote = RemoteFolder::new(&remote_dir);
+            assert_eq!(mirror_backup(&mut NoProgress, &system.name, &chain, &remote, source.as_ref(), staging.path()).unwrap(), "2 backups of its incremental chain", "{}", format);
+            assert_eq!(mirror_backup(&mut NoProgress, &system.name, &chain, &remote, source.as_ref(), staging.path()).unwrap(), "it had the backup already", "{}", format);
+
+            let catalog = load_stored_catalog(&system.name, &remote).unwrap();
+            assert_eq!(catalog.len(), 2, "{}", format);
+            assert!(catalog[0].stored.is_some() && catalog[0].base.is_some(), "{}", format);
+            let remote_chain = load_stored_chain(&remote, &catalog[0]).unwrap();
+            assert_eq!(remote_chain.len(), 2, "{}", format);
+            //A downloaded backup has its base next to it
+            let fetched = fetch_backup(&mut NoProgress, &remote, &catalog[0]).unwrap();
+            assert!(verify_backup(&mut NoProgress, &fetched, None).unwrap().is_ok(), "{}", format);
+
+            //A chain read from a remote storage is downloaded from it to be copied on
+            let other_dir = TempDir::new("remote_other");
+            let other = open(&other_dir);
+            mirror_backup(&mut NoProgress, &system.name, &remote_chain, other.as_ref(), &remote, staging.path()).unwrap();
+            let other_catalog = load_catalog(&system.name, other_dir.path()).unwrap();
+            assert_eq!(other_catalog.len(), 2, "{}", format);
+
+            //Without its base a backup is no complete chain anymore
+            delete_stored_backup(&remote, &catalog[1].stored.as_ref().unwrap().key).unwrap();
+            let catalog = load_stored_catalog(&system.name, &remote).unwrap();
+            assert_eq!(catalog.len(), 1, "{}", format);
+            assert!(load_stored_chain(&remote, &catalog[0]).is_err(), "{}", format);
+            delete_stored_backup(other.as_ref(), &catalog[0].stored.as_ref().unwrap().key).unwrap();
+            assert_eq!(load_catalog(&system.name, other_dir.path()).unwrap().len(), 1, "{}", format);
+        }
+    }
+}
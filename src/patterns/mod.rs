@@ -0,0 +1,176 @@
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobMatcher};
+
+use crate::error::Error;
+
+//One gitignore style rule like *.sbk, show/temp/**, cache/ or !keep.sbk
+struct PatternRule {
+    pattern: String,
+    negated: bool,
+    //Rules ending with / only match folders
+    dir_only: bool,
+    matcher: GlobMatcher,
+}
+
+impl PatternRule {
+    fn new(pattern: &str) -> Result<PatternRule, Error> {
+        //Backslashes are accepted as separators, as that is what windows users write
+        let mut glob = pattern.trim().replace('\\', "/");
+        let negated = glob.starts_with('!');
+        if negated {
+            glob.remove(0);
+        }
+        let dir_only = glob.ends_with('/');
+        if dir_only {
+            glob.pop();
+        }
+        //Like in gitignore, rules without a separator match at any depth, all others are relative to the root
+        if glob.contains('/') {
+            glob = glob.trim_start_matches('/').to_string();
+        } else {
+            glob = format!("**/{}", glob);
+        }
+        let matcher = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::new_s(format!("Invalid pattern {}: {}", pattern, e)))?
+            .compile_matcher();
+        Ok(PatternRule {
+            pattern: pattern.to_string(),
+            negated,
+            dir_only,
+            matcher,
+        })
+    }
+}
+
+//Ordered list of gitignore style rules that are matched against paths relative to a root
+//The last matching rule decides, so a rule starting with ! can take back an earlier one
+pub struct PathPatterns {
+    rules: Vec<PatternRule>,
+}
+
+impl PathPatterns {
+    pub fn new(patterns: &[String]) -> Result<PathPatterns, Error> {
+        let mut rules = Vec::new();
+        for pattern in patterns.iter().filter(|p| !p.trim().is_empty()) {
+            rules.push(PatternRule::new(pattern)?);
+        }
+        Ok(PathPatterns { rules })
+    }
+
+    //Returns the rule that matches the path or None if no rule matches or the last matching rule is negated
+    pub fn matching_rule(&self, relative_path: &Path, is_dir: bool) -> Option<&str> {
+        let relative_path = to_slash_path(relative_path);
+        let mut matching_rule = None;
+        for rule in self.rules.iter() {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            if rule.matcher.is_match(&relative_path) {
+                matching_rule = if rule.negated {
+                    None
+                } else {
+                    Some(rule.pattern.as_str())
+                };
+            }
+        }
+        matching_rule
+    }
+}
+
+//Joins the components of a path with / on every platform
+pub fn to_slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::PathPatterns;
+
+    fn patterns(patterns: &[&str]) -> PathPatterns {
+        PathPatterns::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn matches(patterns: &PathPatterns, path: &str, is_dir: bool) -> bool {
+        patterns.matching_rule(Path::new(path), is_dir).is_some()
+    }
+
+    #[test]
+    fn rules_without_separator_match_at_any_depth() {
+        let p = patterns(&["*.sbk", "heads.all"]);
+        assert!(matches(&p, "a.sbk", false));
+        assert!(matches(&p, "show/a.sbk", false));
+        assert!(matches(&p, "show/deep/er/a.sbk", false));
+        assert!(matches(&p, "show/heads.all", false));
+        assert!(!matches(&p, "a.sbk.txt", false));
+        assert!(!matches(&p, "heads.all.bak", false));
+    }
+
+    #[test]
+    fn rules_with_separator_are_anchored_to_the_root() {
+        let p = patterns(&["/temp", "show/cache"]);
+        assert!(matches(&p, "temp", true));
+        assert!(!matches(&p, "show/temp", true));
+        assert!(matches(&p, "show/cache", true));
+        assert!(!matches(&p, "other/show/cache", true));
+        //A wildcard never crosses a separator
+        assert!(!matches(&patterns(&["show/*.shw"]), "show/sub/a.shw", false));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_folders() {
+        let below = patterns(&["show/temp/**"]);
+        assert!(matches(&below, "show/temp/a", false));
+        assert!(matches(&below, "show/temp/x/y/z", false));
+        assert!(!matches(&below, "show/other/a", false));
+        let anywhere = patterns(&["**/autosave_*"]);
+        assert!(matches(&anywhere, "autosave_1", false));
+        assert!(matches(&anywhere, "show/x/autosave_1", false));
+        let between = patterns(&["show/**/a.shw"]);
+        assert!(matches(&between, "show/a.shw", false));
+        assert!(matches(&between, "show/x/y/a.shw", false));
+    }
+
+    #[test]
+    fn trailing_separator_only_matches_folders() {
+        let p = patterns(&["cache/"]);
+        assert!(matches(&p, "cache", true));
+        assert!(matches(&p, "show/cache", true));
+        assert!(!matches(&p, "cache", false));
+        assert!(!matches(&p, "show/cache", false));
+    }
+
+    #[test]
+    fn the_last_matching_rule_wins() {
+        let p = patterns(&["*.sbk", "!keep.sbk"]);
+        assert!(matches(&p, "a.sbk", false));
+        assert!(!matches(&p, "keep.sbk", false));
+        assert!(!matches(&p, "show/keep.sbk", false));
+        //A negation before the rule it should take back does nothing
+        let p = patterns(&["!keep.sbk", "*.sbk"]);
+        assert!(matches(&p, "keep.sbk", false));
+        let p = patterns(&["*.sbk", "!keep.sbk", "show/keep.sbk"]);
+        assert!(!matches(&p, "keep.sbk", false));
+        assert!(matches(&p, "show/keep.sbk", false));
+    }
+
+    #[test]
+    fn windows_separators_and_empty_rules_are_accepted() {
+        let p = patterns(&["show\\temp\\**", "  ", ""]);
+        assert!(matches(&p, "show/temp/a", false));
+        assert!(!matches(&p, "show/a", false));
+        assert_eq!(p.matching_rule(Path::new("show/temp/a"), false), Some("show\\temp\\**"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_reported() {
+        assert!(PathPatterns::new(&["show/[".to_string()]).is_err());
+    }
+}
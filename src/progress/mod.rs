@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::catalog::format_size;
+
+//Receives what a backup, restore or verification is doing. The menu and the command line print it, programs that embed the backups can show it their own way
+//Every method does nothing by default, so an implementation only needs the ones it cares about
+pub trait Progress {
+    //A backup or restore of a system starts
+    fn title(&mut self, _title: &str) {}
+    //A message worth keeping, like the archive that is created
+    fn info(&mut self, _message: &str) {}
+    fn warn(&mut self, _message: &str) {}
+    //What is done right now, like the file that is zipped. The next task replaces it
+    fn task(&mut self, _task: &str) {}
+    //The pre-scan of a system found the files and bytes that are going to be written, before the first one is read
+    //Called again for the same system if the total changes, like for the systems of a CombinedProgress
+    fn scanned(&mut self, _system: &str, _total: Counts) {}
+    //How much of the scanned total is written so far. Called after every file and while large files are read
+    fn copied(&mut self, _done: Counts) {}
+    //Asks for the passphrase of encrypted backups. None if nobody can answer, which fails the backup unless the passphrase comes from elsewhere
+    fn ask_passphrase(&mut self, _question: &str) -> Option<String> {
+        None
+    }
+}
+
+//Ignores all progress, for callers that only want the result
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+//A number of files and their size, either what a backup writes or how much of it is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Counts {
+    pub fn add(&mut self, other: Counts) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+//Turns the counts of the scanned total and of what is written so far into a percentage, a throughput and the time remaining
+pub struct Meter {
+    pub system: String,
+    pub total: Counts,
+    pub done: Counts,
+    started: Instant,
+}
+
+impl Meter {
+    pub fn new(system: &str, total: Counts) -> Meter {
+        Meter {
+            system: system.to_string(),
+            total,
+            done: Counts::default(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self, done: Counts) {
+        self.done = done;
+    }
+
+    //A new total for a running meter. The time and what is written so far are kept
+    pub fn rescan(&mut self, total: Counts) {
+        self.total = total;
+    }
+
+    //Measured in bytes, as a large file takes longer than a small one. A total without bytes is measured in files
+    //Never more than 100, even if files grew since the pre-scan
+    pub fn percent(&self) -> u64 {
+        let (done, total) = if self.total.bytes > 0 {
+            (self.done.bytes, self.total.bytes)
+        } else {
+            (self.done.files, self.total.files)
+        };
+        if total == 0 {
+            return 100;
+        }
+        (done.min(total) * 100 / total).min(100)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.files >= self.total.files
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    //Bytes written per second since the meter was started
+    pub fn throughput(&self) -> u64 {
+        throughput(self.done.bytes, self.elapsed())
+    }
+
+    //Estimated from the throughput so far. None as long as nothing is written yet
+    pub fn remaining(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        if throughput == 0 {
+            return None;
+        }
+        Some(Duration::from_secs(self.total.bytes.saturating_sub(self.done.bytes) / throughput))
+    }
+
+    //A bar like [#######-------] with width characters between the brackets
+    pub fn bar(&self, width: usize) -> String {
+        let filled = (self.percent() as usize * width / 100).min(width);
+        format!("[{}{}]", "#".repeat(filled), "-".repeat(width - filled))
+    }
+
+    //Everything about the progress in one line, like [####------]  42%  12 of 30 files  1.2 MB of 3.0 MB  4.5 MB/s  1m 20s left
+    pub fn describe(&self) -> String {
+        let remaining = match self.remaining() {
+            Some(remaining) if !self.is_done() => format!("  {} left", format_duration(remaining)),
+            _ => String::new(),
+        };
+        format!("{} {:>3}%  {} of {} files  {} of {}  {}/s{}", self.bar(20), self.percent(), self.done.files, self.total.files,
+                format_size(self.done.bytes), format_size(self.total.bytes), format_size(self.throughput()), remaining)
+    }
+}
+
+//Bytes per second, 0 for no time at all
+pub fn throughput(bytes: u64, duration: Duration) -> u64 {
+    let millis = duration.as_millis() as u64;
+    if millis == 0 {
+        return 0;
+    }
+    bytes.saturating_mul(1000) / millis
+}
+
+//Formats a duration like 2h 05m, 1m 20s or 12s
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+//Name the systems of a CombinedProgress report their added up counts with
+pub const COMBINED_SYSTEMS: &str = "all systems";
+
+//Shares one progress between threads that back up several systems at once
+//Messages are prefixed with the name of their system and the counts of all systems are added up into one progress
+pub struct CombinedProgress<'a> {
+    shared: Mutex<Combined<'a>>,
+}
+
+struct Combined<'a> {
+    progress: &'a mut (dyn Progress + Send),
+    //The systems that started writing since all systems were last finished
+    systems: HashMap<String, SystemCounts>,
+}
+
+struct SystemCounts {
+    total: Counts,
+    done: Counts,
+    finished: bool,
+}
+
+impl Combined<'_> {
+    fn report(&mut self, rescanned: bool) {
+        let mut total = Counts::default();
+        let mut done = Counts::default();
+        for counts in self.systems.values() {
+            total.add(counts.total);
+            done.add(counts.done);
+        }
+        if rescanned {
+            self.progress.scanned(COMBINED_SYSTEMS, total);
+        }
+        self.progress.copied(done);
+    }
+
+    //A system that stopped before writing all of its total, e.g. because it failed, would keep the progress from ever finishing, so its total is cut to what it wrote
+    fn finish(&mut self, system: &str) {
+        if let Some(counts) = self.systems.get_mut(system) {
+            counts.finished = true;
+            if counts.total != counts.done {
+                counts.total = counts.done;
+                self.report(true);
+            }
+        }
+        //The next system starts a new progress then
+        if self.systems.values().all(|counts| counts.finished) {
+            self.systems.clear();
+        }
+    }
+}
+
+impl<'a> CombinedProgress<'a> {
+    pub fn new(progress: &'a mut (dyn Progress + Send)) -> CombinedProgress<'a> {
+        CombinedProgress {
+            shared: Mutex::new(Combined {
+                progress,
+                systems: HashMap::new(),
+            }),
+        }
+    }
+
+    //The progress a system reports to. The system counts as finished once it is dropped
+    pub fn system(&self, system: &str) -> SystemProgress<'_, 'a> {
+        SystemProgress {
+            combined: self,
+            system: system.to_string(),
+        }
+    }
+
+    //A thread that panicked while reporting leaves nothing half done that the others couldn't go on with
+    fn lock(&self) -> MutexGuard<'_, Combined<'a>> {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+//Progress of one system of a CombinedProgress
+pub struct SystemProgress<'c, 'a> {
+    combined: &'c CombinedProgress<'a>,
+    system: String,
+}
+
+impl Progress for SystemProgress<'_, '_> {
+    //The titles of systems running at once would only push each other off the console, so they are plain messages
+    fn title(&mut self, title: &str) {
+        self.combined.lock().progress.info(title);
+    }
+    fn info(&mut self, message: &str) {
+        self.combined.lock().progress.info(&format!("{}: {}", self.system, message));
+    }
+    fn warn(&mut self, message: &str) {
+        self.combined.lock().progress.warn(&format!("{}: {}", self.system, message));
+    }
+    fn task(&mut self, task: &str) {
+        self.combined.lock().progress.task(&format!("{}: {}", self.system, task));
+    }
+    fn scanned(&mut self, _system: &str, total: Counts) {
+        let mut combined = self.combined.lock();
+        combined.systems.insert(self.system.clone(), SystemCounts {
+            total,
+            done: Counts::default(),
+            finished: false,
+        });
+        combined.report(true);
+    }
+    fn copied(&mut self, done: Counts) {
+        let mut combined = self.combined.lock();
+        if let Some(counts) = combined.systems.get_mut(&self.system) {
+            counts.done = done;
+            combined.report(false);
+        }
+    }
+    //The other systems wait while the question is asked, so they don't write over it
+    fn ask_passphrase(&mut self, question: &str) -> Option<String> {
+        self.combined.lock().progress.ask_passphrase(&format!("{}: {}", self.system, question))
+    }
+}
+
+impl Drop for SystemProgress<'_, '_> {
+    fn drop(&mut self) {
+        self.combined.lock().finish(&self.system);
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::NaiveDateTime;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use sha2::{Digest, Sha256};
+
+use crate::archive::{ArchiveFormat, FileAttributes};
+use crate::compression::FileCompression;
+use crate::error::Error;
+use crate::manifest::{Manifest, MANIFEST_NAME, ManifestFile};
+use crate::progress::{Counts, Progress};
+use crate::systems::BackupRelPath;
+use crate::verify::verify_snapshot_against_source;
+use crate::zip::{collect_entries, CollectedEntries, CopyResult, CopySettings, DEFAULT_BUFFER_SIZE};
+use crate::zip_name::{get_backup_path, get_partial_path, list_backup_paths};
+
+//Folder in dest that holds the repository
+const REPOSITORY_DIR: &str = "repository";
+//Folder in the repository with the content of all files, each stored once by its SHA-256 and compressed
+const BLOBS_DIR: &str = "blobs";
+//Folder in the repository with one index file per backup that lists the files with their SHA-256
+const SNAPSHOTS_DIR: &str = "snapshots";
+//Extension of the snapshot index files
+pub const SNAPSHOT_EXTENSION: &str = "json";
+//Extension of blobs that are still being written
+const PARTIAL_BLOB_EXTENSION: &str = "partial";
+
+//Makes the names of blobs that are still being written unique within this process
+static PARTIAL_BLOB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+//A deduplicating store of backups. Every file content is stored once as blob named by its SHA-256, no matter in how many snapshots of how many systems it is
+//A snapshot is a manifest as it is embedded in the zips, only that it also lists the folders
+pub struct Repository {
+    root: PathBuf,
+    //Blobs the dest of a staged repository already has, which are not stored again
+    stored_blobs: HashSet<String>,
+}
+
+impl Repository {
+    //The repository in the dest folder of a system
+    pub fn in_dest(dest: &Path) -> Repository {
+        Repository {
+            root: dest.join(REPOSITORY_DIR),
+            stored_blobs: HashSet::new(),
+        }
+    }
+
+    //A repository in the folder a backup is made in before it is copied to its dest. It only gets the blobs that are not in stored_blobs
+    pub fn staged(folder: &Path, stored_blobs: HashSet<String>) -> Repository {
+        Repository {
+            root: folder.join(REPOSITORY_DIR),
+            stored_blobs,
+        }
+    }
+
+    //The repository a snapshot belongs to
+    pub fn of_snapshot(snapshot: &Path) -> Result<Repository, Error> {
+        match snapshot.parent().and_then(Path::parent) {
+            Some(root) => Ok(Repository {
+                root: root.to_path_buf(),
+                stored_blobs: HashSet::new(),
+            }),
+            None => Err(Error::new_s(format!("{} is not in a repository", snapshot.display()))),
+        }
+    }
+
+    //Folder of the repository
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    //Path for a new snapshot of the system
+    pub fn new_snapshot_path(&self, system_name: &str) -> PathBuf {
+        get_backup_path(system_name, &self.root.join(SNAPSHOTS_DIR), SNAPSHOT_EXTENSION)
+    }
+
+    //All snapshots of a system with their creation time, newest first
+    pub fn list_snapshots(&self, system_name: &str) -> Result<Vec<(PathBuf, NaiveDateTime)>, Error> {
+        list_backup_paths(system_name, &self.root.join(SNAPSHOTS_DIR), SNAPSHOT_EXTENSION)
+    }
+
+    //Blobs are split into folders by the first two characters of the hash, so no folder gets too large
+    pub fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root.join(BLOBS_DIR).join(sha256.get(..2).unwrap_or("00")).join(sha256)
+    }
+
+    //Says if the blob is in the repository or, for a staged one, in its dest
+    pub fn has_blob(&self, sha256: &str) -> bool {
+        self.stored_blobs.contains(sha256) || self.blob_path(sha256).exists()
+    }
+
+    //Opens the content of a file stored in the repository
+    pub fn open_blob(&self, sha256: &str) -> Result<ZlibDecoder<File>, Error> {
+        let blob_path = self.blob_path(sha256);
+        if !blob_path.exists() {
+            return Err(Error::new_s(format!("{} is missing in the repository", blob_path.display())));
+        }
+        Ok(ZlibDecoder::new(File::open(blob_path)?))
+    }
+
+    //Stores the content of a file as blob if there is none with the same SHA-256 yet. Returns the size and the SHA-256
+    //The file is only read once: it is compressed into a partial blob while it is hashed, which then either becomes the blob or is thrown away
+    fn store_blob(&self, path: &Path, buffer: &mut [u8]) -> Result<(u64, String), Error> {
+        let blobs_dir = self.root.join(BLOBS_DIR);
+        create_dir_all(&blobs_dir)?;
+        let partial_blob = blobs_dir.join(format!("{}_{}.{}", std::process::id(), PARTIAL_BLOB_COUNTER.fetch_add(1, Ordering::SeqCst), PARTIAL_BLOB_EXTENSION));
+        let stored = write_partial_blob(path, &partial_blob, buffer).and_then(|(size, sha256)| {
+            let blob_path = self.blob_path(&sha256);
+            if self.has_blob(&sha256) {
+                std::fs::remove_file(&partial_blob)?;
+            } else {
+                if let Some(parent) = blob_path.parent() {
+                    create_dir_all(parent)?;
+                }
+                std::fs::rename(&partial_blob, &blob_path)?;
+            }
+            Ok((size, sha256))
+        });
+        if stored.is_err() {
+            let _ = std::fs::remove_file(&partial_blob);
+        }
+        stored
+    }
+}
+
+//Compresses a file into a new blob while calculating its SHA-256
+fn write_partial_blob(path: &Path, partial_blob: &Path, buffer: &mut [u8]) -> Result<(u64, String), Error> {
+    let mut f = File::open(path)?;
+    let mut encoder = ZlibEncoder::new(File::create(partial_blob)?, Compression::default());
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    loop {
+        let read = match f.read(buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        encoder.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    encoder.finish()?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+//Key of the folder with the blobs in a storage that holds copies of the backups of a dest
+pub fn blobs_key() -> String {
+    format!("{}/{}/", REPOSITORY_DIR, BLOBS_DIR)
+}
+
+//Key of a blob in a storage, split into folders like in the repository
+pub fn blob_key(sha256: &str) -> String {
+    format!("{}{}/{}", blobs_key(), sha256.get(..2).unwrap_or("00"), sha256)
+}
+
+//Key of the folder with the snapshots in a storage
+pub fn snapshots_key() -> String {
+    format!("{}/{}/", REPOSITORY_DIR, SNAPSHOTS_DIR)
+}
+
+//Says if the path is the index file of a snapshot in a repository
+pub fn is_snapshot(path: &Path) -> bool {
+    let in_snapshots_dir = path.parent().and_then(Path::file_name).map(|n| n == SNAPSHOTS_DIR).unwrap_or(false);
+    in_snapshots_dir && path.extension().map(|e| e == SNAPSHOT_EXTENSION).unwrap_or(false)
+}
+
+//Reads the index file of a snapshot
+pub fn read_snapshot(snapshot: &Path) -> Result<Manifest, Error> {
+    Ok(serde_json::from_reader(File::open(snapshot)?)?)
+}
+
+//Stores a set of user specified paths/files with the same rules as copy_to_zip in the repository and writes the snapshot describing them
+//Files with the same size and modification time as in the delta_base are not read again (unless compare_hashes is set), as their blob is already there
+pub fn copy_to_repository<S: AsRef<str>>(progress: &mut dyn Progress, system_name: &str, src_root_absolute: S, dirs: Vec<BackupRelPath>, repository: &Repository, snapshot: &Path, settings: CopySettings) -> Result<CopyResult, Error> {
+    if snapshot.exists() {
+        return Err(Error::new_s(format!("{} already exists!", snapshot.display())));
+    }
+    let src_root = Path::new(src_root_absolute.as_ref());
+    if !src_root.exists() {
+        return Err(Error::new_s(format!("{} does not exist", src_root_absolute.as_ref())));
+    }
+    let CollectedEntries { entries, skipped } = collect_entries(progress, src_root_absolute.as_ref(), &dirs)?;
+    let known_files: HashMap<&str, &ManifestFile> = settings.delta_base.iter()
+        .flat_map(|delta_base| delta_base.base_files.iter())
+        .map(|file| (file.path.as_str(), file))
+        .collect();
+
+    let mut buffer = vec![0u8; settings.buffer_size.max(1)];
+    let mut manifest = Manifest::new(system_name, src_root_absolute.as_ref(), Vec::new());
+    manifest.dirs = entries.iter().filter(|e| e.is_dir).map(|e| e.relative_name.clone()).collect();
+    //All files are described before the first one is stored, so the progress knows the total
+    let mut files = Vec::new();
+    for entry in entries.iter().filter(|e| !e.is_dir) {
+        files.push((entry, ManifestFile::from_file(&entry.path, &entry.relative_name, false)?));
+    }
+    progress.scanned(system_name, Counts {
+        files: files.len() as u64,
+        bytes: files.iter().map(|(_, file)| file.size).sum(),
+    });
+    let mut done = Counts::default();
+    let mut stored = Counts::default();
+    for (entry, mut file) in files.into_iter() {
+        let known_sha256 = known_files.get(file.path.as_str())
+            .filter(|known| !settings.compare_hashes && known.is_same_as(&file, false))
+            .and_then(|known| known.sha256.clone())
+            .filter(|sha256| repository.has_blob(sha256));
+        let sha256 = match known_sha256 {
+            Some(sha256) => sha256,
+            None => {
+                progress.task(&format!("Storing {}", entry.path.display()));
+                let (size, sha256) = repository.store_blob(&entry.path, &mut buffer)?;
+                file.size = size;
+                stored.add(Counts { files: 1, bytes: size });
+                sha256
+            }
+        };
+        //Files the repository has already count as done as well
+        done.add(Counts { files: 1, bytes: file.size });
+        progress.copied(done);
+        file.sha256 = Some(sha256);
+        manifest.files.push(file);
+    }
+    if let Some(unchanged_check) = settings.unchanged_check {
+        progress.task(&format!("Comparing with {}", unchanged_check.latest_zip.display()));
+        if manifest.has_same_files(&unchanged_check.latest_files, settings.compare_hashes) {
+            return Ok(CopyResult {
+                unchanged_since: Some(unchanged_check.latest_zip),
+                verified_files: None,
+                delta: None,
+                skipped,
+                written: Counts::default(),
+            });
+        }
+    }
+
+    progress.task("Writing snapshot...");
+    if let Some(parent) = snapshot.parent() {
+        create_dir_all(parent)?;
+    }
+    let partial_snapshot = get_partial_path(snapshot);
+    let written = File::create(&partial_snapshot)
+        .map_err(Error::from)
+        .and_then(|mut f| Ok(f.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?))
+        .and_then(|_| Ok(std::fs::rename(&partial_snapshot, snapshot)?));
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&partial_snapshot);
+        return Err(err);
+    }
+    let mut verified_files = None;
+    if settings.verify_after_backup {
+        let verified = verify_snapshot_against_source(progress, snapshot, repository, src_root).and_then(|report| {
+            if report.is_ok() {
+                Ok(report.checked_files)
+            } else {
+                Err(Error::new_j("Verification of the backup failed", Error::new(report.texts())))
+            }
+        });
+        match verified {
+            Ok(checked_files) => verified_files = Some(checked_files),
+            Err(err) => {
+                let _ = std::fs::remove_file(snapshot);
+                return Err(err);
+            }
+        }
+    }
+    Ok(CopyResult {
+        unchanged_since: None,
+        verified_files,
+        delta: None,
+        skipped,
+        written: stored,
+    })
+}
+
+//Writes a snapshot with all of its files into a zip like copy_to_zip creates them, so it can be used without the repository
+pub fn export_to_zip(progress: &mut dyn Progress, snapshot: &Path, dest_zip: &Path) -> Result<(), Error> {
+    if dest_zip.exists() {
+        return Err(Error::new_s(format!("{} already exists!", dest_zip.display())));
+    }
+    let repository = Repository::of_snapshot(snapshot)?;
+    let manifest = read_snapshot(snapshot)?;
+    let partial_zip = get_partial_path(dest_zip);
+    let written = write_export(progress, &repository, &manifest, &partial_zip).and_then(|_| Ok(std::fs::rename(&partial_zip, dest_zip)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial_zip);
+    }
+    written
+}
+
+fn write_export(progress: &mut dyn Progress, repository: &Repository, manifest: &Manifest, zip_path: &Path) -> Result<(), Error> {
+    let mut zip = ArchiveFormat::Zip.create_writer(zip_path, None, None)?;
+    let mut buffer = vec![0u8; DEFAULT_BUFFER_SIZE];
+    for dir in manifest.dirs.iter() {
+        zip.add_dir(dir)?;
+    }
+    for file in manifest.files.iter() {
+        progress.task(&format!("Exporting {}", file.path));
+        let sha256 = match file.sha256.as_ref() {
+            Some(sha256) => sha256,
+            None => return Err(Error::new_s(format!("{} has no SHA-256 in the snapshot", file.path))),
+        };
+        let attributes = FileAttributes::modified_at(UNIX_EPOCH + Duration::from_secs(file.modified));
+        zip.add_file(&file.path, file.size, attributes, FileCompression::default(), &mut repository.open_blob(sha256)?, &mut buffer)?;
+    }
+    //The exported zip is a full backup of its own
+    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    zip.add_file(MANIFEST_NAME, manifest_json.len() as u64, FileAttributes::modified_at(SystemTime::now()), FileCompression::default(), &mut manifest_json.as_bytes(), &mut buffer)?;
+    zip.finish()?;
+    Ok(())
+}
@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, File};
+use std::io::{copy, Read};
+use std::path::{Path, PathBuf};
+
+use crate::archive::read_entries;
+use crate::chain::{files_at, load_chain};
+use crate::error::Error;
+use crate::manifest::{is_reserved_entry, Manifest};
+use crate::progress::Progress;
+use crate::repository::{is_snapshot, read_snapshot, Repository};
+use crate::zip_name::timestamp;
+
+//How a backup gets restored
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RestoreOptions {
+    //Renames existing files to <file>.before_restore_<timestamp> before they get overwritten
+    pub move_aside: bool,
+}
+
+//Everything a restore of an archive would do, calculated without touching any file
+pub struct RestorePreview {
+    pub new_files: Vec<PathBuf>,
+    pub overwritten_files: Vec<PathBuf>,
+    //Entries that would end up outside of the root and are therefore never restored
+    pub refused_entries: Vec<String>,
+}
+
+//Converts the name of a zip entry to a path relative to the root. Returns None if the entry would escape the root (absolute paths, drive letters or ..)
+//Both separators are accepted as backups made on windows contain backslashes
+fn safe_relative_path(entry_name: &str) -> Option<PathBuf> {
+    if entry_name.starts_with('/') || entry_name.starts_with('\\') {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for (index, part) in entry_name.split(['/', '\\']).enumerate() {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            //A drive letter like C: only comes first. On windows a : anywhere else would write to an alternate data stream, other systems allow it in file names
+            part if part.contains(':') && (index == 0 || cfg!(windows)) => return None,
+            part => path.push(part),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+//Which entries of one archive a restore takes
+struct RestoreSource {
+    archive: PathBuf,
+    //Names of the files to take, None for all files
+    files: Option<HashSet<String>>,
+    with_dirs: bool,
+}
+
+impl RestoreSource {
+    fn takes(&self, name: &str, is_dir: bool) -> bool {
+        if is_reserved_entry(name) {
+            false
+        } else if is_dir {
+            self.with_dirs
+        } else {
+            self.files.as_ref().map(|files| files.contains(name)).unwrap_or(true)
+        }
+    }
+}
+
+//Finds out which archives a restore has to read. A full backup is read completely, an incremental backup needs its whole chain
+//Every file is taken from the newest archive of the chain that has it, and files deleted along the chain are taken from none, so restoring the chain gives the same result as replaying it
+//A full backup with a manifest gives the files the manifest lists, so the manifest of a backup of an earlier version, which is among the files, is not restored
+fn restore_sources(archive: &Path) -> Result<Vec<RestoreSource>, Error> {
+    let manifest = Manifest::read_from_backup(archive).ok().flatten();
+    if manifest.as_ref().map(|manifest| manifest.base.is_none()).unwrap_or(true) {
+        return Ok(vec![RestoreSource {
+            archive: archive.to_path_buf(),
+            files: manifest.map(|manifest| manifest.files.into_iter().map(|file| file.path).collect()),
+            with_dirs: true,
+        }]);
+    }
+    let chain = load_chain(archive)?;
+    //The newest archive has all folders of the time of its backup
+    let mut sources: Vec<RestoreSource> = chain.iter().enumerate().map(|(i, link)| RestoreSource {
+        archive: link.archive.clone(),
+        files: Some(HashSet::new()),
+        with_dirs: i == chain.len() - 1,
+    }).collect();
+    for (path, chain_file) in files_at(&chain).into_iter() {
+        sources[chain_file.link].files.get_or_insert_with(HashSet::new).insert(path);
+    }
+    Ok(sources)
+}
+
+//Adds one entry to the preview
+fn preview_entry(preview: &mut RestorePreview, src_root: &Path, name: &str, is_dir: bool) {
+    match safe_relative_path(name) {
+        None => preview.refused_entries.push(name.to_string()),
+        Some(_) if is_dir => {}
+        Some(relative_path) => {
+            let target = src_root.join(relative_path);
+            if target.exists() {
+                preview.overwritten_files.push(target);
+            } else {
+                preview.new_files.push(target);
+            }
+        }
+    }
+}
+
+//Checks every entry the restore of the archive (or snapshot) would extract against the files currently in src_root
+pub fn preview_restore(src_root: &Path, archive: &Path) -> Result<RestorePreview, Error> {
+    let mut preview = RestorePreview {
+        new_files: Vec::new(),
+        overwritten_files: Vec::new(),
+        refused_entries: Vec::new(),
+    };
+    if is_snapshot(archive) {
+        let manifest = read_snapshot(archive)?;
+        for dir in manifest.dirs.iter() {
+            preview_entry(&mut preview, src_root, dir, true);
+        }
+        for file in manifest.files.iter() {
+            preview_entry(&mut preview, src_root, &file.path, false);
+        }
+        return Ok(preview);
+    }
+    for source in restore_sources(archive)?.iter() {
+        read_entries(&source.archive, None, |entry| {
+            if source.takes(&entry.name, entry.is_dir) {
+                preview_entry(&mut preview, src_root, &entry.name, entry.is_dir);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(preview)
+}
+
+//Writes the entries of a restore below src_root and counts what it did
+struct Extraction<'a> {
+    src_root: &'a Path,
+    options: RestoreOptions,
+    aside_suffix: String,
+    restored: usize,
+    moved_aside: usize,
+    refused: Vec<String>,
+}
+
+impl<'a> Extraction<'a> {
+    fn new(src_root: &'a Path, options: RestoreOptions) -> Extraction<'a> {
+        Extraction {
+            src_root,
+            options,
+            aside_suffix: format!("before_restore_{}", timestamp()),
+            restored: 0,
+            moved_aside: 0,
+            refused: Vec::new(),
+        }
+    }
+
+    //Extracts one entry. Entries escaping the root are refused
+    fn extract(&mut self, progress: &mut dyn Progress, name: &str, is_dir: bool, content: &mut dyn Read) -> Result<(), Error> {
+        let relative_path = match safe_relative_path(name) {
+            Some(relative_path) => relative_path,
+            None => {
+                progress.task(&format!("Refusing {} because it is outside of {}", name, self.src_root.display()));
+                self.refused.push(name.to_string());
+                return Ok(());
+            }
+        };
+        let target = self.src_root.join(relative_path);
+        if is_dir {
+            create_dir_all(&target)?;
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        if self.options.move_aside && target.exists() {
+            let mut aside = target.clone().into_os_string();
+            aside.push(".");
+            aside.push(&self.aside_suffix);
+            progress.task(&format!("Moving {} aside", target.display()));
+            std::fs::rename(&target, aside)?;
+            self.moved_aside += 1;
+        }
+        progress.task(&format!("Restoring {}", target.display()));
+        copy(content, &mut File::create(&target)?)?;
+        self.restored += 1;
+        Ok(())
+    }
+}
+
+//Extracts all entries of the archive below src_root. Entries escaping the root are refused and listed in the returned message
+//For an incremental backup the entries are taken from all archives of its chain, for a snapshot from the blobs of its repository. Encrypted backups need the passphrase
+pub fn restore_from_zip(progress: &mut dyn Progress, src_root: &Path, archive: &Path, options: RestoreOptions, passphrase: Option<&str>) -> Result<String, Error> {
+    if !src_root.exists() {
+        return Err(Error::new_s(format!("{} does not exist", src_root.display())));
+    }
+    let mut extraction = Extraction::new(src_root, options);
+    let mut source_count = 1;
+    if is_snapshot(archive) {
+        let repository = Repository::of_snapshot(archive)?;
+        let manifest = read_snapshot(archive)?;
+        for dir in manifest.dirs.iter() {
+            extraction.extract(progress, dir, true, &mut std::io::empty())?;
+        }
+        for file in manifest.files.iter() {
+            let sha256 = match file.sha256.as_ref() {
+                Some(sha256) => sha256,
+                None => return Err(Error::new_s(format!("{} has no SHA-256 in the snapshot", file.path))),
+            };
+            extraction.extract(progress, &file.path, false, &mut repository.open_blob(sha256)?)?;
+        }
+    } else {
+        let sources = restore_sources(archive)?;
+        source_count = sources.len();
+        for source in sources.iter() {
+            read_entries(&source.archive, passphrase, |entry| {
+                if source.takes(&entry.name, entry.is_dir) {
+                    extraction.extract(progress, &entry.name, entry.is_dir, entry.content)?;
+                }
+                Ok(())
+            })?;
+        }
+    }
+    progress.task("All entries restored...");
+
+    let mut message = format!("\nRestored {} files from {} to {}\n", extraction.restored, archive.display(), src_root.display());
+    if source_count > 1 {
+        message.push_str(&format!("The files were taken from the {} backups of its incremental chain\n", source_count));
+    }
+    if extraction.moved_aside > 0 {
+        message.push_str(&format!("{} existing files were renamed to <file>.{}\n", extraction.moved_aside, extraction.aside_suffix));
+    }
+    if !extraction.refused.is_empty() {
+        message.push_str(&format!("Refused {} entries pointing outside of {}:\n{}\n", extraction.refused.len(), src_root.display(), extraction.refused.join("\n")));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::safe_relative_path;
+
+    #[test]
+    fn refuses_paths_leaving_the_root() {
+        assert_eq!(safe_relative_path("../x"), None);
+        assert_eq!(safe_relative_path("a/../../x"), None);
+        assert_eq!(safe_relative_path("a\\..\\x"), None);
+        assert_eq!(safe_relative_path("/abs"), None);
+        assert_eq!(safe_relative_path("\\abs"), None);
+        assert_eq!(safe_relative_path("C:\\x"), None);
+        assert_eq!(safe_relative_path("C:x"), None);
+        assert_eq!(safe_relative_path(""), None);
+        assert_eq!(safe_relative_path("./"), None);
+    }
+
+    #[test]
+    fn accepts_nested_paths_with_both_separators() {
+        assert_eq!(safe_relative_path("show/projects/a.shw"), Some(PathBuf::from("show").join("projects").join("a.shw")));
+        assert_eq!(safe_relative_path("show\\icons\\b.mc2"), Some(PathBuf::from("show").join("icons").join("b.mc2")));
+        assert_eq!(safe_relative_path("./show//a.shw"), Some(PathBuf::from("show").join("a.shw")));
+    }
+
+    #[test]
+    fn colons_after_the_first_component_are_file_names_outside_of_windows() {
+        let restored = safe_relative_path("notes/notes:2024.txt");
+        if cfg!(windows) {
+            assert_eq!(restored, None);
+        } else {
+            assert_eq!(restored, Some(PathBuf::from("notes").join("notes:2024.txt")));
+        }
+    }
+}
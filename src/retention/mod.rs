@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use chrono::{Datelike, Duration, NaiveDateTime};
+use serde::*;
+
+use crate::archive::ArchiveFormat;
+use crate::catalog::{BackupEntry, format_size};
+use crate::error::Error;
+use crate::mirror::{collect_stored_garbage, delete_stored_backup, stored_key};
+use crate::progress::Progress;
+use crate::repository::{is_snapshot, SNAPSHOT_EXTENSION};
+use crate::storage::Storage;
+use crate::zip_name::parse_backup_name;
+
+//Rules which backups of a system are kept when pruning. A backup is kept if any rule wants to keep it
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Retention {
+    //Keeps the newest n backups
+    pub keep_last: Option<usize>,
+    //Keeps the newest backup of each of the last n days
+    pub keep_daily: Option<u32>,
+    //Keeps the newest backup of each of the last n weeks
+    pub keep_weekly: Option<u32>,
+}
+
+impl Retention {
+    fn has_rules(&self) -> bool {
+        self.keep_last.is_some() || self.keep_daily.is_some() || self.keep_weekly.is_some()
+    }
+}
+
+//Backups of a system split into the ones to keep and the ones to delete, both newest first
+pub struct PrunePlan {
+    pub keep: Vec<BackupEntry>,
+    pub delete: Vec<BackupEntry>,
+}
+
+//Calculates which backups of a catalog (sorted newest first) the retention rules keep
+//Without any rule everything is kept, and the newest backup is never deleted
+//An incremental backup can't be restored without its base, so the whole chain of every kept backup is kept as well
+pub fn plan_prune(catalog: Vec<BackupEntry>, retention: &Retention, now: NaiveDateTime) -> PrunePlan {
+    let mut keep_paths: HashSet<PathBuf> = HashSet::new();
+    if !retention.has_rules() {
+        return PrunePlan {
+            keep: catalog,
+            delete: Vec::new(),
+        };
+    }
+    if let Some(newest) = catalog.first() {
+        keep_paths.insert(newest.path.clone());
+    }
+    if let Some(keep_last) = retention.keep_last {
+        for entry in catalog.iter().take(keep_last) {
+            keep_paths.insert(entry.path.clone());
+        }
+    }
+    if let Some(keep_daily) = retention.keep_daily {
+        for days_ago in 0..keep_daily {
+            let day = (now - Duration::days(days_ago as i64)).date();
+            if let Some(entry) = catalog.iter().find(|e| e.created.date() == day) {
+                keep_paths.insert(entry.path.clone());
+            }
+        }
+    }
+    if let Some(keep_weekly) = retention.keep_weekly {
+        for weeks_ago in 0..keep_weekly {
+            let week = (now - Duration::weeks(weeks_ago as i64)).iso_week();
+            if let Some(entry) = catalog.iter().find(|e| e.created.iso_week() == week) {
+                keep_paths.insert(entry.path.clone());
+            }
+        }
+    }
+    let bases: HashMap<&PathBuf, &PathBuf> = catalog.iter()
+        .filter_map(|e| e.base.as_ref().map(|base| (&e.path, base)))
+        .collect();
+    for kept in keep_paths.clone().into_iter() {
+        let mut next = bases.get(&kept);
+        while let Some(base) = next {
+            if !keep_paths.insert((*base).clone()) {
+                break;
+            }
+            next = bases.get(base);
+        }
+    }
+    let (keep, delete) = catalog.into_iter().partition(|e| keep_paths.contains(&e.path));
+    PrunePlan { keep, delete }
+}
+
+//Deletes the backups the retention rules don't keep from the storage the catalog was read from. With dry_run nothing is deleted and only the plan is reported
+pub fn prune(progress: &mut dyn Progress, catalog: Vec<BackupEntry>, retention: &Retention, dry_run: bool, storage: &dyn Storage) -> Result<String, Error> {
+    let plan = plan_prune(catalog, retention, chrono::offset::Local::now().naive_local());
+    //The catalog only contains backups, but never delete a file that isn't named like one
+    for entry in plan.delete.iter() {
+        let extension = if is_snapshot(&entry.path) {
+            SNAPSHOT_EXTENSION
+        } else {
+            ArchiveFormat::of_path(&entry.path).unwrap_or_default().extension()
+        };
+        let is_backup = entry.path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| parse_backup_name(&entry.system, n, extension))
+            .is_some();
+        if !is_backup {
+            return Err(Error::new_s(format!("Refusing to delete {} because it is not named like a backup", entry.path.display())));
+        }
+    }
+    let delete_keys = plan.delete.iter().map(|entry| stored_key(storage, entry)).collect::<Result<Vec<String>, Error>>()?;
+    let mut message = String::new();
+    for entry in plan.keep.iter() {
+        message.push_str(&format!("Keeping  {}\n", entry.location()));
+    }
+    for (entry, key) in plan.delete.iter().zip(delete_keys.iter()) {
+        if dry_run {
+            message.push_str(&format!("Would delete {}\n", entry.location()));
+        } else {
+            progress.task(&format!("Deleting {}", entry.location()));
+            delete_stored_backup(storage, key)?;
+            message.push_str(&format!("Deleted  {}\n", entry.location()));
+        }
+    }
+    if plan.delete.is_empty() {
+        message.push_str("Nothing to prune\n");
+    }
+    //Blobs of deleted snapshots that no other snapshot uses are deleted as well
+    let deleted_snapshots: Vec<String> = plan.delete.iter().zip(delete_keys).filter(|(entry, _)| is_snapshot(&entry.path)).map(|(_, key)| key).collect();
+    if !deleted_snapshots.is_empty() {
+        let (count, size) = collect_stored_garbage(progress, storage, &deleted_snapshots, dry_run)?;
+        if dry_run {
+            message.push_str(&format!("Would delete {} unused blobs with {} from the repository\n", count, format_size(size)));
+        } else {
+            message.push_str(&format!("Deleted {} unused blobs with {} from the repository\n", count, format_size(size)));
+        }
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    use crate::catalog::BackupEntry;
+    use crate::progress::NoProgress;
+    use crate::storage::DestConfig;
+    use crate::test_util::TempDir;
+
+    use super::{plan_prune, prune, Retention};
+
+    fn at(month: u32, day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap().and_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    fn entry(created: NaiveDateTime) -> BackupEntry {
+        BackupEntry {
+            system: "MQ".to_string(),
+            created,
+            path: PathBuf::from(format!("MQ_backup_{}.zip", created.format("%Y_%m_%d__%H_%M_%S"))),
+            size: 0,
+            entry_count: None,
+            base: None,
+            stored: None,
+        }
+    }
+
+    fn kept(catalog: Vec<BackupEntry>, retention: Retention) -> Vec<NaiveDateTime> {
+        plan_prune(catalog, &retention, at(3, 15, 12)).keep.into_iter().map(|e| e.created).collect()
+    }
+
+    #[test]
+    fn keeps_everything_without_rules() {
+        let catalog = vec![entry(at(3, 15, 10)), entry(at(1, 1, 10))];
+        assert_eq!(kept(catalog, Retention::default()), vec![at(3, 15, 10), at(1, 1, 10)]);
+    }
+
+    #[test]
+    fn keep_last_keeps_the_newest_backups() {
+        let catalog = vec![entry(at(3, 15, 10)), entry(at(3, 14, 10)), entry(at(3, 13, 10)), entry(at(3, 12, 10))];
+        let retention = Retention { keep_last: Some(2), ..Retention::default() };
+        assert_eq!(kept(catalog, retention), vec![at(3, 15, 10), at(3, 14, 10)]);
+    }
+
+    #[test]
+    fn keep_daily_keeps_the_newest_backup_of_each_day() {
+        let catalog = vec![entry(at(3, 15, 10)), entry(at(3, 15, 8)), entry(at(3, 14, 20)), entry(at(3, 14, 9)), entry(at(3, 12, 10))];
+        let retention = Retention { keep_daily: Some(3), ..Retention::default() };
+        assert_eq!(kept(catalog, retention), vec![at(3, 15, 10), at(3, 14, 20)]);
+    }
+
+    #[test]
+    fn keep_weekly_keeps_the_newest_backup_of_each_iso_week() {
+        //March 11 2024 is the monday of the week of March 15
+        let catalog = vec![entry(at(3, 14, 10)), entry(at(3, 11, 10)), entry(at(3, 8, 10)), entry(at(3, 4, 10)), entry(at(2, 28, 10))];
+        let retention = Retention { keep_weekly: Some(2), ..Retention::default() };
+        assert_eq!(kept(catalog, retention), vec![at(3, 14, 10), at(3, 8, 10)]);
+    }
+
+    #[test]
+    fn rules_add_up() {
+        let catalog = vec![entry(at(3, 15, 10)), entry(at(3, 15, 8)), entry(at(3, 8, 10)), entry(at(3, 1, 10))];
+        let retention = Retention { keep_last: Some(2), keep_weekly: Some(2), ..Retention::default() };
+        assert_eq!(kept(catalog, retention), vec![at(3, 15, 10), at(3, 15, 8), at(3, 8, 10)]);
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_even_if_no_rule_wants_it() {
+        let catalog = vec![entry(at(1, 2, 10)), entry(at(1, 1, 10))];
+        let retention = Retention { keep_daily: Some(1), ..Retention::default() };
+        assert_eq!(kept(catalog, retention), vec![at(1, 2, 10)]);
+    }
+
+    #[test]
+    fn keeps_the_bases_of_kept_incremental_backups() {
+        let full = entry(at(3, 12, 10));
+        let mut first_delta = entry(at(3, 13, 10));
+        first_delta.base = Some(full.path.clone());
+        let mut second_delta = entry(at(3, 14, 10));
+        second_delta.base = Some(first_delta.path.clone());
+        let catalog = vec![second_delta, first_delta, full, entry(at(3, 11, 10))];
+        let retention = Retention { keep_last: Some(1), ..Retention::default() };
+        assert_eq!(kept(catalog, retention), vec![at(3, 14, 10), at(3, 13, 10), at(3, 12, 10)]);
+    }
+
+    #[test]
+    fn deletes_the_backups_that_are_not_kept() {
+        let dir = TempDir::new("prune");
+        let storage = DestConfig::Path(dir.path().display().to_string()).open().unwrap();
+        let mut catalog = vec![entry(at(3, 15, 10)), entry(at(3, 14, 10))];
+        for e in catalog.iter_mut() {
+            e.path = dir.write(&e.path.to_string_lossy(), b"backup");
+        }
+        let retention = Retention { keep_last: Some(1), ..Retention::default() };
+        prune(&mut NoProgress, catalog.clone(), &retention, true, storage.as_ref()).unwrap();
+        assert!(catalog[1].path.exists());
+        prune(&mut NoProgress, catalog.clone(), &retention, false, storage.as_ref()).unwrap();
+        assert!(catalog[0].path.exists());
+        assert!(!catalog[1].path.exists());
+    }
+
+    #[test]
+    fn never_deletes_a_file_that_is_not_named_like_a_backup() {
+        let dir = TempDir::new("prune_foreign");
+        let storage = DestConfig::Path(dir.path().display().to_string()).open().unwrap();
+        let newest = entry(at(3, 15, 10));
+        let newest_path = dir.write(&newest.path.to_string_lossy(), b"backup");
+        let mut older = entry(at(3, 14, 10));
+        older.path = dir.write("MQ_notes.zip", b"not a backup");
+        let mut oldest = entry(at(3, 13, 10));
+        oldest.path = dir.write(&oldest.path.to_string_lossy(), b"backup");
+        let catalog = vec![BackupEntry { path: newest_path.clone(), ..newest }, older.clone(), oldest.clone()];
+        let retention = Retention { keep_last: Some(1), ..Retention::default() };
+        assert!(prune(&mut NoProgress, catalog, &retention, false, storage.as_ref()).is_err());
+        assert!(newest_path.exists());
+        assert!(older.path.exists());
+        //Nothing is deleted if any backup can't be deleted safely
+        assert!(oldest.path.exists());
+    }
+}
@@ -0,0 +1,395 @@
+use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::*;
+
+use crate::error::Error;
+use crate::zip_name::get_partial_path;
+
+pub use self::s3::S3Config;
+pub use self::sftp::SftpConfig;
+
+mod s3;
+mod sftp;
+
+//How long a remote storage may take to connect or answer before it counts as unreachable, unless its config sets timeout_seconds
+const DEFAULT_TIMEOUT_SECONDS: u64 = 60;
+
+//Makes the names of temporary folders unique within this process
+static TEMP_FOLDER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+//A file in a storage
+pub struct StoredFile {
+    pub key: String,
+    pub size: u64,
+}
+
+//A place backups can be copied to, like a local folder, an SFTP server or an S3 bucket
+//Files are addressed by keys, which are paths relative to the root of the storage with / as separator
+pub trait Storage {
+    //Shown to the user, e.g. sftp://backup@nas:22/backups
+    fn describe(&self) -> String;
+    //The folder of a storage on this computer. None for remote storages
+    fn local_root(&self) -> Option<&Path>;
+    //Stores a local file under the key. The key only shows up in list once the file is complete
+    fn upload(&self, local: &Path, key: &str) -> Result<(), Error>;
+    //All files whose key starts with prefix
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>, Error>;
+    //Writes the file stored under the key to a local file
+    fn download(&self, key: &str, local: &Path) -> Result<(), Error>;
+    fn delete(&self, key: &str) -> Result<(), Error>;
+
+    //Stores a local folder with all files below it, which get keys below the key
+    //Remote storages show the files one by one, so whatever uploads a folder has to mark it as complete itself
+    fn upload_dir(&self, local: &Path, key: &str) -> Result<(), Error> {
+        for entry in std::fs::read_dir(local)? {
+            let entry = entry?;
+            let entry_key = format!("{}/{}", key, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                self.upload_dir(&entry.path(), &entry_key)?;
+            } else {
+                self.upload(&entry.path(), &entry_key)?;
+            }
+        }
+        Ok(())
+    }
+
+    //Deletes a folder with all files below it
+    fn delete_dir(&self, key: &str) -> Result<(), Error> {
+        for file in self.list(&format!("{}/", key))?.into_iter() {
+            self.delete(&file.key)?;
+        }
+        Ok(())
+    }
+
+    //Folder the files of a remote storage are downloaded to before they are read, below the download cache of this run
+    fn cache_dir(&self) -> PathBuf {
+        let name: String = self.describe().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        download_cache().join(name)
+    }
+}
+
+//A dest in the config, either the path of a local folder or a storage with its settings
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum DestConfig {
+    Path(String),
+    Storage(StorageConfig),
+}
+
+//A storage in the config, chosen by "type"
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum StorageConfig {
+    #[serde(rename = "local")]
+    Local {
+        path: String,
+    },
+    #[serde(rename = "sftp")]
+    Sftp(SftpConfig),
+    #[serde(rename = "s3")]
+    S3(S3Config),
+}
+
+impl DestConfig {
+    //The folder if the dest is on this computer
+    pub fn local_path(&self) -> Option<&str> {
+        match self {
+            DestConfig::Path(path) | DestConfig::Storage(StorageConfig::Local { path }) => Some(path),
+            _ => None,
+        }
+    }
+
+    //Shown to the user without connecting to the storage
+    pub fn describe(&self) -> String {
+        match self {
+            DestConfig::Path(path) | DestConfig::Storage(StorageConfig::Local { path }) => path.clone(),
+            DestConfig::Storage(StorageConfig::Sftp(config)) => config.describe(),
+            DestConfig::Storage(StorageConfig::S3(config)) => config.describe(),
+        }
+    }
+
+    //Checks the settings without connecting to the storage
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            DestConfig::Path(path) | DestConfig::Storage(StorageConfig::Local { path }) if path.is_empty() => Err(Error::new_s("The path of a dest is empty")),
+            DestConfig::Path(_) | DestConfig::Storage(StorageConfig::Local { .. }) => Ok(()),
+            DestConfig::Storage(StorageConfig::Sftp(config)) => config.validate(),
+            DestConfig::Storage(StorageConfig::S3(config)) => config.validate(),
+        }
+    }
+
+    //Connects to the storage
+    pub fn open(&self) -> Result<Box<dyn Storage>, Error> {
+        match self {
+            DestConfig::Path(path) | DestConfig::Storage(StorageConfig::Local { path }) => Ok(Box::new(LocalStorage {
+                root: PathBuf::from(path),
+            })),
+            DestConfig::Storage(StorageConfig::Sftp(config)) => Ok(Box::new(config.connect()?)),
+            DestConfig::Storage(StorageConfig::S3(config)) => Ok(Box::new(config.connect()?)),
+        }
+    }
+}
+
+//A folder on this computer or in its network
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    //Copies to a partial file first, which is renamed once it is complete
+    fn upload(&self, local: &Path, key: &str) -> Result<(), Error> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let partial_path = get_partial_path(&path);
+        let copied = std::fs::copy(local, &partial_path).and_then(|_| std::fs::rename(&partial_path, &path));
+        if let Err(err) = copied {
+            let _ = std::fs::remove_file(&partial_path);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    //Copies into a partial folder first, which is renamed once all files are there, so a folder is complete once it shows up
+    fn upload_dir(&self, local: &Path, key: &str) -> Result<(), Error> {
+        let path = self.path(key);
+        let partial_path = get_partial_path(&path);
+        if partial_path.exists() {
+            std::fs::remove_dir_all(&partial_path)?;
+        }
+        let copied = copy_dir(local, &partial_path).and_then(|_| Ok(std::fs::rename(&partial_path, &path)?));
+        if copied.is_err() {
+            let _ = std::fs::remove_dir_all(&partial_path);
+        }
+        copied
+    }
+
+    //Also deletes the folders below the folder that have no files
+    fn delete_dir(&self, key: &str) -> Result<(), Error> {
+        std::fs::remove_dir_all(self.path(key))?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>, Error> {
+        let mut files = Vec::new();
+        let dir = prefix_dir(prefix);
+        list_local_dir(&self.path(dir), dir, prefix, &mut files)?;
+        Ok(files)
+    }
+
+    fn download(&self, key: &str, local: &Path) -> Result<(), Error> {
+        std::fs::copy(self.path(key), local)?;
+        Ok(())
+    }
+
+    //Also deletes the folders that are empty afterwards, like the ones of directory backups
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let path = self.path(key);
+        std::fs::remove_file(&path)?;
+        let mut parent = path.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != self.root && dir.starts_with(&self.root)) {
+            if std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+        Ok(())
+    }
+}
+
+//Adds the files below dir whose key starts with prefix. dir_key is the key of dir, either empty or ending with /
+fn list_local_dir(dir: &Path, dir_key: &str, prefix: &str, files: &mut Vec<StoredFile>) -> Result<(), Error> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let key = format!("{}{}", dir_key, name);
+        //Only folders on the way to the prefix or below it are walked. Partial files and folders are still being copied
+        if (!key.starts_with(prefix) && !prefix.starts_with(&format!("{}/", key))) || name.ends_with(".partial") {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_local_dir(&entry.path(), &format!("{}/", key), prefix, files)?;
+        } else if key.starts_with(prefix) {
+            files.push(StoredFile {
+                key,
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+//Copies a local folder with everything below it
+fn copy_dir(from: &Path, to: &Path) -> Result<(), Error> {
+    create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+//The folder part of a prefix, either empty or ending with /
+fn prefix_dir(prefix: &str) -> &str {
+    match prefix.rfind('/') {
+        Some(i) => &prefix[..=i],
+        None => "",
+    }
+}
+
+//Downloads a file of a storage to a partial file next to local first, so an interrupted download never looks complete
+pub fn download_completely(storage: &dyn Storage, key: &str, local: &Path) -> Result<(), Error> {
+    if let Some(parent) = local.parent() {
+        create_dir_all(parent)?;
+    }
+    let partial_path = get_partial_path(local);
+    let downloaded = storage.download(key, &partial_path).and_then(|_| Ok(std::fs::rename(&partial_path, local)?));
+    if downloaded.is_err() {
+        let _ = std::fs::remove_file(&partial_path);
+    }
+    downloaded
+}
+
+//A folder in the temp folder of the system that is deleted with everything in it when dropped, like the one a backup is made in before it is copied to its dests
+pub struct TempFolder {
+    path: PathBuf,
+}
+
+impl TempFolder {
+    pub fn new(name: &str) -> Result<TempFolder, Error> {
+        let path = std::env::temp_dir().join(format!("mq_backuper_{}_{}_{}", name, std::process::id(), TEMP_FOLDER_COUNTER.fetch_add(1, Ordering::SeqCst)));
+        create_dir_all(&path)?;
+        Ok(TempFolder { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFolder {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+//Folder the files of remote storages are downloaded to. Every run of the program has its own, which remove_download_cache deletes when it ends
+fn download_cache() -> PathBuf {
+    std::env::temp_dir().join(format!("mq_backuper_cache_{}", std::process::id()))
+}
+
+//Deletes everything this run downloaded from remote storages
+pub fn remove_download_cache() {
+    let _ = std::fs::remove_dir_all(download_cache());
+}
+
+//The timeout of a remote storage from the timeout_seconds of its config
+fn timeout(timeout_seconds: Option<u64>) -> Duration {
+    Duration::from_secs(timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS))
+}
+
+//A timeout of 0 would mean waiting forever for some connections and failing at once for others
+fn validate_timeout(timeout_seconds: Option<u64>, description: &str) -> Result<(), Error> {
+    if timeout_seconds == Some(0) {
+        return Err(Error::new_s(format!("timeout_seconds of {} has to be at least 1", description)));
+    }
+    Ok(())
+}
+
+//Reads a value of the config that names an environment variable, like the password of a storage
+fn read_env(variable: &str, what: &str) -> Result<String, Error> {
+    match std::env::var(variable) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(Error::new_s(format!("Environment variable {} with the {} is not set", variable, what))),
+    }
+}
+
+//Opens a local file to upload it
+fn open_upload(local: &Path) -> Result<(File, u64), Error> {
+    let f = File::open(local)?;
+    let size = f.metadata()?.len();
+    Ok((f, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+
+    use super::{download_completely, LocalStorage, Storage};
+
+    fn keys(storage: &dyn Storage, prefix: &str) -> Vec<(String, u64)> {
+        let mut keys: Vec<(String, u64)> = storage.list(prefix).unwrap().into_iter().map(|file| (file.key, file.size)).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn local_storage_round_trip() {
+        let local = TempDir::new("local_files");
+        let root = TempDir::new("local_storage");
+        let storage = LocalStorage {
+            root: root.path().to_path_buf(),
+        };
+        let zip = local.write("backup.zip", b"zip");
+        let blob = local.write("blob", b"blob content");
+        storage.upload(&zip, "MQ_backup_1.zip").unwrap();
+        storage.upload(&blob, "repository/blobs/ab/abcd").unwrap();
+        storage.upload(&blob, "MQ_backup_2.dir/folder/file.txt").unwrap();
+        //Uploading again replaces the file
+        storage.upload(&blob, "MQ_backup_1.zip").unwrap();
+
+        assert_eq!(keys(&storage, ""), vec![
+            ("MQ_backup_1.zip".to_string(), 12),
+            ("MQ_backup_2.dir/folder/file.txt".to_string(), 12),
+            ("repository/blobs/ab/abcd".to_string(), 12),
+        ]);
+        assert_eq!(keys(&storage, "MQ_backup_2"), vec![("MQ_backup_2.dir/folder/file.txt".to_string(), 12)]);
+        assert_eq!(keys(&storage, "repository/blobs/"), vec![("repository/blobs/ab/abcd".to_string(), 12)]);
+        assert!(keys(&storage, "repository/snapshots/").is_empty());
+        //Files that are still being written are not listed
+        root.write("MQ_backup_3.zip.partial", b"");
+        assert_eq!(keys(&storage, "MQ_backup_3").len(), 0);
+
+        let downloaded = local.path().join("downloaded").join("file.txt");
+        download_completely(&storage, "MQ_backup_2.dir/folder/file.txt", &downloaded).unwrap();
+        assert_eq!(std::fs::read(&downloaded).unwrap(), b"blob content");
+        assert!(download_completely(&storage, "missing", &local.path().join("missing")).is_err());
+        assert!(!local.path().join("missing.partial").exists());
+
+        storage.delete("MQ_backup_2.dir/folder/file.txt").unwrap();
+        storage.delete("repository/blobs/ab/abcd").unwrap();
+        assert_eq!(keys(&storage, ""), vec![("MQ_backup_1.zip".to_string(), 12)]);
+        //The folders that got empty are gone, but never the root
+        assert!(!root.path().join("MQ_backup_2.dir").exists());
+        assert!(!root.path().join("repository").exists());
+        storage.delete("MQ_backup_1.zip").unwrap();
+        assert!(keys(&storage, "").is_empty());
+        assert!(root.path().exists());
+    }
+}
@@ -0,0 +1,406 @@
+use std::fs::File;
+use std::io::{copy, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use serde::*;
+use sha2::{Digest, Sha256};
+use ureq::{Agent, AgentBuilder, Request, Response};
+
+use crate::error::Error;
+use crate::storage::{open_upload, read_env, Storage, StoredFile, timeout, validate_timeout};
+
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_ACCESS_KEY_ENV: &str = "AWS_ACCESS_KEY_ID";
+const DEFAULT_SECRET_KEY_ENV: &str = "AWS_SECRET_ACCESS_KEY";
+//Headers that are part of the signature of every request
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+//Uploaded content is not hashed for the signature, as that would mean reading every file twice. The connection protects it anyway
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+//Files larger than this are uploaded in parts, as a single upload may not be larger than 5 GB
+const MULTIPART_THRESHOLD: u64 = 1024 * 1024 * 1024;
+const PART_SIZE: u64 = 128 * 1024 * 1024;
+
+//A bucket of Amazon S3 or of a compatible server like MinIO in the config. The keys are never written into the config, it only names the environment variables that hold them
+#[derive(Debug, Deserialize, Clone)]
+pub struct S3Config {
+    //Address of the server, like https://s3.eu-central-1.amazonaws.com or http://localhost:9000
+    pub endpoint: String,
+    //Defaults to us-east-1, which MinIO uses as well
+    pub region: Option<String>,
+    pub bucket: String,
+    //Folder in the bucket the backups are stored in. Defaults to the top of the bucket
+    pub prefix: Option<String>,
+    //Environment variable with the access key. Defaults to AWS_ACCESS_KEY_ID
+    pub access_key_env: Option<String>,
+    //Environment variable with the secret key. Defaults to AWS_SECRET_ACCESS_KEY
+    pub secret_key_env: Option<String>,
+    //Seconds to wait for connecting and for every read or write before the server counts as unreachable. Defaults to 60
+    pub timeout_seconds: Option<u64>,
+}
+
+impl S3Config {
+    //The address of the folder in the bucket
+    pub fn describe(&self) -> String {
+        format!("{}/{}/{}", self.endpoint.trim_end_matches('/'), self.bucket, self.prefix()).trim_end_matches('/').to_string()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.endpoint.starts_with("http://") && !self.endpoint.starts_with("https://") {
+            return Err(Error::new_s(format!("The endpoint {} of an s3 dest has to start with http:// or https://", self.endpoint)));
+        }
+        if self.bucket.is_empty() || self.bucket.contains('/') {
+            return Err(Error::new_s(format!("{} is no valid bucket name", self.bucket)));
+        }
+        validate_timeout(self.timeout_seconds, &self.describe())
+    }
+
+    //The prefix either empty or ending with /
+    fn prefix(&self) -> String {
+        let prefix = self.prefix.as_deref().unwrap_or("").trim_matches('/');
+        if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", prefix)
+        }
+    }
+
+    //Reads the keys. Nothing is sent to the server before the first request
+    //A request to a server that doesn't answer fails after the timeout like any other request
+    pub fn connect(&self) -> Result<S3Storage, Error> {
+        let timeout = timeout(self.timeout_seconds);
+        let endpoint = self.endpoint.trim_end_matches('/').to_string();
+        let authority = endpoint.split_once("://").map(|(_, authority)| authority).unwrap_or("");
+        //The Host header leaves out the default port of the scheme, so the signature has to as well
+        let host = authority.split('/').next().unwrap_or("")
+            .trim_end_matches(if endpoint.starts_with("https://") { ":443" } else { ":80" })
+            .to_string();
+        Ok(S3Storage {
+            description: self.describe(),
+            endpoint,
+            host,
+            region: self.region.clone().unwrap_or_else(|| DEFAULT_REGION.to_string()),
+            bucket: self.bucket.clone(),
+            prefix: self.prefix(),
+            access_key: read_env(self.access_key_env.as_deref().unwrap_or(DEFAULT_ACCESS_KEY_ENV), "S3 access key")?,
+            secret_key: read_env(self.secret_key_env.as_deref().unwrap_or(DEFAULT_SECRET_KEY_ENV), "S3 secret key")?,
+            agent: AgentBuilder::new()
+                .timeout_connect(timeout)
+                .timeout_read(timeout)
+                .timeout_write(timeout)
+                .build(),
+        })
+    }
+}
+
+//A folder in a bucket, addressed path style (endpoint/bucket/key) as all S3 compatible servers support it
+pub struct S3Storage {
+    description: String,
+    endpoint: String,
+    host: String,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    agent: Agent,
+}
+
+impl S3Storage {
+    //Creates a request signed with AWS signature version 4
+    fn request(&self, method: &str, key: Option<&str>, query: &[(&str, &str)], payload_hash: &str) -> Request {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let path = match key {
+            Some(key) => format!("/{}/{}", self.bucket, uri_encode(&format!("{}{}", self.prefix, key), false)),
+            None => format!("/{}", self.bucket),
+        };
+        let query = canonical_query(query);
+        let scope = credential_scope(&amz_date, &self.region);
+        let signature = signature(&self.secret_key, &self.region, &amz_date, &canonical_request(method, &path, &query, &self.host, payload_hash, &amz_date));
+        let mut url = format!("{}{}", self.endpoint, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        self.agent.request(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", payload_hash)
+            .set("Authorization", &format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.access_key, scope, SIGNED_HEADERS, signature))
+    }
+
+    //Turns an error response of the server into an error with the message the server sent
+    fn checked(&self, what: String, sent: Result<Response, ureq::Error>) -> Result<Response, Error> {
+        match sent {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                let message = xml_value(&body, "Message").unwrap_or(body);
+                Err(Error::new_s(format!("{} failed with status {}: {}", what, status, message)))
+            }
+            Err(err) => Err(Error::new_j(format!("{} failed", what), err.into())),
+        }
+    }
+
+    //Uploads a large file in parts, which the server puts together once all are there. An upload that fails is aborted, so the parts don't take up space
+    fn upload_in_parts(&self, f: &mut File, size: u64, key: &str) -> Result<(), Error> {
+        let response = self.checked(format!("Starting the upload of {}", key), self.request("POST", Some(key), &[("uploads", "")], &sha256_hex(b"")).call())?;
+        let upload_id = match xml_value(&response.into_string()?, "UploadId") {
+            Some(upload_id) => upload_id,
+            None => return Err(Error::new_s(format!("The server sent no UploadId for {}", key))),
+        };
+        let uploaded = self.upload_parts(f, size, key, &upload_id);
+        if uploaded.is_err() {
+            let _ = self.request("DELETE", Some(key), &[("uploadId", &upload_id)], &sha256_hex(b"")).call();
+        }
+        uploaded
+    }
+
+    fn upload_parts(&self, f: &mut File, size: u64, key: &str, upload_id: &str) -> Result<(), Error> {
+        let mut completion = String::from("<CompleteMultipartUpload>");
+        let part_count = size.div_ceil(PART_SIZE);
+        for part in 0..part_count {
+            let start = part * PART_SIZE;
+            let length = PART_SIZE.min(size - start);
+            let part_number = (part + 1).to_string();
+            f.seek(SeekFrom::Start(start))?;
+            let sent = self.request("PUT", Some(key), &[("partNumber", &part_number), ("uploadId", upload_id)], UNSIGNED_PAYLOAD)
+                .set("Content-Length", &length.to_string())
+                .send(Read::by_ref(f).take(length));
+            let response = self.checked(format!("Uploading part {} of {} of {}", part_number, part_count, key), sent)?;
+            let etag = match response.header("ETag") {
+                Some(etag) => etag.to_string(),
+                None => return Err(Error::new_s(format!("The server sent no ETag for part {} of {}", part_number, key))),
+            };
+            completion.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", part_number, xml_escape(&etag)));
+        }
+        completion.push_str("</CompleteMultipartUpload>");
+        let sent = self.request("POST", Some(key), &[("uploadId", upload_id)], &sha256_hex(completion.as_bytes()))
+            .set("Content-Length", &completion.len().to_string())
+            .send_bytes(completion.as_bytes());
+        //Completing can fail after the server already answered with 200, the error is in the body then
+        let body = self.checked(format!("Completing the upload of {}", key), sent)?.into_string()?;
+        if body.contains("<Error>") {
+            return Err(Error::new_s(format!("Completing the upload of {} failed: {}", key, xml_value(&body, "Message").unwrap_or(body))));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for S3Storage {
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+
+    //Objects only show up once they are uploaded completely
+    fn upload(&self, local: &Path, key: &str) -> Result<(), Error> {
+        let (mut f, size) = open_upload(local)?;
+        if size > MULTIPART_THRESHOLD {
+            return self.upload_in_parts(&mut f, size, key);
+        }
+        let sent = self.request("PUT", Some(key), &[], UNSIGNED_PAYLOAD)
+            .set("Content-Length", &size.to_string())
+            .send(f);
+        self.checked(format!("Uploading {}", key), sent)?;
+        Ok(())
+    }
+
+    //Lists in pages of up to 1000 objects, as the server returns them
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>, Error> {
+        let mut files = Vec::new();
+        let object_prefix = format!("{}{}", self.prefix, prefix);
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", object_prefix.as_str())];
+            if let Some(token) = continuation_token.as_ref() {
+                query.push(("continuation-token", token));
+            }
+            let response = self.checked(format!("Listing {}", self.description), self.request("GET", None, &query, &sha256_hex(b"")).call())?;
+            let body = response.into_string()?;
+            let (page, next_token) = match parse_list(&body, &self.prefix) {
+                Ok(page) => page,
+                Err(err) => return Err(Error::new_s(format!("The server sent an invalid list of {}: {}", self.description, err))),
+            };
+            files.extend(page);
+            continuation_token = next_token;
+            if continuation_token.is_none() {
+                return Ok(files);
+            }
+        }
+    }
+
+    fn download(&self, key: &str, local: &Path) -> Result<(), Error> {
+        let response = self.checked(format!("Downloading {}", key), self.request("GET", Some(key), &[], &sha256_hex(b"")).call())?;
+        copy(&mut response.into_reader(), &mut File::create(local)?)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        self.checked(format!("Deleting {}", key), self.request("DELETE", Some(key), &[], &sha256_hex(b"")).call())?;
+        Ok(())
+    }
+}
+
+//The objects of one page of a ListObjectsV2 answer with the prefix of the storage removed from their keys
+//The continuation token is only returned if the list is truncated and there are more pages
+fn parse_list(body: &str, prefix: &str) -> Result<(Vec<StoredFile>, Option<String>), roxmltree::Error> {
+    let document = roxmltree::Document::parse(body)?;
+    let mut files = Vec::new();
+    for contents in document.descendants().filter(|node| node.has_tag_name("Contents")) {
+        let child_text = |name: &str| contents.children().find(|node| node.has_tag_name(name)).and_then(|node| node.text()).unwrap_or("");
+        if let Some(key) = child_text("Key").strip_prefix(prefix) {
+            files.push(StoredFile {
+                key: key.to_string(),
+                size: child_text("Size").parse().unwrap_or(0),
+            });
+        }
+    }
+    let is_truncated = document.descendants().any(|node| node.has_tag_name("IsTruncated") && node.text() == Some("true"));
+    let continuation_token = document.descendants().find(|node| node.has_tag_name("NextContinuationToken")).and_then(|node| node.text()).map(String::from);
+    Ok((files, continuation_token.filter(|_| is_truncated)))
+}
+
+//The query string as it is signed and sent: encoded and sorted by name
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut query: Vec<(String, String)> = query.iter().map(|(name, value)| (uri_encode(name, true), uri_encode(value, true))).collect();
+    query.sort();
+    query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<String>>().join("&")
+}
+
+//The request in the form AWS signature version 4 signs it, with the headers of SIGNED_HEADERS
+fn canonical_request(method: &str, path: &str, query: &str, host: &str, payload_hash: &str, amz_date: &str) -> String {
+    let canonical_headers = format!("host:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n", host, payload_hash, amz_date);
+    format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, SIGNED_HEADERS, payload_hash)
+}
+
+//The day and region a signature is valid for
+fn credential_scope(amz_date: &str, region: &str) -> String {
+    format!("{}/{}/s3/aws4_request", amz_date.get(..8).unwrap_or(amz_date), region)
+}
+
+//Signs a canonical request with a key derived from the secret key for the day, the region and the service
+fn signature(secret_key: &str, region: &str, amz_date: &str, canonical_request: &str) -> String {
+    let scope = credential_scope(amz_date, region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+    let mut signing_key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), amz_date.get(..8).unwrap_or(amz_date).as_bytes());
+    for part in &[region, "s3", "aws4_request"] {
+        signing_key = hmac_sha256(&signing_key, part.as_bytes());
+    }
+    hmac_sha256(&signing_key, string_to_sign.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+//Percent encodes everything but the characters S3 leaves as they are. / is kept in paths
+fn uri_encode(text: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+//Text of the first element with the name in an XML answer of the server
+fn xml_value(xml: &str, name: &str) -> Option<String> {
+    let document = roxmltree::Document::parse(xml).ok()?;
+    let value = document.descendants().find(|node| node.has_tag_name(name))?.text()?.to_string();
+    Some(value)
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical_query, canonical_request, parse_list, sha256_hex, signature, uri_encode};
+
+    //The GET Bucket example of the AWS documentation of signature version 4 for S3
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+    const AMZ_DATE: &str = "20130524T000000Z";
+    const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn signs_like_the_aws_example() {
+        let query = canonical_query(&[("prefix", "J"), ("max-keys", "2")]);
+        assert_eq!(query, "max-keys=2&prefix=J");
+        let canonical_request = canonical_request("GET", "/", &query, "examplebucket.s3.amazonaws.com", EMPTY_PAYLOAD_HASH, AMZ_DATE);
+        assert_eq!(canonical_request, [
+            "GET",
+            "/",
+            "max-keys=2&prefix=J",
+            "host:examplebucket.s3.amazonaws.com",
+            "x-amz-content-sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "x-amz-date:20130524T000000Z",
+            "",
+            "host;x-amz-content-sha256;x-amz-date",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ].join("\n"));
+        assert_eq!(sha256_hex(canonical_request.as_bytes()), "df57d21db20da04d7fa30298dd4488ba3a2b47ca3a489c74750e0f1e7df1b9b7");
+        assert_eq!(signature(SECRET_KEY, "us-east-1", AMZ_DATE, &canonical_request), "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7");
+    }
+
+    #[test]
+    fn encodes_uris_like_s3() {
+        assert_eq!(uri_encode("AZaz09-_.~", true), "AZaz09-_.~");
+        assert_eq!(uri_encode("a b+c=d&e", true), "a%20b%2Bc%3Dd%26e");
+        assert_eq!(uri_encode("backups/MQ_backup.zip", false), "backups/MQ_backup.zip");
+        assert_eq!(uri_encode("backups/MQ_backup.zip", true), "backups%2FMQ_backup.zip");
+        //Every byte of a character outside of ASCII is encoded on its own
+        assert_eq!(uri_encode("ä", true), "%C3%A4");
+        assert_eq!(canonical_query(&[("uploads", "")]), "uploads=");
+    }
+
+    #[test]
+    fn parses_list_pages() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+    <Name>bucket</Name>
+    <Prefix>backups/MQ_backup_</Prefix>
+    <KeyCount>2</KeyCount>
+    <MaxKeys>1000</MaxKeys>
+    <IsTruncated>true</IsTruncated>
+    <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=</NextContinuationToken>
+    <Contents>
+        <Key>backups/MQ_backup_2024_03_15__12_00_00.zip</Key>
+        <LastModified>2024-03-15T12:00:05.000Z</LastModified>
+        <Size>1234</Size>
+    </Contents>
+    <Contents>
+        <Key>backups/MQ_backup_2024_03_15__12_00_00.zip.manifest.json</Key>
+        <Size>56</Size>
+    </Contents>
+</ListBucketResult>"#;
+        let (files, continuation_token) = parse_list(body, "backups/").unwrap();
+        let files: Vec<(&str, u64)> = files.iter().map(|file| (file.key.as_str(), file.size)).collect();
+        assert_eq!(files, vec![
+            ("MQ_backup_2024_03_15__12_00_00.zip", 1234),
+            ("MQ_backup_2024_03_15__12_00_00.zip.manifest.json", 56),
+        ]);
+        assert_eq!(continuation_token.as_deref(), Some("1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM="));
+
+        //A token is only followed while the list is truncated
+        let last_page = "<ListBucketResult><IsTruncated>false</IsTruncated><NextContinuationToken>x</NextContinuationToken></ListBucketResult>";
+        let (files, continuation_token) = parse_list(last_page, "").unwrap();
+        assert!(files.is_empty());
+        assert_eq!(continuation_token, None);
+
+        assert!(parse_list("<ListBucketResult>", "").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::copy;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::*;
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+
+use crate::error::Error;
+use crate::storage::{open_upload, prefix_dir, read_env, Storage, StoredFile, timeout, validate_timeout};
+
+const DEFAULT_PORT: u16 = 22;
+
+//An SFTP server in the config. Passwords are never written into the config, it only names the environment variable that holds them
+#[derive(Debug, Deserialize, Clone)]
+pub struct SftpConfig {
+    pub host: String,
+    //Defaults to 22
+    pub port: Option<u16>,
+    pub user: String,
+    //Folder on the server the backups are stored in
+    pub path: String,
+    //Environment variable with the password. Not needed with key_file
+    pub password_env: Option<String>,
+    //Private key to log in with instead of a password
+    pub key_file: Option<String>,
+    //Environment variable with the passphrase of key_file if it has one
+    pub key_passphrase_env: Option<String>,
+    //The key of the server has to be in this file. Defaults to .ssh/known_hosts in the home folder, where ssh adds it when connecting the first time
+    pub known_hosts: Option<String>,
+    //Seconds to wait for connecting and for every answer of the server before it counts as unreachable. Defaults to 60
+    pub timeout_seconds: Option<u64>,
+}
+
+impl SftpConfig {
+    pub fn describe(&self) -> String {
+        format!("sftp://{}@{}:{}{}", self.user, self.host, self.port.unwrap_or(DEFAULT_PORT), self.root())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.host.is_empty() || self.user.is_empty() || self.path.is_empty() {
+            return Err(Error::new_s("An sftp dest needs host, user and path"));
+        }
+        if self.password_env.is_none() && self.key_file.is_none() {
+            return Err(Error::new_s(format!("{} needs password_env or key_file", self.describe())));
+        }
+        validate_timeout(self.timeout_seconds, &self.describe())
+    }
+
+    //The folder on the server without a trailing /
+    fn root(&self) -> String {
+        let root = self.path.trim_end_matches('/');
+        if root.starts_with('/') {
+            root.to_string()
+        } else {
+            format!("/{}", root)
+        }
+    }
+
+    fn known_hosts_path(&self) -> Result<PathBuf, Error> {
+        if let Some(known_hosts) = self.known_hosts.as_ref() {
+            return Ok(PathBuf::from(known_hosts));
+        }
+        match std::env::var("HOME").or_else(|_| std::env::var("USERPROFILE")) {
+            Ok(home) => Ok(Path::new(&home).join(".ssh").join("known_hosts")),
+            Err(_) => Err(Error::new_s(format!("No home folder found to read known_hosts from. Set known_hosts for {}", self.describe()))),
+        }
+    }
+
+    //Logs in and opens the SFTP channel. The key of the server is checked before any password or key is sent
+    //Every call to the server fails once it takes longer than the timeout, so a server that stops answering doesn't stall the backup
+    pub fn connect(&self) -> Result<SftpStorage, Error> {
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        let timeout = timeout(self.timeout_seconds);
+        let mut session = Session::new()?;
+        session.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+        session.set_tcp_stream(self.connect_tcp(port, timeout)?);
+        session.handshake()?;
+        self.check_host_key(&session, port)?;
+        if let Some(key_file) = self.key_file.as_ref() {
+            let passphrase = match self.key_passphrase_env.as_ref() {
+                Some(variable) => Some(read_env(variable, "passphrase of the key_file")?),
+                None => None,
+            };
+            session.userauth_pubkey_file(&self.user, None, Path::new(key_file), passphrase.as_deref())?;
+        } else if let Some(password_env) = self.password_env.as_ref() {
+            session.userauth_password(&self.user, &read_env(password_env, "SFTP password")?)?;
+        }
+        if !session.authenticated() {
+            return Err(Error::new_s(format!("Login to {} failed", self.describe())));
+        }
+        Ok(SftpStorage {
+            description: self.describe(),
+            root: self.root(),
+            sftp: session.sftp()?,
+        })
+    }
+
+    //Tries every address of the host until one answers within the timeout
+    fn connect_tcp(&self, port: u16, timeout: Duration) -> Result<TcpStream, Error> {
+        let mut last_error = None;
+        for address in (self.host.as_str(), port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        match last_error {
+            Some(err) => Err(Error::new_j(format!("Connecting to {}:{} failed", self.host, port), err.into())),
+            None => Err(Error::new_s(format!("{} has no address", self.host))),
+        }
+    }
+
+    fn check_host_key(&self, session: &Session, port: u16) -> Result<(), Error> {
+        let known_hosts_path = self.known_hosts_path()?;
+        let (key, _) = match session.host_key() {
+            Some(host_key) => host_key,
+            None => return Err(Error::new_s(format!("{} sent no host key", self.host))),
+        };
+        let mut known_hosts = session.known_hosts()?;
+        if known_hosts_path.exists() {
+            known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)?;
+        }
+        match known_hosts.check_port(&self.host, port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(Error::new_s(format!("The key of {} does not match the one in {}. Someone may be pretending to be the server", self.host, known_hosts_path.display()))),
+            CheckResult::NotFound => Err(Error::new_s(format!("{} is not in {}. Connect once with ssh to add its key", self.host, known_hosts_path.display()))),
+            CheckResult::Failure => Err(Error::new_s(format!("The key of {} could not be checked", self.host))),
+        }
+    }
+}
+
+//A folder on an SFTP server
+pub struct SftpStorage {
+    description: String,
+    root: String,
+    sftp: Sftp,
+}
+
+impl SftpStorage {
+    //Paths on the server always use /, no matter on which system this program runs
+    fn remote_path(&self, key: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{}", self.root, key))
+    }
+
+    //Creates the folders of the key that don't exist yet
+    fn create_dirs(&self, key: &str) -> Result<(), Error> {
+        let mut dir = self.root.clone();
+        let parents = key.rsplit_once('/').map(|(parents, _)| parents).unwrap_or("");
+        let check = |dir: &str| -> Result<(), Error> {
+            if self.sftp.stat(Path::new(dir)).is_err() {
+                self.sftp.mkdir(Path::new(dir), 0o755)?;
+            }
+            Ok(())
+        };
+        check(&dir)?;
+        for part in parents.split('/').filter(|p| !p.is_empty()) {
+            dir = format!("{}/{}", dir, part);
+            check(&dir)?;
+        }
+        Ok(())
+    }
+
+    //Renames the file to path, replacing the file that is already there
+    //SFTP version 3, which most servers speak, ignores the flags and fails if path exists, so it is deleted first then
+    fn replace(&self, file: &Path, path: &Path) -> Result<(), Error> {
+        if self.sftp.rename(file, path, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE)).is_ok() {
+            return Ok(());
+        }
+        if self.sftp.stat(path).is_ok() {
+            self.sftp.unlink(path)?;
+        }
+        Ok(self.sftp.rename(file, path, None)?)
+    }
+
+    //Adds the files below the folder with the key dir_key whose key starts with prefix
+    fn list_dir(&self, dir_key: &str, prefix: &str, files: &mut Vec<StoredFile>) -> Result<(), Error> {
+        let dir = self.remote_path(dir_key);
+        if self.sftp.stat(&dir).is_err() {
+            return Ok(());
+        }
+        for (path, stat) in self.sftp.readdir(&dir)?.into_iter() {
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let key = format!("{}{}", dir_key, name);
+            if !key.starts_with(prefix) && !prefix.starts_with(&format!("{}/", key)) {
+                continue;
+            }
+            if stat.is_dir() {
+                self.list_dir(&format!("{}/", key), prefix, files)?;
+            } else if key.starts_with(prefix) && !name.ends_with(".partial") {
+                files.push(StoredFile {
+                    key,
+                    size: stat.size.unwrap_or(0),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SftpStorage {
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+
+    //Uploads to a partial file first, which is renamed once it is complete
+    fn upload(&self, local: &Path, key: &str) -> Result<(), Error> {
+        self.create_dirs(key)?;
+        let (mut f, _) = open_upload(local)?;
+        let partial_path = self.remote_path(&format!("{}.partial", key));
+        let uploaded = self.sftp.create(&partial_path)
+            .map_err(Error::from)
+            .and_then(|mut remote| Ok(copy(&mut f, &mut remote)?))
+            .and_then(|_| self.replace(&partial_path, &self.remote_path(key)));
+        if uploaded.is_err() {
+            let _ = self.sftp.unlink(&partial_path);
+        }
+        uploaded
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredFile>, Error> {
+        let mut files = Vec::new();
+        self.list_dir(prefix_dir(prefix), prefix, &mut files)?;
+        Ok(files)
+    }
+
+    fn download(&self, key: &str, local: &Path) -> Result<(), Error> {
+        let mut remote = self.sftp.open(self.remote_path(key))?;
+        copy(&mut remote, &mut File::create(local)?)?;
+        Ok(())
+    }
+
+    //Also deletes the folders that are empty afterwards, like the ones of directory backups
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        self.sftp.unlink(&self.remote_path(key))?;
+        let mut dir_key = key;
+        while let Some((parent, _)) = dir_key.rsplit_once('/') {
+            if self.sftp.rmdir(&self.remote_path(parent)).is_err() {
+                break;
+            }
+            dir_key = parent;
+        }
+        Ok(())
+    }
+}
@@ -4,20 +4,73 @@ use std::path::Path;
 
 use serde::*;
 
+use crate::compression::Compression;
 use crate::error::Error;
+use crate::file_filter::{Age, ByteSize, FileFilter};
 use crate::local_installation::LocalInstallation;
+use crate::patterns::PathPatterns;
 
 #[derive(Debug, Deserialize)]
 pub struct Systems {
     pub systems: Option<Vec<LocalInstallation>>,
+    //How many systems are backed up at once when all of them are. Defaults to 1
+    pub workers: Option<usize>,
 }
 
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct BackupRelPath {
+    //If set, only files matching one of these patterns are backed up. Same syntax as excluded_files, applied before them
+    pub included_files: Option<Vec<String>>,
+    //gitignore style patterns relative to rel_path, like *.sbk, show/temp/**, autosave_* or !keep.sbk
     pub excluded_files: Option<Vec<String>>,
     pub rel_path: String,
     pub include_subfolders: bool,
+    //Folders that are not walked at all, by name like cache or .git or by pattern like show/logs or autosave_*
+    pub excluded_dirs: Option<Vec<String>>,
+    //How many folder levels below rel_path are walked if include_subfolders is set. 1 means only the direct subfolders
+    pub max_depth: Option<usize>,
+    //Files larger than this are skipped, e.g. 500MB or 2GB
+    pub max_file_size: Option<ByteSize>,
+    //Files smaller than this are skipped
+    pub min_file_size: Option<ByteSize>,
+    //Only files changed within this time are backed up, e.g. 12h or 7d
+    pub modified_within: Option<Age>,
+    //Files changed within this time are skipped, e.g. 10m for files that may still be written
+    pub modified_before: Option<Age>,
+    //Overrides the compression of the system for the files of this path, e.g. "stored" for folders with videos
+    pub compression: Option<Compression>,
+}
+
+impl BackupRelPath {
+    //The compiled included_files, None if all files are included
+    pub fn included_patterns(&self) -> Result<Option<PathPatterns>, Error> {
+        match &self.included_files {
+            None => Ok(None),
+            Some(included_files) => Ok(Some(PathPatterns::new(included_files)?)),
+        }
+    }
+    //The compiled excluded_files
+    pub fn excluded_patterns(&self) -> Result<PathPatterns, Error> {
+        PathPatterns::new(self.excluded_files.as_deref().unwrap_or(&[]))
+    }
+    //The compiled excluded_dirs
+    pub fn excluded_dir_patterns(&self) -> Result<PathPatterns, Error> {
+        PathPatterns::new(self.excluded_dirs.as_deref().unwrap_or(&[]))
+    }
+    //The size and age limits for the files
+    pub fn file_filter(&self) -> FileFilter {
+        FileFilter {
+            max_file_size: self.max_file_size,
+            min_file_size: self.min_file_size,
+            modified_within: self.modified_within,
+            modified_before: self.modified_before,
+        }
+    }
+    //Says if a folder at the given depth below rel_path is walked. rel_path itself has depth 0
+    pub fn walks_into_depth(&self, depth: usize) -> bool {
+        self.include_subfolders && self.max_depth.map(|max_depth| depth <= max_depth).unwrap_or(true)
+    }
 }
 
 pub const CONFIG_FILE_NAME: &str = "config.json";
@@ -27,16 +80,38 @@ pub fn get_example_config_file() -> String {
 }
 
 const EXAMPLE_CONFIG_FILE_WITHOUT_UN: &str = r#"{
+  "workers": 2,
   "systems": [
     {
       "name": "My MQ500m",
       "src": "M:\\magicq",
-      "dest": "C:\\PathToYourGoogleDriveFolder",
+      "dest": [
+        "C:\\PathToYourGoogleDriveFolder",
+        "E:\\MQBackups",
+        {
+          "type": "sftp",
+          "host": "nas.local",
+          "user": "backup",
+          "path": "/backups/magicq",
+          "password_env": "MQ_SFTP_PASSWORD",
+          "timeout_seconds": 30
+        }
+      ],
+      "verify_after_backup": true,
+      "archive_format": "tar.zst",
+      "incremental": true,
+      "max_deltas": 6,
+      "retention": {
+        "keep_last": 10,
+        "keep_daily": 7,
+        "keep_weekly": 8
+      },
       "backup_rel_paths": [
         {
           "excluded_files": [
             "heads.all",
-            "*.sbk"
+            "*.sbk",
+            "autosave_*"
           ],
           "rel_path": "show",
           "include_subfolders": false
@@ -44,13 +119,31 @@ const EXAMPLE_CONFIG_FILE_WITHOUT_UN: &str = r#"{
         {
           "rel_path": "show\\icons\\icon0a00000b.mc2",
           "include_subfolders": true
+        },
+        {
+          "included_files": [
+            "*.shw",
+            "*.mc2"
+          ],
+          "rel_path": "show\\projects",
+          "include_subfolders": true
         }
       ]
     },
     {
       "name": "MagicQ on Pc",
       "src": "C:\\Users\\{your_username}\\Documents\\MagicQ",
-      "dest": "C:\\PathToYourGoogleDriveFolder",
+      "dest": [
+        "C:\\PathToYourGoogleDriveFolder",
+        {
+          "type": "s3",
+          "endpoint": "https://s3.eu-central-1.amazonaws.com",
+          "region": "eu-central-1",
+          "bucket": "my-show-backups",
+          "prefix": "magicq-pc"
+        }
+      ],
+      "repository": true,
       "backup_rel_paths": [
         {
           "excluded_files": [
@@ -70,22 +163,49 @@ const EXAMPLE_CONFIG_FILE_WITHOUT_UN: &str = r#"{
       "name": "Capture",
       "src": "D:\\{your_username}\\Documents\\Capture",
       "dest": "C:\\PathToYourGoogleDriveFolder",
+      "skip_unchanged": true,
+      "compare_hashes": false,
+      "buffer_size": 1048576,
+      "compression": {
+        "method": "zstd",
+        "level": 9,
+        "store_compressed": true
+      },
+      "encryption": {
+        "passphrase_env": "MQ_BACKUP_PASSPHRASE",
+        "prompt": true
+      },
       "backup_rel_paths": [
         {
           "rel_path": "",
-          "include_subfolders": true
+          "include_subfolders": true,
+          "excluded_dirs": [
+            "cache",
+            "logs",
+            ".git",
+            "videos"
+          ],
+          "max_depth": 5,
+          "max_file_size": "500MB",
+          "modified_before": "10m"
+        },
+        {
+          "rel_path": "videos",
+          "include_subfolders": true,
+          "compression": {
+            "method": "stored"
+          }
         }
       ]
     }
   ]
 }"#;
 
-//Loads the systems from Config.json
-fn load_systems() -> Result<Systems, Error> {
-    let path = Path::new(&CONFIG_FILE_NAME);
+//Loads the systems from a config file
+fn load_systems(path: &Path) -> Result<Systems, Error> {
     if !path.exists() {
         return Err(Error::new(vec![
-            format!("file {} is missing. Please add file before using the application", &CONFIG_FILE_NAME),
+            format!("file {} is missing. Please add file before using the application", path.display()),
             "Consider looking into the help section for further information".to_string(),
         ]));
     }
@@ -96,6 +216,9 @@ fn load_systems() -> Result<Systems, Error> {
 pub struct ValidConsolesAndLocalInstallations {
     pub systems: Vec<LocalInstallation>,
     pub warnings: Vec<Error>,
+    //Notices about settings of the config that apply to all systems. They don't make any system invalid
+    pub notices: Vec<Error>,
+    pub workers: usize,
 }
 
 impl ValidConsolesAndLocalInstallations {
@@ -106,12 +229,26 @@ impl ValidConsolesAndLocalInstallations {
 
 //Loads all systems from config file, prints errors if available and returns valid entries as well as a list of errors that should just be warnings
 pub fn load_validated_consoles_and_local_installations() -> Result<ValidConsolesAndLocalInstallations, Error> {
-    match load_systems() {
+    load_validated_systems_from(Path::new(CONFIG_FILE_NAME))
+}
+
+//Same as load_validated_consoles_and_local_installations for a config file somewhere else, like one of a program that embeds the backups
+pub fn load_validated_systems_from(path: &Path) -> Result<ValidConsolesAndLocalInstallations, Error> {
+    match load_systems(path) {
         Ok(systems) => {
             let mut warnings = Vec::new();
+            let mut notices = Vec::new();
             let mut local_installations = Vec::new();
-            if systems.systems.is_some() {
-                for local_installation in systems.systems.unwrap().into_iter() {
+            let workers = match systems.workers {
+                Some(0) => {
+                    notices.push(Error::new_s("workers has to be at least 1, the systems are backed up one after another"));
+                    1
+                }
+                Some(workers) => workers,
+                None => 1,
+            };
+            if let Some(systems) = systems.systems {
+                for local_installation in systems.into_iter() {
                     match local_installation.validate() {
                         Ok(_) => local_installations.push(local_installation),
                         Err(e) => warnings.push(e)
@@ -122,10 +259,12 @@ pub fn load_validated_consoles_and_local_installations() -> Result<ValidConsoles
             Ok(ValidConsolesAndLocalInstallations {
                 systems: local_installations,
                 warnings,
+                notices,
+                workers,
             })
         }
         Err(err) => {
-            Err(Error::new_j(format!("could not read {}", CONFIG_FILE_NAME), err))
+            Err(Error::new_j(format!("could not read {}", path.display()), err))
         }
     }
 }
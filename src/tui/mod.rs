@@ -1,24 +1,49 @@
 use std::{
     io::{stdin, stdout, Write},
 };
+use std::cmp::Reverse;
 use std::io::{Stdin, Stdout};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use crossterm::{ExecutableCommand, style::{Color, SetForegroundColor}};
+use crossterm::{Command, ExecutableCommand, style::{Color, SetForegroundColor}};
 use crossterm::cursor::MoveTo;
 use crossterm::style::{Attribute, ResetColor, SetAttribute};
 use crossterm::terminal::{Clear, ClearType};
 
-use crate::local_installation::LocalInstallation;
-use crate::systems::{CONFIG_FILE_NAME, create_config_json, get_example_config_file, load_validated_consoles_and_local_installations};
+use mq_backuper::backup_plan::export_plans;
+use mq_backuper::catalog::{BackupEntry, format_size, list_contents};
+use mq_backuper::local_installation::LocalInstallation;
+use mq_backuper::manifest::Manifest;
+use mq_backuper::progress::{Counts, Meter, Progress};
+use mq_backuper::repository::{export_to_zip, is_snapshot};
+use mq_backuper::restore::RestoreOptions;
+use mq_backuper::storage::remove_download_cache;
+use mq_backuper::systems::{CONFIG_FILE_NAME, create_config_json, get_example_config_file, load_validated_consoles_and_local_installations, ValidConsolesAndLocalInstallations};
+
+use crate::cli::USAGE;
 
 pub const SEPARATOR_LINE: &[u8] = "---------------------------------------------------------------------\n".as_bytes();
 pub const EMPTY_LINE: &[u8] = "\n".as_bytes();
+//The progress line is redrawn at most this often, so small files don't make the console flicker
+const PROGRESS_REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+//In headless mode the progress is written every this many percent, as every line stays in the log
+const HEADLESS_PROGRESS_STEP_PERCENT: u64 = 10;
 
 //Terminal UI
 //It has multiple methods to enter a program-part or menu. These parts are blocking, showing the user choices, then the choice is sent back up the tree (so unused variables get dropped) until the main loop to show the next (or same) menu
+#[allow(clippy::upper_case_acronyms)]
 pub struct TUI {
     stdout: Stdout,
     stdin: Stdin,
+    headless: bool,
+    //Progress of the archive that is written right now
+    meter: Option<Meter>,
+    //Shown behind the progress bar while there is one
+    current_task: String,
+    progress_drawn: Option<Instant>,
+    //The last step of HEADLESS_PROGRESS_STEP_PERCENT that was written
+    progress_step: u64,
 }
 
 
@@ -32,13 +57,26 @@ impl TUI {
         TUI {
             stdout,
             stdin,
+            headless: false,
+            meter: None,
+            current_task: String::new(),
+            progress_drawn: None,
+            progress_step: 0,
+        }
+    }
+
+    //Terminal UI for the headless command line mode. It never clears the console or writes styling codes, so the output of scheduled jobs stays readable in log files
+    pub fn new_headless() -> TUI {
+        TUI {
+            headless: true,
+            ..TUI::new()
         }
     }
 
     //Shows and handles the main menu
     pub fn show_main_menu(&mut self) -> MenuItem {
         self.write_title("Welcome to MagicQ Backuper");
-        self.show_menu(vec![MenuItem::Help, MenuItem::ChooseBackupSystem], MenuItem::Home)
+        self.show_menu(vec![MenuItem::Help, MenuItem::ChooseBackupSystem, MenuItem::ChooseSystemToShowBackups, MenuItem::ChooseRestoreSystem, MenuItem::ChooseSystemToPrune], MenuItem::Home)
     }
     //Shows some help about the program to the user and shows him a menu for more info or going back home
     pub fn show_help(&mut self) -> MenuItem {
@@ -49,11 +87,32 @@ impl TUI {
         self.writeln("The pc installations can be other softwares than MagicQ (like Capture or any other software containing information about your show)");
         self.writeln("The backup will be zipped in the destination");
         self.writeln("The destination location is most likely your local folder to google-drive or dropbox so your files get synced to the cloud automatically");
+        self.writeln("dest can also be a list of folders, like one in google-drive and one on a USB stick. The backup is copied to all of them, and a failing copy doesn't stop the others");
+        self.writeln("dest can be a folder, an {\"type\": \"sftp\", ...} / {\"type\": \"s3\", ...} object for an SFTP server or an S3 compatible bucket, or a list of them, see the config example. Passwords and keys are read from the environment variables named there. Backups are made in a temporary folder and then copied to the dests, and new ones build on the latest backup in the first dest");
         self.writeln("");
         self.writeln(format!("Note that you need to specify a {} file to the location where this program runs. In this file you specify all the systems that are on this computer or in the network of this computer", CONFIG_FILE_NAME));
         self.writeln("If you are unfamiliar with json file format consider downloading notepad++ to edit the file as it has code highlighting for json files");
         self.writeln("");
         self.writeln("To access any console you need to set the src like \\\\\\\\192.168.0.235\\\\mangicq. Note that your pc must already have been connected to the location because of the username and password");
+        self.writeln("");
+        self.writeln("excluded_files work like a .gitignore file relative to rel_path:");
+        self.writeln(" - *.sbk or autosave_* match files with this name in any folder");
+        self.writeln(" - temp/** or show\\\\temp\\\\** match everything below this folder");
+        self.writeln(" - cache/ matches folders only");
+        self.writeln(" - !important.sbk takes back an earlier pattern. The last matching pattern wins");
+        self.writeln("included_files use the same patterns. If set, only matching files are backed up before excluded_files are applied");
+        self.writeln("excluded_dirs use the same patterns for folders that should not be walked at all. max_depth limits how many folder levels below rel_path are walked");
+        self.writeln("max_file_size and min_file_size (like 500MB or 2GB) as well as modified_within and modified_before (like 30m, 12h, 7d or 2w) skip files by size and age");
+        self.writeln("archive_format sets how backups are written: \"zip\" (default), \"tar.gz\", \"tar.zst\" or \"directory\" for a plain copy of the files in a folder");
+        self.writeln("compression sets the method (\"stored\", \"deflate\", \"bzip2\" or \"zstd\") and level of a system or of a single backup_rel_path. With \"store_compressed\": true images, videos and archives are stored as they are");
+        self.writeln("encryption encrypts zip backups with AES-256. The passphrase comes from the environment variable in passphrase_env, the first line of key_file or, with \"prompt\": true, is asked for in the menu");
+        self.writeln("Encryption only protects the content of the files. Their names, sizes and modification times, the SHA-256 of every file, the src and the computer and user that made the backup stay readable without the passphrase, in every dest the backup is copied to");
+        self.writeln("With \"repository\": true backups are stored as snapshots in dest/repository, where every file content is stored only once, and snapshots can be exported as zips");
+        self.writeln("With \"incremental\": true a backup only contains the files changed since the previous one, and after max_deltas (default 6) incremental backups a full backup is made again");
+        self.writeln("\"workers\": 3 next to systems backs up up to 3 systems at once when all systems are backed up, which helps when most of the time is spent waiting for consoles in the network. Systems sharing a dest are still backed up one after another");
+        self.writeln("");
+        self.writeln("The program can also run without this menu, e.g. for scheduled tasks:");
+        self.writeln(USAGE);
 
         self.show_menu(vec![MenuItem::ShowConfigExample, MenuItem::ShowConfigLocation, MenuItem::CreateConfigExample], MenuItem::Help)
     }
@@ -88,15 +147,282 @@ impl TUI {
     //Shows a list of available systems to the user and lets him choose what system (or all) he wants to backup.
     pub fn show_choose_system_to_backup(&mut self) -> MenuItem {
         self.write_title("Choose system to backup");
+        match self.load_valid_items_with_warnings() {
+            Some(ValidConsolesAndLocalInstallations { systems, workers, .. }) => {
+                let mut menu = vec![MenuItem::BackupAllSystems(systems.clone(), workers)];
+
+                for local_installation in systems.iter() {
+                    menu.push(MenuItem::BackupLocalInstallation(local_installation.clone()));
+                }
+                for local_installation in systems.into_iter() {
+                    menu.push(MenuItem::PreviewBackup(local_installation));
+                }
+                self.show_menu(menu, MenuItem::ChooseBackupSystem)
+            }
+            None => MenuItem::Home
+        }
+    }
+
+    //Shows which files a backup of the system would contain without creating it and offers to export this plan
+    pub fn preview_backup(&mut self, local_installation: LocalInstallation) -> MenuItem {
+        self.write_title(format!("Preview backup of {}", local_installation.name));
+        let plan = match local_installation.plan_backup(self) {
+            Ok(plan) => plan,
+            Err(err) => return self.show_and_confirm_error(err.texts(), MenuItem::ChooseBackupSystem, true)
+        };
+        self.writeln("");
+        for text in plan.texts().into_iter() {
+            self.writeln(text);
+        }
+        let export = PathBuf::from(format!("{}_backup_plan.json", local_installation.name));
+        if !self.ask_yes_no(format!("Export this plan to {}?", export.display())) {
+            return MenuItem::ChooseBackupSystem;
+        }
+        match export_plans(&[plan], &export) {
+            Ok(()) => self.show_and_confirm_success(vec![format!("Exported the plan to {}", export.display())], MenuItem::ChooseBackupSystem),
+            Err(err) => self.show_and_confirm_error(err.texts(), MenuItem::ChooseBackupSystem, false)
+        }
+    }
+
+    //Shows a list of available systems to the user and lets him choose the system he wants to restore a backup for
+    pub fn show_choose_system_to_restore(&mut self) -> MenuItem {
+        self.write_title("Choose system to restore");
+        match self.load_systems_with_warnings() {
+            Some(systems) => {
+                let menu = systems.into_iter().map(MenuItem::ChooseRestoreArchive).collect();
+                self.show_menu(menu, MenuItem::ChooseRestoreSystem)
+            }
+            None => MenuItem::Home
+        }
+    }
+
+    //Shows all backups of a system and lets the user choose the one to restore
+    pub fn show_choose_archive_to_restore(&mut self, local_installation: LocalInstallation) -> MenuItem {
+        self.write_title(format!("Choose backup of {} to restore", local_installation.name));
+        let (backups, errors) = local_installation.all_backups();
+        for error in errors.iter() {
+            self.write_errorln(error);
+        }
+        if backups.is_empty() {
+            return self.show_and_confirm_error(vec![format!("No backups of {} found", local_installation.name)], MenuItem::ChooseRestoreSystem, false);
+        }
+        let menu = backups.into_iter().map(|backup| MenuItem::RestoreBackup(local_installation.clone(), backup)).collect();
+        self.show_menu(menu, MenuItem::ChooseRestoreSystem)
+    }
+
+    //Lets the user choose the systems to show the backups of
+    pub fn show_choose_system_to_show_backups(&mut self) -> MenuItem {
+        self.write_title("Choose system to show backups");
+        match self.load_systems_with_warnings() {
+            Some(systems) => {
+                let mut menu = vec![MenuItem::ShowAllBackups(systems.clone())];
+                for local_installation in systems.into_iter() {
+                    menu.push(MenuItem::ShowBackups(local_installation));
+                }
+                self.show_menu(menu, MenuItem::ChooseSystemToShowBackups)
+            }
+            None => MenuItem::Home
+        }
+    }
+
+    //Shows the backups of the given systems newest first and lets the user choose one to open
+    pub fn show_backups(&mut self, local_installations: Vec<LocalInstallation>) -> MenuItem {
+        self.write_title("Backups");
+        self.writeln("Scanning backups. Please wait...");
+        let mut backups = Vec::new();
+        let mut errors = Vec::new();
+        for local_installation in local_installations.into_iter() {
+            let (entries, catalog_errors) = local_installation.all_backups();
+            for entry in entries.into_iter() {
+                backups.push((local_installation.clone(), entry));
+            }
+            errors.extend(catalog_errors);
+            if let Ok(partial_zips) = local_installation.partial_backups() {
+                for partial_zip in partial_zips.iter() {
+                    self.write_warnln(format!("{} is left over from an interrupted backup", partial_zip.display()));
+                }
+            }
+        }
+        backups.sort_by_key(|b| Reverse(b.1.created));
+        for error in errors.iter() {
+            self.write_errorln(error);
+        }
+        if backups.is_empty() {
+            return self.show_and_confirm_error(vec!["No backups found"], MenuItem::ChooseSystemToShowBackups, false);
+        }
+        let menu = backups.into_iter().map(|(local_installation, entry)| MenuItem::ShowBackup(local_installation, entry)).collect();
+        self.show_menu(menu, MenuItem::ChooseSystemToShowBackups)
+    }
+
+    //Lists the content of a backup and lets the user restore it. A backup in a remote storage is downloaded first
+    pub fn show_backup(&mut self, local_installation: LocalInstallation, entry: BackupEntry) -> MenuItem {
+        self.write_title(format!("Backup of {}", local_installation.name));
+        self.writeln(entry.location());
+        self.writeln(entry.summary());
+        let entry = match local_installation.fetch(self, &entry) {
+            //The downloaded backup is used from here on, so restoring it doesn't download it again
+            Ok(path) => BackupEntry { path, stored: None, ..entry },
+            Err(err) => return self.show_and_confirm_error(err.texts(), MenuItem::ChooseSystemToShowBackups, false),
+        };
+        if let Ok(Some(manifest)) = Manifest::read_from_backup(&entry.path) {
+            self.writeln(manifest.origin());
+            if let Some(delta_description) = manifest.delta_description() {
+                self.writeln(delta_description);
+            }
+        }
+        self.writeln("");
+        match list_contents(&entry.path) {
+            Ok(contents) => {
+                for content in contents.iter() {
+                    if content.is_dir {
+                        self.writeln(&content.name);
+                    } else {
+                        self.writeln(format!("{}  ({})", content.name, format_size(content.size)));
+                    }
+                }
+            }
+            Err(err) => {
+                return self.show_and_confirm_error(err.texts(), MenuItem::ChooseSystemToShowBackups, false);
+            }
+        }
+        let mut menu = vec![MenuItem::VerifyBackup(local_installation.clone(), entry.path.clone())];
+        if is_snapshot(&entry.path) {
+            menu.push(MenuItem::ExportBackup(entry.path.clone()));
+        }
+        menu.push(MenuItem::RestoreBackup(local_installation, entry));
+        self.show_menu(menu, MenuItem::ChooseSystemToShowBackups)
+    }
+
+    //Checks if every file of a backup can still be read and matches the manifest
+    pub fn verify_backup(&mut self, local_installation: LocalInstallation, archive: PathBuf) -> MenuItem {
+        self.write_title("Verify backup");
+        self.writeln(format!("Verifying {}\n", archive.display()));
+        match local_installation.verify(self, &archive) {
+            Ok(report) => {
+                if report.is_ok() {
+                    self.show_and_confirm_success(report.texts(), MenuItem::ChooseSystemToShowBackups)
+                } else {
+                    self.show_and_confirm_error(report.texts(), MenuItem::ChooseSystemToShowBackups, false)
+                }
+            }
+            Err(err) => self.show_and_confirm_error(err.texts(), MenuItem::ChooseSystemToShowBackups, false)
+        }
+    }
+
+    //Writes a snapshot of a repository into a zip in the current folder
+    pub fn export_backup(&mut self, snapshot: PathBuf) -> MenuItem {
+        self.write_title("Export snapshot");
+        let dest_zip = PathBuf::from(snapshot.with_extension("zip").file_name().unwrap_or_default());
+        match export_to_zip(self, &snapshot, &dest_zip) {
+            Ok(()) => {
+                let dest_zip = std::env::current_dir().map(|dir| dir.join(&dest_zip)).unwrap_or(dest_zip);
+                self.show_and_confirm_success(vec![format!("Exported {} to {}", snapshot.display(), dest_zip.display())], MenuItem::ChooseSystemToShowBackups)
+            }
+            Err(err) => self.show_and_confirm_error(err.texts(), MenuItem::ChooseSystemToShowBackups, false)
+        }
+    }
+
+    //Lets the user choose the systems to prune the old backups of
+    pub fn show_choose_system_to_prune(&mut self) -> MenuItem {
+        self.write_title("Choose system to prune");
+        match self.load_systems_with_warnings() {
+            Some(systems) => {
+                let mut menu = vec![MenuItem::PruneAllBackups(systems.clone())];
+                for local_installation in systems.into_iter() {
+                    menu.push(MenuItem::PruneBackups(local_installation));
+                }
+                self.show_menu(menu, MenuItem::ChooseSystemToPrune)
+            }
+            None => MenuItem::Home
+        }
+    }
+
+    //Shows which backups the retention rules would delete and deletes them after the user confirmed
+    pub fn prune_backups(&mut self, local_installations: Vec<LocalInstallation>) -> MenuItem {
+        self.write_title("Prune old backups");
+        let mut errors = Vec::new();
+        for local_installation in local_installations.iter() {
+            self.writeln(format!("{}:", local_installation.name));
+            match local_installation.prune(self, true) {
+                Ok(message) => self.writeln(message),
+                Err(err) => errors.push(format!("Could not prune {}: {}", local_installation.name, err.to_string().trim())),
+            }
+        }
+        if !errors.is_empty() {
+            return self.show_and_confirm_error(errors, MenuItem::ChooseSystemToPrune, false);
+        }
+        if !self.ask_yes_no("Delete the backups listed above?") {
+            return MenuItem::ChooseSystemToPrune;
+        }
+        let mut successes = Vec::new();
+        for local_installation in local_installations.iter() {
+            match local_installation.prune(self, false) {
+                Ok(message) => successes.push(format!("{}:\n{}", local_installation.name, message)),
+                Err(err) => errors.push(format!("Could not prune {}: {}", local_installation.name, err.to_string().trim())),
+            }
+        }
+        if !errors.is_empty() {
+            let _ = self.show_and_confirm_error(errors, MenuItem::ChooseSystemToPrune, true);
+        }
+        self.show_and_confirm_success(successes, MenuItem::ChooseSystemToPrune)
+    }
+
+    //Shows what a restore would overwrite, asks the user for confirmation and restores the backup
+    pub fn restore_backup(&mut self, local_installation: LocalInstallation, entry: BackupEntry) -> MenuItem {
+        self.write_title(format!("Restore {}", local_installation.name));
+        let archive = match local_installation.fetch(self, &entry) {
+            Ok(archive) => archive,
+            Err(err) => return self.show_and_confirm_error(err.texts(), MenuItem::ChooseRestoreSystem, true)
+        };
+        let preview = match local_installation.preview_restore(&archive) {
+            Ok(preview) => preview,
+            Err(err) => return self.show_and_confirm_error(err.texts(), MenuItem::ChooseRestoreSystem, true)
+        };
+        self.writeln(format!("Restoring {} to {}", archive.display(), local_installation.src()));
+        self.writeln(format!("{} files will be created", preview.new_files.len()));
+        if !preview.overwritten_files.is_empty() {
+            self.write_warnln(format!("{} existing files will be overwritten:", preview.overwritten_files.len()));
+            for file in preview.overwritten_files.iter() {
+                self.write_warnln(format!("  {}", file.display()));
+            }
+        }
+        if !preview.refused_entries.is_empty() {
+            self.write_errorln(format!("{} entries point outside of {} and will not be restored:", preview.refused_entries.len(), local_installation.src()));
+            for entry in preview.refused_entries.iter() {
+                self.write_errorln(format!("  {}", entry));
+            }
+        }
+        let mut options = RestoreOptions::default();
+        if !preview.overwritten_files.is_empty() {
+            options.move_aside = self.ask_yes_no("Move the existing files aside before overwriting them?");
+        }
+        if !self.ask_yes_no("Restore now?") {
+            return MenuItem::ChooseRestoreSystem;
+        }
+        match local_installation.restore(self, &archive, options) {
+            Ok(success_message) => self.show_and_confirm_success(vec![success_message], MenuItem::Home),
+            Err(err) => self.show_and_confirm_error(err.texts(), MenuItem::ChooseRestoreSystem, true)
+        }
+    }
+
+    //Loads the valid systems of the config file and lets the user confirm the warnings about invalid ones
+    //If there is no valid system, the error is shown and None is returned
+    fn load_systems_with_warnings(&mut self) -> Option<Vec<LocalInstallation>> {
+        self.load_valid_items_with_warnings().map(|valid_items| valid_items.systems)
+    }
+    //Like load_systems_with_warnings, with the settings of the config file that apply to all systems
+    fn load_valid_items_with_warnings(&mut self) -> Option<ValidConsolesAndLocalInstallations> {
         self.writeln("Calculating systems. Please wait...");
         match load_validated_consoles_and_local_installations() {
-            Ok(valid_items) => {
+            Ok(mut valid_items) => {
                 if valid_items.is_empty() {
-                    return self.show_and_confirm_error(vec![format!("No valid systems found for backup in {}", CONFIG_FILE_NAME), format!("Consider looking in the {} menu", MenuItem::Help.text()), "There may be error messages printed out in the console to help you find what you did wrong".to_string()], MenuItem::Home, false);
+                    self.show_and_confirm_error(vec![format!("No valid systems found in {}", CONFIG_FILE_NAME), format!("Consider looking in the {} menu", MenuItem::Help.text()), "There may be error messages printed out in the console to help you find what you did wrong".to_string()], MenuItem::Home, false);
+                    return None;
                 }
-                if !valid_items.warnings.is_empty() {
+                if !valid_items.warnings.is_empty() || !valid_items.notices.is_empty() {
                     let mut w = Vec::new();
-                    for e in valid_items.warnings.into_iter() {
+                    let notices = std::mem::take(&mut valid_items.notices);
+                    for e in notices.into_iter().chain(std::mem::take(&mut valid_items.warnings)) {
                         for e in e.texts().into_iter() {
                             w.push(e);
                         }
@@ -104,26 +430,21 @@ impl TUI {
                     }
                     self.show_and_confirm_warning(w);
                 }
-
-                let mut menu = vec![MenuItem::BackupAllSystems(valid_items.systems.clone())];
-
-                for local_installation in valid_items.systems.into_iter() {
-                    menu.push(MenuItem::BackupLocalInstallation(local_installation));
-                }
-                self.show_menu(menu, MenuItem::ChooseBackupSystem)
+                Some(valid_items)
             }
             Err(err) => {
-                self.show_and_confirm_error(err.texts(), MenuItem::Home, true)
+                self.show_and_confirm_error(err.texts(), MenuItem::Home, true);
+                None
             }
         }
     }
 
     //Clears the console and then writes a title with separator lines in a constant styling
     pub fn write_title<S: AsRef<str>>(&mut self, text: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(Clear(ClearType::Purge));
-        let _ = self.stdout.execute(SetAttribute(Attribute::Bold));
-        let _ = self.stdout.execute(SetForegroundColor(Color::Blue));
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(Clear(ClearType::Purge));
+        self.style(SetAttribute(Attribute::Bold));
+        self.style(SetForegroundColor(Color::Blue));
         let _ = self.stdout.flush();
         let _ = self.stdout.write(EMPTY_LINE);
         let _ = self.stdout.write(SEPARATOR_LINE);
@@ -132,66 +453,119 @@ impl TUI {
         let _ = self.stdout.write(SEPARATOR_LINE);
         let _ = self.stdout.write(SEPARATOR_LINE);
         let _ = self.stdout.write(EMPTY_LINE);
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
+        self.style(SetAttribute(Attribute::Reset));
     }
 
     //Simply writes a line in standard style and color to the command outpout
     pub fn writeln<S: AsRef<str>>(&mut self, text: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(ResetColor);
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(ResetColor);
         let _ = self.stdout.flush();
         let _ = self.stdout.write(format!("{}\n", text.as_ref()).as_bytes());
     }
 
     //Writes a line in red to the command outpout
     pub fn write_errorln<S: AsRef<str>>(&mut self, text: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(SetForegroundColor(Color::Red));
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(SetForegroundColor(Color::Red));
         let _ = self.stdout.flush();
         let _ = self.stdout.write(format!("{}\n", text.as_ref()).as_bytes());
     }
     //Writes a line in red to the command outpout
     pub fn write_success<S: AsRef<str>>(&mut self, text: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(SetForegroundColor(Color::Green));
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(SetForegroundColor(Color::Green));
         let _ = self.stdout.flush();
         let _ = self.stdout.write(text.as_ref().as_bytes());
     }
     //Writes a line in green to the command outpout
     pub fn write_successln<S: AsRef<str>>(&mut self, text: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(SetForegroundColor(Color::Green));
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(SetForegroundColor(Color::Green));
         let _ = self.stdout.flush();
         let _ = self.stdout.write(format!("{}\n", text.as_ref()).as_bytes());
     }
     //Writes a line in yellow to the command outpout
     pub fn write_warnln<S: AsRef<str>>(&mut self, text: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(SetForegroundColor(Color::DarkYellow));
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(SetForegroundColor(Color::DarkYellow));
         let _ = self.stdout.flush();
         let _ = self.stdout.write(format!("{}\n", text.as_ref()).as_bytes());
     }
     //Writes the current task withouth styling but in a way that the next line wil override it again.
+    //In headless mode every task gets its own line, as log files can't override lines
     pub fn update_current_task<S: AsRef<str>>(&mut self, task: S) {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
+        if self.headless {
+            self.writeln(task);
+            return;
+        }
+        self.style(SetAttribute(Attribute::Reset));
         let _ = self.stdout.flush();
         print!("\r{}", task.as_ref());
         let _ = self.stdout.flush();
     }
 
+    //Draws the progress bar with the current task over the current line, at most every PROGRESS_REDRAW_INTERVAL unless forced
+    //In headless mode a line is written for every HEADLESS_PROGRESS_STEP_PERCENT instead
+    fn draw_progress(&mut self, force: bool) {
+        let meter = match self.meter.as_ref() {
+            Some(meter) => meter,
+            None => return,
+        };
+        if self.headless {
+            //100% is only written once the last file is done, which forces it
+            let step = meter.percent() / HEADLESS_PROGRESS_STEP_PERCENT;
+            if force || (step > self.progress_step && meter.percent() < 100) {
+                self.progress_step = step;
+                let line = meter.describe();
+                self.writeln(line);
+            }
+            return;
+        }
+        if !force && self.progress_drawn.map(|drawn| drawn.elapsed() < PROGRESS_REDRAW_INTERVAL).unwrap_or(false) {
+            return;
+        }
+        self.progress_drawn = Some(Instant::now());
+        //A line longer than the console would wrap and could not be overridden anymore
+        let width = crossterm::terminal::size().ok().map(|(columns, _)| columns as usize).filter(|columns| *columns > 0).unwrap_or(80);
+        let line: String = format!("{}  {}", meter.describe(), self.current_task).chars().take(width.saturating_sub(1)).collect();
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(Clear(ClearType::CurrentLine));
+        print!("\r{}", line);
+        let _ = self.stdout.flush();
+    }
+
+    //Removes the progress bar from the current line, so a message can be written there instead. Returns if there was one to draw again below the message
+    fn clear_progress(&mut self) -> bool {
+        if self.meter.is_none() || self.headless {
+            return false;
+        }
+        self.style(Clear(ClearType::CurrentLine));
+        print!("\r");
+        let _ = self.stdout.flush();
+        true
+    }
+
+    //Executes a styling or cursor command on the console. Skipped in headless mode
+    fn style<C: Command>(&mut self, command: C) {
+        if !self.headless {
+            let _ = self.stdout.execute(command);
+        }
+    }
+
     //Shows any generic menu. The current_item will be reused in case there is an invalid input
     fn show_menu(&mut self, mut menu_items: Vec<MenuItem>, current_item: MenuItem) -> MenuItem {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(ResetColor);
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(ResetColor);
         let _ = self.stdout.flush();
         let _ = self.stdout.write("\n".as_bytes());
-        let _ = self.stdout.execute(SetAttribute(Attribute::Underlined));
+        self.style(SetAttribute(Attribute::Underlined));
         let _ = self.stdout.flush();
         let _ = self.stdout.write("Menu Options\n".as_bytes());
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
+        self.style(SetAttribute(Attribute::Reset));
         let _ = self.stdout.flush();
         let _ = self.stdout.write("\n".as_bytes());
-        let _ = self.stdout.execute(SetAttribute(Attribute::Italic));
+        self.style(SetAttribute(Attribute::Italic));
         let _ = self.stdout.flush();
         for (index, menu_item) in menu_items.iter().enumerate() {
             match menu_item {
@@ -213,14 +587,15 @@ impl TUI {
         }
 
         let _ = self.stdout.write(format!("{}) {}\n\n", exit_program_index, MenuItem::ExitProgram().text()).as_bytes());
-        let _ = self.stdout.execute(ResetColor);
+        self.style(ResetColor);
 
         let _ = self.stdout.write("Waiting for user input...".as_bytes());
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
+        self.style(SetAttribute(Attribute::Reset));
         let mut input = String::new();
         self.stdin.read_line(&mut input).expect("Unexpected program error");
         let mut input = input.trim().to_string().parse().unwrap_or(usize::MAX);
         if input == exit_program_index {
+            remove_download_cache();
             std::process::exit(0);
         }
         match current_item {
@@ -231,9 +606,7 @@ impl TUI {
                 }
             }
         }
-        if input > 0 {
-            input -= 1;
-        }
+        input = input.saturating_sub(1);
         match menu_items.get(input) {
             None => self.show_and_confirm_error(vec!["Invalid input"], current_item, true),
             Some(_) => menu_items.remove(input)
@@ -245,7 +618,7 @@ impl TUI {
         let _ = self.stdout.write(EMPTY_LINE);
 
         for text in texts.iter() {
-            let _ = self.write_successln(text);
+            self.write_successln(text);
         }
         self.wait_for_any_key(menu_item)
     }
@@ -253,22 +626,22 @@ impl TUI {
     //Shows a promenent error message to the screen and waits for the user to press any key until it returns the menu-item you want to
     pub fn show_and_confirm_error<S: AsRef<str>>(&mut self, texts: Vec<S>, menu_item: MenuItem, clear_console_before_print: bool) -> MenuItem {
         if clear_console_before_print {
-            let _ = self.stdout.execute(Clear(ClearType::All));
-            let _ = self.stdout.execute(MoveTo(0, 0));
+            self.style(Clear(ClearType::All));
+            self.style(MoveTo(0, 0));
         }
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
-        let _ = self.stdout.execute(SetAttribute(Attribute::Bold));
-        let _ = self.stdout.execute(SetForegroundColor(Color::Red));
+        self.style(SetAttribute(Attribute::Reset));
+        self.style(SetAttribute(Attribute::Bold));
+        self.style(SetForegroundColor(Color::Red));
         let _ = self.stdout.write(SEPARATOR_LINE);
         let _ = self.stdout.write(SEPARATOR_LINE);
         let _ = self.stdout.write("   Error\n".to_uppercase().as_bytes());
         let _ = self.stdout.write(SEPARATOR_LINE);
         let _ = self.stdout.write(SEPARATOR_LINE);
         let _ = self.stdout.write(EMPTY_LINE);
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
+        self.style(SetAttribute(Attribute::Reset));
 
         for text in texts.iter() {
-            let _ = self.write_errorln(text);
+            self.write_errorln(text);
         }
         self.wait_for_any_key(menu_item)
     }
@@ -276,14 +649,33 @@ impl TUI {
     //Shows a list of warnings and wait for user to press enter before continuing
     pub fn show_and_confirm_warning<S: AsRef<str>>(&mut self, texts: Vec<S>) {
         for text in texts.iter() {
-            let _ = self.write_warnln(text);
+            self.write_warnln(text);
         }
         let _ = self.wait_for_any_key(MenuItem::Home);
     }
 
+    //Asks the user a yes/no question. Anything else than y or yes counts as no
+    pub fn ask_yes_no<S: AsRef<str>>(&mut self, question: S) -> bool {
+        self.style(SetAttribute(Attribute::Reset));
+        let _ = self.stdout.write(format!("{} (y/n) ", question.as_ref()).as_bytes());
+        let _ = self.stdout.flush();
+        let mut input = String::new();
+        let _ = self.stdin.read_line(&mut input);
+        matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+    }
+
+    //Asks for a passphrase without showing what is typed. Returns None in the headless mode, as nobody is there to answer
+    pub fn ask_passphrase<S: AsRef<str>>(&mut self, question: S) -> Option<String> {
+        if self.headless {
+            return None;
+        }
+        self.style(SetAttribute(Attribute::Reset));
+        rpassword::prompt_password(question.as_ref()).ok()
+    }
+
     //Prints Press any key to continue and passes the menu_item provided back when the user enters any key
     fn wait_for_any_key(&mut self, menu_item: MenuItem) -> MenuItem {
-        let _ = self.stdout.execute(SetAttribute(Attribute::Reset));
+        self.style(SetAttribute(Attribute::Reset));
         let _ = self.stdout.write(EMPTY_LINE);
         self.writeln("Press enter to continue...");
         let mut buf = String::new();
@@ -292,6 +684,79 @@ impl TUI {
     }
 }
 
+//The backups report their progress to the user through the TUI
+impl Progress for TUI {
+    fn title(&mut self, title: &str) {
+        self.meter = None;
+        self.write_title(title);
+    }
+    fn info(&mut self, message: &str) {
+        let cleared = self.clear_progress();
+        self.writeln(message);
+        if cleared {
+            self.draw_progress(true);
+        }
+    }
+    fn warn(&mut self, message: &str) {
+        let cleared = self.clear_progress();
+        self.write_warnln(message);
+        if cleared {
+            self.draw_progress(true);
+        }
+    }
+    //While there is a progress bar the task is shown behind it. The headless mode writes every task anyway
+    fn task(&mut self, task: &str) {
+        if self.meter.is_some() && !self.headless {
+            self.current_task = task.to_string();
+            self.draw_progress(false);
+        } else {
+            self.update_current_task(task);
+        }
+    }
+    fn scanned(&mut self, system: &str, total: Counts) {
+        if self.headless {
+            self.writeln(format!("Writing {} files of {} with {}", total.files, system, format_size(total.bytes)));
+        }
+        //Several systems backed up at once share one meter, which grows with every system that starts writing
+        if let Some(meter) = self.meter.as_mut().filter(|meter| meter.system == system) {
+            meter.rescan(total);
+            self.draw_progress(true);
+            return;
+        }
+        if total.files == 0 {
+            return;
+        }
+        self.meter = Some(Meter::new(system, total));
+        self.current_task = String::new();
+        self.progress_step = 0;
+        self.draw_progress(true);
+    }
+    //The finished bar stays on the console, the tasks after it get their own line again
+    fn copied(&mut self, done: Counts) {
+        let finished = match self.meter.as_mut() {
+            Some(meter) => {
+                meter.update(done);
+                meter.is_done()
+            }
+            None => return,
+        };
+        if finished {
+            self.current_task = String::new();
+            self.draw_progress(true);
+            if !self.headless {
+                self.writeln("");
+            }
+            self.meter = None;
+        } else {
+            self.draw_progress(false);
+        }
+    }
+    fn ask_passphrase(&mut self, question: &str) -> Option<String> {
+        self.clear_progress();
+        TUI::ask_passphrase(self, question)
+    }
+}
+
 pub enum MenuItem {
     Home,
     Help,
@@ -299,8 +764,22 @@ pub enum MenuItem {
     ShowConfigExample,
     CreateConfigExample,
     ChooseBackupSystem,
-    BackupAllSystems(Vec<LocalInstallation>),
+    //The systems and how many of them are backed up at once
+    BackupAllSystems(Vec<LocalInstallation>, usize),
     BackupLocalInstallation(LocalInstallation),
+    PreviewBackup(LocalInstallation),
+    ChooseSystemToShowBackups,
+    ShowAllBackups(Vec<LocalInstallation>),
+    ShowBackups(LocalInstallation),
+    ShowBackup(LocalInstallation, BackupEntry),
+    VerifyBackup(LocalInstallation, PathBuf),
+    ExportBackup(PathBuf),
+    ChooseSystemToPrune,
+    PruneAllBackups(Vec<LocalInstallation>),
+    PruneBackups(LocalInstallation),
+    ChooseRestoreSystem,
+    ChooseRestoreArchive(LocalInstallation),
+    RestoreBackup(LocalInstallation, BackupEntry),
     ExitProgram(),
 }
 
@@ -312,8 +791,21 @@ impl MenuItem {
             MenuItem::ShowConfigLocation => format!("Where should this {} be located?", CONFIG_FILE_NAME),
             MenuItem::CreateConfigExample => format!("Create {} with example data for me", CONFIG_FILE_NAME),
             MenuItem::ChooseBackupSystem => "Backup one ore more systems".to_string(),
-            MenuItem::BackupAllSystems(_) => "All listed systems".to_string(),
+            MenuItem::BackupAllSystems(..) => "All listed systems".to_string(),
             MenuItem::BackupLocalInstallation(local_installation) => format!("Backup {}", local_installation.name),
+            MenuItem::PreviewBackup(local_installation) => format!("Preview backup of {} (dry run)", local_installation.name),
+            MenuItem::ChooseSystemToShowBackups => "Show backups".to_string(),
+            MenuItem::ShowAllBackups(_) => "Backups of all listed systems".to_string(),
+            MenuItem::ShowBackups(local_installation) => format!("Backups of {}", local_installation.name),
+            MenuItem::ShowBackup(_, entry) => entry.summary(),
+            MenuItem::VerifyBackup(_, _) => "Verify this backup".to_string(),
+            MenuItem::ExportBackup(_) => "Export this snapshot as zip".to_string(),
+            MenuItem::ChooseSystemToPrune => "Prune old backups".to_string(),
+            MenuItem::PruneAllBackups(_) => "Prune backups of all listed systems".to_string(),
+            MenuItem::PruneBackups(local_installation) => format!("Prune backups of {}", local_installation.name),
+            MenuItem::ChooseRestoreSystem => "Restore a backup".to_string(),
+            MenuItem::ChooseRestoreArchive(local_installation) => format!("Restore {}", local_installation.name),
+            MenuItem::RestoreBackup(_, entry) => format!("Restore {}", entry.location()),
             MenuItem::ExitProgram() => "End program".to_string(),
             MenuItem::ShowConfigExample => format!("Show example of {}", CONFIG_FILE_NAME)
         }
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::archive::read_entries;
+use crate::error::Error;
+use crate::manifest::{hash_file, is_reserved_entry, LEGACY_MANIFEST_NAME, Manifest, MANIFEST_NAME};
+use crate::progress::Progress;
+use crate::repository::{is_snapshot, read_snapshot, Repository};
+
+//Result of checking a backup archive
+pub struct VerifyReport {
+    pub archive: PathBuf,
+    pub checked_files: usize,
+    pub has_manifest: bool,
+    //Files listed in the manifest but not found in the archive
+    pub missing: Vec<String>,
+    //Files in the archive that are not listed in the manifest
+    pub extra: Vec<String>,
+    //Files that can't be decompressed, fail the CRC check or don't match the manifest, with the reason
+    pub corrupted: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.corrupted.is_empty()
+    }
+
+    //Human readable description of the result
+    pub fn texts(&self) -> Vec<String> {
+        let mut texts = Vec::new();
+        if self.is_ok() {
+            texts.push(format!("{} is ok, {} files checked", self.archive.display(), self.checked_files));
+        } else {
+            texts.push(format!("{} is damaged, {} files checked", self.archive.display(), self.checked_files));
+        }
+        if !self.has_manifest {
+            texts.push("  The backup has no manifest, only the CRC of the files could be checked".to_string());
+        }
+        for missing in self.missing.iter() {
+            texts.push(format!("  Missing: {}", missing));
+        }
+        for extra in self.extra.iter() {
+            texts.push(format!("  Extra: {}", extra));
+        }
+        for corrupted in self.corrupted.iter() {
+            texts.push(format!("  Corrupted: {}", corrupted));
+        }
+        texts
+    }
+}
+
+//Verifies a backup in any archive format or a snapshot in a repository, see verify_zip and verify_snapshot
+//An incremental backup is also checked for its base, as it is useless without the backup it builds on
+pub fn verify_backup(progress: &mut dyn Progress, backup: &Path, passphrase: Option<&str>) -> Result<VerifyReport, Error> {
+    if is_snapshot(backup) {
+        return verify_snapshot(progress, backup);
+    }
+    let mut report = verify_zip(progress, backup, passphrase)?;
+    if let Some(base) = Manifest::read_from_backup(backup).ok().flatten().and_then(|manifest| manifest.base) {
+        if !backup.with_file_name(&base).exists() {
+            report.missing.insert(0, format!("{} (base of this incremental backup)", base));
+        }
+    }
+    Ok(report)
+}
+
+//Decompresses every blob a snapshot uses and checks if its size and SHA-256 still match
+pub fn verify_snapshot(progress: &mut dyn Progress, snapshot: &Path) -> Result<VerifyReport, Error> {
+    verify_snapshot_in(progress, snapshot, &Repository::of_snapshot(snapshot)?)
+}
+
+//Blobs that only the dest of a staged repository has were verified when they were stored there and are skipped
+fn verify_snapshot_in(progress: &mut dyn Progress, snapshot: &Path, repository: &Repository) -> Result<VerifyReport, Error> {
+    let manifest = read_snapshot(snapshot)?;
+    let mut report = VerifyReport {
+        archive: snapshot.to_path_buf(),
+        checked_files: 0,
+        has_manifest: true,
+        missing: Vec::new(),
+        extra: Vec::new(),
+        corrupted: Vec::new(),
+    };
+    for file in manifest.files.iter() {
+        if file.sha256.as_ref().map(|sha256| !repository.blob_path(sha256).exists() && repository.has_blob(sha256)).unwrap_or(false) {
+            continue;
+        }
+        progress.task(&format!("Verifying {}", file.path));
+        report.checked_files += 1;
+        let sha256 = match file.sha256.as_ref() {
+            Some(sha256) => sha256,
+            None => {
+                report.corrupted.push(format!("{} (no SHA-256 in the snapshot)", file.path));
+                continue;
+            }
+        };
+        let mut blob = match repository.open_blob(sha256) {
+            Ok(blob) => blob,
+            Err(_) => {
+                report.missing.push(format!("{} (blob {})", file.path, sha256));
+                continue;
+            }
+        };
+        let mut hasher = Sha256::new();
+        match std::io::copy(&mut blob, &mut hasher) {
+            Ok(size) if size != file.size => report.corrupted.push(format!("{} (size is {} instead of {})", file.path, size, file.size)),
+            Ok(_) if &format!("{:x}", hasher.finalize()) != sha256 => report.corrupted.push(format!("{} (SHA-256 of blob does not match)", file.path)),
+            Ok(_) => {}
+            Err(err) => report.corrupted.push(format!("{} ({})", file.path, err)),
+        }
+    }
+    progress.task(&format!("Verified {}", snapshot.display()));
+    Ok(report)
+}
+
+//Decompresses every entry of a backup archive, which checks the CRC of zips, and compares the files with the embedded manifest if there is one
+//Returns an error only if the archive does not exist, an archive that can't be read counts as corrupted
+//Encrypted files need the passphrase, without it they count as corrupted
+pub fn verify_zip(progress: &mut dyn Progress, archive: &Path, passphrase: Option<&str>) -> Result<VerifyReport, Error> {
+    if !archive.exists() {
+        return Err(Error::new_s(format!("{} does not exist", archive.display())));
+    }
+    let mut report = VerifyReport {
+        archive: archive.to_path_buf(),
+        checked_files: 0,
+        has_manifest: false,
+        missing: Vec::new(),
+        extra: Vec::new(),
+        corrupted: Vec::new(),
+    };
+    let manifest = match Manifest::read_from_backup(archive) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            report.corrupted.push(format!("{} ({})", MANIFEST_NAME, err.to_string().trim()));
+            None
+        }
+    };
+    report.has_manifest = manifest.is_some();
+    let mut expected = HashMap::new();
+    if let Some(manifest) = manifest {
+        for file in manifest.files.into_iter() {
+            expected.insert(file.path.clone(), file);
+        }
+    }
+
+    let read = read_entries(archive, passphrase, |entry| {
+        //The manifest of a backup of an earlier version is among the files, but not one of them
+        let is_legacy_manifest = entry.name == LEGACY_MANIFEST_NAME && report.has_manifest && !expected.contains_key(&entry.name);
+        if entry.is_dir || is_reserved_entry(&entry.name) || is_legacy_manifest {
+            return Ok(());
+        }
+        let name = entry.name;
+        progress.task(&format!("Verifying {}", name));
+        report.checked_files += 1;
+        //Reading the entry to the end makes the zip crate check the CRC
+        let mut hasher = Sha256::new();
+        let size = match std::io::copy(entry.content, &mut hasher) {
+            Ok(size) => size,
+            Err(err) => {
+                report.corrupted.push(format!("{} ({})", name, err));
+                expected.remove(&name);
+                return Ok(());
+            }
+        };
+        if !report.has_manifest {
+            return Ok(());
+        }
+        match expected.remove(&name) {
+            None => report.extra.push(name),
+            Some(file) => {
+                if file.size != size {
+                    report.corrupted.push(format!("{} (size is {} instead of {})", name, size, file.size));
+                } else if let Some(sha256) = file.sha256 {
+                    if sha256 != format!("{:x}", hasher.finalize()) {
+                        report.corrupted.push(format!("{} (SHA-256 does not match the manifest)", name));
+                    }
+                }
+            }
+        }
+        Ok(())
+    });
+    //A damaged archive can't be read any further, everything after the damage counts as missing
+    if let Err(err) = read {
+        report.corrupted.push(format!("archive after {} files ({})", report.checked_files, err.to_string().trim()));
+    }
+    let mut missing: Vec<String> = expected.into_keys().collect();
+    missing.sort();
+    report.missing.extend(missing);
+    progress.task(&format!("Verified {}", archive.display()));
+    Ok(report)
+}
+
+//Verifies a freshly written backup and additionally compares every file in it with the file in the source it was made from
+//The base of an incremental backup is not checked, as a new backup is made in a temporary folder and copied to its dests afterwards
+pub fn verify_against_source(progress: &mut dyn Progress, archive: &Path, src_root: &Path, passphrase: Option<&str>) -> Result<VerifyReport, Error> {
+    let report = verify_zip(progress, archive, passphrase)?;
+    compare_with_source(progress, report, archive, src_root)
+}
+
+//Like verify_against_source for a snapshot in a staged repository
+pub fn verify_snapshot_against_source(progress: &mut dyn Progress, snapshot: &Path, repository: &Repository, src_root: &Path) -> Result<VerifyReport, Error> {
+    let report = verify_snapshot_in(progress, snapshot, repository)?;
+    compare_with_source(progress, report, snapshot, src_root)
+}
+
+fn compare_with_source(progress: &mut dyn Progress, mut report: VerifyReport, archive: &Path, src_root: &Path) -> Result<VerifyReport, Error> {
+    let manifest = match Manifest::read_from_backup(archive)? {
+        Some(manifest) => manifest,
+        None => return Err(Error::new_s(format!("{} has no manifest to compare with the source", archive.display()))),
+    };
+    for file in manifest.files.iter() {
+        let source = src_root.join(&file.path);
+        progress.task(&format!("Comparing {} with the source", file.path));
+        match hash_file(&source) {
+            Ok(sha256) => {
+                if file.sha256.as_ref() != Some(&sha256) {
+                    report.corrupted.push(format!("{} (differs from {})", file.path, source.display()));
+                }
+            }
+            Err(err) => report.corrupted.push(format!("{} ({} can't be read: {})", file.path, source.display(), err.to_string().trim())),
+        }
+    }
+    Ok(report)
+}
@@ -1,156 +1,427 @@
-extern crate zip;
-
-use std::ffi::OsStr;
+use std::collections::{HashMap, HashSet};
 use std::fs::{create_dir_all, File};
 use std::io::prelude::*;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use zip::write::FileOptions;
+use serde::*;
+use sha2::{Digest, Sha256};
 
+use crate::archive::{ArchiveFormat, ArchiveWriter, FileAttributes, remove_backup};
+use crate::backup_plan::{BackupPlan, PlannedFile};
+use crate::catalog::format_size;
+use crate::compression::{Compression, FileCompression};
 use crate::error::Error;
+use crate::manifest::{Manifest, MANIFEST_NAME, ManifestFile, RESERVED_DIR};
+use crate::progress::{Counts, Progress};
 use crate::systems::BackupRelPath;
-use crate::tui::TUI;
-
-use self::zip::{CompressionMethod, ZipWriter};
-
-//Zips exactly one file from a src to a zip file while copying
-fn zip_one_file_entry(tui: &mut TUI, file: PathBuf, zip: &mut ZipWriter<File>, src_root: &str, options: FileOptions, buffer: &mut Vec<u8>) -> Result<(), Error> {
-    tui.update_current_task(format!("Zipping {}", file.display()));
-    let relative_name = file.strip_prefix(src_root)?.as_os_str().to_str();
-    match relative_name {
-        None => Err(Error::new_s("Unexpected error in path calculations")),
-        Some(relative_name) => {
-            zip.start_file(relative_name, options)?;
-            let mut f = File::open(file)?;
-
-            f.read_to_end(buffer)?;
-            zip.write_all(&*buffer)?;
-            buffer.clear();
-            Ok(())
-        }
-    }
+use crate::verify::verify_against_source;
+use crate::zip_name::get_partial_path;
+
+//One file or folder found in the source that goes into the zip
+pub struct ZipEntry {
+    pub path: PathBuf,
+    pub relative_name: String,
+    pub is_dir: bool,
+    //Compression of the backup_rel_path the entry was found in
+    pub compression: Compression,
 }
 
-//Adds a path to a zip file without content
-fn add_path_to_zip(tui: &mut TUI, file: &Path, zip: &mut ZipWriter<File>, src_root: &str, options: FileOptions) -> Result<(), Error> {
-    tui.update_current_task(format!("Adding path {} to zip", file.display()));
-    let relative_name = file.strip_prefix(src_root)?.as_os_str().to_str();
-    match relative_name {
-        None => Err(Error::new_s("Unexpected error in path calculations")),
-        Some(relative_name) => {
-            zip.add_directory(relative_name, options)?;
-            Ok(())
+impl ZipEntry {
+    fn new(path: PathBuf, src_root: &str, is_dir: bool, dir: &BackupRelPath) -> Result<ZipEntry, Error> {
+        let relative_name = path.strip_prefix(src_root)?.as_os_str().to_str();
+        match relative_name {
+            None => Err(Error::new_s("Unexpected error in path calculations")),
+            Some(relative_name) => Ok(ZipEntry {
+                relative_name: relative_name.to_string(),
+                path,
+                is_dir,
+                compression: dir.compression.clone().unwrap_or_default(),
+            }),
         }
     }
 }
 
-//Copies a set of user specified paths/files with specified rules about skipping some files or ignoring subdirs in a zip while compressing
-pub fn copy_to_zip<S: AsRef<str>>(tui: &mut TUI, src_root_absolute: S, dirs: Vec<BackupRelPath>, dest_zip: &Path) -> Result<(), Error> {
-    if dest_zip.exists() {
-        return Err(Error::new_s(format!("{} already exists!", dest_zip.display())));
-    }
-    if dest_zip.extension().and_then(OsStr::to_str).unwrap_or("?") != "zip" {
-        return Err(Error::new_s(format!("{} is not a zip file!", dest_zip.display())));
-    }
-    let src_root = Path::new(src_root_absolute.as_ref());
-    if !src_root.exists() {
-        return Err(Error::new_s(format!("{} does not exist", src_root_absolute.as_ref())));
-    }
-    let dest_parent = dest_zip.parent();
-    if dest_parent.is_none() {
-        return Err(Error::new_s(format!("{} is an invalid path", dest_zip.display())));
+//Compares the files of a backup with the files of the latest backup so no identical backup gets created
+pub struct UnchangedCheck {
+    pub latest_zip: PathBuf,
+    //All files of the latest backup, replayed through its chain if it is incremental
+    pub latest_files: Vec<ManifestFile>,
+}
+
+//The backup an incremental backup builds on
+pub struct DeltaBase {
+    pub base_zip: PathBuf,
+    //All files of the base, replayed through its chain if it is incremental itself
+    pub base_files: Vec<ManifestFile>,
+}
+
+//Settings of a system that change how copy_to_zip works
+pub struct CopySettings {
+    pub unchanged_check: Option<UnchangedCheck>,
+    //With a base only the files added or changed since the base and a list of the deleted ones go into the zip
+    pub delta_base: Option<DeltaBase>,
+    //Also compares the SHA-256 of every file to find changes, which means every file gets read once more
+    pub compare_hashes: bool,
+    //Reads the written zip back and compares it with the source before it gets its final name
+    pub verify_after_backup: bool,
+    //Size of the buffer files are streamed through, which is the most memory a single file uses while zipping
+    pub buffer_size: usize,
+    //Compression of the system, used for everything the backup_rel_paths don't set themselves
+    pub compression: Compression,
+    //Encrypts the content of all files with AES-256 and this passphrase
+    pub passphrase: Option<String>,
+}
+
+//Default for the buffer size of CopySettings
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+//Files from this size on report their progress while they are zipped
+const LARGE_FILE_SIZE: u64 = 16 * 1024 * 1024;
+//Progress of large files is reported in steps of this many percent
+const PROGRESS_STEP_PERCENT: u64 = 5;
+
+//What copy_to_zip did
+pub struct CopyResult {
+    //Set if nothing changed since this latest backup, so no zip was created
+    pub unchanged_since: Option<PathBuf>,
+    //Number of files compared with the source if verify_after_backup is set
+    pub verified_files: Option<usize>,
+    //Set if an incremental backup was created
+    pub delta: Option<DeltaSummary>,
+    pub skipped: Vec<SkippedFile>,
+    //Files and bytes read from the source into the backup
+    pub written: Counts,
+}
+
+//What went into an incremental backup
+pub struct DeltaSummary {
+    pub base_zip: PathBuf,
+    pub changed_files: usize,
+    pub deleted_files: usize,
+}
+
+//A file or folder in the source that is not backed up, with the reason why
+#[derive(Debug, Serialize)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+//Everything the walk through the source found
+pub struct CollectedEntries {
+    pub entries: Vec<ZipEntry>,
+    pub skipped: Vec<SkippedFile>,
+}
+
+impl CollectedEntries {
+    fn skip(&mut self, progress: &mut dyn Progress, path: PathBuf, reason: String) {
+        progress.task(&format!("Skipping {} ({})", path.display(), reason));
+        self.skipped.push(SkippedFile { path, reason });
     }
-    let dest_parent = dest_parent.unwrap();
-    if !dest_parent.exists() {
-        create_dir_all(dest_parent)?
+}
+
+//Hashes and counts everything read through it and reports the progress of the whole archive as well as of large files
+struct HashingReader<'a> {
+    progress: &'a mut dyn Progress,
+    //What is written of the archive so far, including what is read of this file
+    done: &'a mut Counts,
+    entry: &'a ZipEntry,
+    file: File,
+    hasher: Sha256,
+    total_size: u64,
+    read: u64,
+    reported_percent: u64,
+}
+
+impl<'a> Read for HashingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+        self.done.bytes += read as u64;
+        self.progress.copied(*self.done);
+        if self.total_size >= LARGE_FILE_SIZE {
+            let percent = self.read * 100 / self.total_size.max(1);
+            if percent >= self.reported_percent + PROGRESS_STEP_PERCENT {
+                self.reported_percent = percent;
+                self.progress.task(&format!("Zipping {} ({} of {}, {}%)", self.entry.path.display(), format_size(self.read), format_size(self.total_size), percent));
+            }
+        }
+        Ok(read)
     }
-    let zip_file = File::create(dest_zip)?;
-    let mut zip = zip::ZipWriter::new(zip_file);
+}
 
-    let options = FileOptions::default()
-        .compression_method(CompressionMethod::Deflated)
-        .unix_permissions(0o755);
+//Adds exactly one file from a src to an archive while copying. Returns the size and the SHA-256 of the added content
+//The file is streamed through the buffer, so no more than the buffer size is held in memory. Large files report their progress
+fn zip_one_file_entry(progress: &mut dyn Progress, done: &mut Counts, entry: &ZipEntry, archive: &mut dyn ArchiveWriter, compression: FileCompression, buffer: &mut [u8]) -> Result<(u64, String), Error> {
+    progress.task(&format!("Zipping {}", entry.path.display()));
+    let file = File::open(&entry.path)?;
+    let metadata = file.metadata()?;
+    let total_size = metadata.len();
+    let mut reader = HashingReader {
+        progress,
+        done,
+        entry,
+        file,
+        hasher: Sha256::new(),
+        total_size,
+        read: 0,
+        reported_percent: 0,
+    };
+    archive.add_file(&entry.relative_name, total_size, FileAttributes::of(&metadata), compression, &mut reader, buffer)?;
+    reader.done.files += 1;
+    reader.progress.copied(*reader.done);
+    Ok((reader.read, format!("{:x}", reader.hasher.finalize())))
+}
 
-    let mut buffer = Vec::new();
+//Adds a path to an archive without content
+fn add_path_to_zip(progress: &mut dyn Progress, entry: &ZipEntry, archive: &mut dyn ArchiveWriter) -> Result<(), Error> {
+    progress.task(&format!("Adding path {} to zip", entry.path.display()));
+    archive.add_dir(&entry.relative_name)?;
+    Ok(())
+}
+
+//Says if a path of the source would end up in RESERVED_DIR of the backup
+fn is_reserved_path(src_root: &Path, path: &Path) -> bool {
+    path.strip_prefix(src_root).ok()
+        .and_then(|relative_path| relative_path.components().next())
+        .map(|first| first.as_os_str() == RESERVED_DIR)
+        .unwrap_or(false)
+}
 
+//Walks the user specified paths with the rules about skipping some files or ignoring subdirs and collects everything that goes into the zip
+//The inclusion and exclusion patterns are matched against the path relative to the rel_path of the user specified path
+//Inclusion patterns only apply to files, so all folders are still walked. Size and age filters are checked last
+pub fn collect_entries(progress: &mut dyn Progress, src_root_absolute: &str, dirs: &[BackupRelPath]) -> Result<CollectedEntries, Error> {
+    let src_root = Path::new(src_root_absolute);
+    let now = SystemTime::now();
+    let mut collected = CollectedEntries {
+        entries: Vec::new(),
+        skipped: Vec::new(),
+    };
     for user_specified_dir_to_run in dirs.iter() {
-        let mut skip_files = Vec::new();
-        match &user_specified_dir_to_run.excluded_files {
-            None => {}
-            Some(skip) => {
-                for skip in skip.iter() {
-                    skip_files.push(SkipFile::new(skip))
-                }
-            }
-        }
+        let included = user_specified_dir_to_run.included_patterns()?;
+        let excluded = user_specified_dir_to_run.excluded_patterns()?;
+        let excluded_dirs = user_specified_dir_to_run.excluded_dir_patterns()?;
+        let file_filter = user_specified_dir_to_run.file_filter();
 
         let user_specified_dir_to_run_path = src_root.join(&user_specified_dir_to_run.rel_path);
         if !user_specified_dir_to_run_path.exists() {
             return Err(Error::new_s(format!("{} does not exist", user_specified_dir_to_run_path.display())));
         }
-        if user_specified_dir_to_run_path.is_file() {
-            zip_one_file_entry(tui, user_specified_dir_to_run_path, &mut zip, &src_root_absolute.as_ref(), options, &mut buffer)?;
+        if is_reserved_path(src_root, &user_specified_dir_to_run_path) {
+            collected.skip(progress, user_specified_dir_to_run_path, format!("{} is reserved for the files of the backup itself", RESERVED_DIR));
+        } else if user_specified_dir_to_run_path.is_file() {
+            collected.entries.push(ZipEntry::new(user_specified_dir_to_run_path, src_root_absolute, false, user_specified_dir_to_run)?);
         } else {
-            let mut dir_tree_to_run = vec![src_root.join(&user_specified_dir_to_run_path)];
-            while let Some(dir_in_to_run_tree) = dir_tree_to_run.pop() {
+            //Every folder is walked together with its depth below rel_path
+            let mut dir_tree_to_run = vec![(src_root.join(&user_specified_dir_to_run_path), 0)];
+            while let Some((dir_in_to_run_tree, depth)) = dir_tree_to_run.pop() {
                 //Can't be file at this point
-                for file_or_subdir in std::fs::read_dir(dir_in_to_run_tree)?.into_iter() {
+                for file_or_subdir in std::fs::read_dir(dir_in_to_run_tree)? {
                     let file_or_subdir = file_or_subdir?.path();
-                    if file_or_subdir.is_file() {
-                        if file_is_excluded(&file_or_subdir, &skip_files) {
-                            tui.update_current_task(format!("Skipping file {} because it's excluded", file_or_subdir.display()));
-                        } else {
-                            zip_one_file_entry(tui, file_or_subdir, &mut zip, &src_root_absolute.as_ref(), options, &mut buffer)?;
+                    let is_file = file_or_subdir.is_file();
+                    if !is_file && !user_specified_dir_to_run.walks_into_depth(depth + 1) {
+                        continue;
+                    }
+                    let relative_path = file_or_subdir.strip_prefix(&user_specified_dir_to_run_path)?;
+                    if is_reserved_path(src_root, &file_or_subdir) {
+                        collected.skip(progress, file_or_subdir, format!("{} is reserved for the files of the backup itself", RESERVED_DIR));
+                        continue;
+                    }
+                    if !is_file {
+                        if let Some(rule) = excluded_dirs.matching_rule(relative_path, true) {
+                            let reason = format!("folder excluded by {}", rule);
+                            collected.skip(progress, file_or_subdir, reason);
+                            continue;
                         }
-                    } else if user_specified_dir_to_run.include_subfolders {
-                        add_path_to_zip(tui, &file_or_subdir, &mut zip, &src_root_absolute.as_ref(), options)?;
-                        dir_tree_to_run.push(file_or_subdir);
+                    }
+                    let is_included = !is_file || included.as_ref().map(|i| i.matching_rule(relative_path, false).is_some()).unwrap_or(true);
+                    if !is_included {
+                        collected.skip(progress, file_or_subdir, "not in included_files".to_string());
+                    } else if let Some(rule) = excluded.matching_rule(relative_path, !is_file) {
+                        let reason = format!("excluded by {}", rule);
+                        collected.skip(progress, file_or_subdir, reason);
+                    } else if is_file {
+                        match file_filter.skip_reason(&std::fs::metadata(&file_or_subdir)?, now) {
+                            Some(reason) => collected.skip(progress, file_or_subdir, reason),
+                            None => collected.entries.push(ZipEntry::new(file_or_subdir, src_root_absolute, false, user_specified_dir_to_run)?),
+                        }
+                    } else {
+                        collected.entries.push(ZipEntry::new(file_or_subdir.clone(), src_root_absolute, true, user_specified_dir_to_run)?);
+                        dir_tree_to_run.push((file_or_subdir, depth + 1));
                     }
                 }
             }
         }
     }
-    tui.update_current_task("All entries zipped...");
-    zip.finish()?;
-    Ok(())
+    Ok(collected)
 }
 
-//Says if a file should be excluded due to rules the user said
-fn file_is_excluded(file: &Path, skip_files: &[SkipFile]) -> bool {
-    for skip_file in skip_files.iter() {
-        if skip_file.skip(file) {
-            return true;
+//Describes the collected files for the manifest. Without with_hashes the hashes are filled in later while zipping
+fn describe_files(progress: &mut dyn Progress, entries: &[ZipEntry], with_hashes: bool) -> Result<Vec<ManifestFile>, Error> {
+    let mut files = Vec::new();
+    for entry in entries.iter().filter(|e| !e.is_dir) {
+        if with_hashes {
+            progress.task(&format!("Hashing {}", entry.path.display()));
         }
+        files.push(ManifestFile::from_file(&entry.path, &entry.relative_name, with_hashes)?);
     }
-    false
+    Ok(files)
 }
 
-//Holds a rule what files to skip (either *.extension for all files with this extension or an explicit filename)
-struct SkipFile {
-    name: String,
-    is_extension: bool,
-}
+//Writes all entries and the manifest into a new archive in the format and returns the files and bytes written
+//Files in a zip get the compression of their backup_rel_path, a tar is compressed as a whole with the level of the system
+fn write_archive(progress: &mut dyn Progress, entries: &[ZipEntry], manifest: &mut Manifest, archive_path: &Path, format: ArchiveFormat, settings: &CopySettings) -> Result<Counts, Error> {
+    let compression = &settings.compression;
+    let buffer_size = settings.buffer_size;
+    let mut archive = format.create_writer(archive_path, compression.level, settings.passphrase.as_deref())?;
+    manifest.encrypted = settings.passphrase.is_some();
+    let mut buffer = vec![0u8; buffer_size.max(1)];
+    //The sizes of the pre-scan are the total the progress is measured against
+    progress.scanned(&manifest.system, Counts {
+        files: manifest.files.len() as u64,
+        bytes: manifest.files.iter().map(|file| file.size).sum(),
+    });
+    let mut done = Counts::default();
 
-impl SkipFile {
-    fn new(value: &str) -> Self {
-        let mut is_extension = false;
-        let name = if value.starts_with("*.") {
-            is_extension = true;
-            value.replace("*.", "")
+    manifest.dirs = entries.iter().filter(|e| e.is_dir).map(|e| e.relative_name.clone()).collect();
+    //The manifest lists the files in the same order as they are zipped
+    let mut manifest_files = manifest.files.iter_mut();
+    for entry in entries.iter() {
+        if entry.is_dir {
+            add_path_to_zip(progress, entry, archive.as_mut())?;
         } else {
-            value.to_string()
-        };
-        Self {
-            name,
-            is_extension,
+            let file_compression = entry.compression.or(compression).for_file(&entry.relative_name);
+            let (size, sha256) = zip_one_file_entry(progress, &mut done, entry, archive.as_mut(), file_compression, &mut buffer)?;
+            if let Some(manifest_file) = manifest_files.next() {
+                manifest_file.size = size;
+                manifest_file.sha256 = Some(sha256);
+            }
         }
     }
-    fn skip(&self, path: &Path) -> bool {
-        if self.is_extension {
-            self.name == path.extension().and_then(OsStr::to_str).unwrap_or("?")
-        } else {
-            self.name == path.file_name().and_then(OsStr::to_str).unwrap_or("?")
+    progress.task("Adding manifest...");
+    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    archive.add_file(MANIFEST_NAME, manifest_json.len() as u64, FileAttributes::modified_at(SystemTime::now()), FileCompression::default(), &mut manifest_json.as_bytes(), &mut buffer)?;
+    progress.task("All entries zipped...");
+    archive.finish()?;
+    Ok(done)
+}
+
+//Removes all files from the entries and the manifest that are the same in the base and adds the files of the base that are gone as deleted
+//Folders are kept, as they are cheap and show the complete folder structure at the time of the backup
+fn reduce_to_delta(entries: &mut Vec<ZipEntry>, manifest: &mut Manifest, delta_base: &DeltaBase, compare_hashes: bool) -> DeltaSummary {
+    let base_files: HashMap<&str, &ManifestFile> = delta_base.base_files.iter().map(|file| (file.path.as_str(), file)).collect();
+    let current_paths: HashSet<&str> = manifest.files.iter().map(|file| file.path.as_str()).collect();
+    let mut deleted: Vec<String> = base_files.keys()
+        .filter(|path| !current_paths.contains(*path))
+        .map(|path| path.to_string())
+        .collect();
+    deleted.sort();
+    manifest.files.retain(|file| base_files.get(file.path.as_str()).map(|base_file| !file.is_same_as(base_file, compare_hashes)).unwrap_or(true));
+    let changed: HashSet<&str> = manifest.files.iter().map(|file| file.path.as_str()).collect();
+    entries.retain(|entry| entry.is_dir || changed.contains(entry.relative_name.as_str()));
+    manifest.base = delta_base.base_zip.file_name().map(|name| name.to_string_lossy().to_string());
+    manifest.deleted = deleted;
+    DeltaSummary {
+        base_zip: delta_base.base_zip.clone(),
+        changed_files: manifest.files.len(),
+        deleted_files: manifest.deleted.len(),
+    }
+}
+
+//Walks the user specified paths like copy_to_zip does and returns what the zip would contain, without writing anything
+pub fn plan_zip<S: AsRef<str>>(progress: &mut dyn Progress, system_name: &str, src_root_absolute: S, dirs: &[BackupRelPath]) -> Result<BackupPlan, Error> {
+    if !Path::new(src_root_absolute.as_ref()).exists() {
+        return Err(Error::new_s(format!("{} does not exist", src_root_absolute.as_ref())));
+    }
+    let CollectedEntries { entries, skipped } = collect_entries(progress, src_root_absolute.as_ref(), dirs)?;
+    let files: Vec<PlannedFile> = describe_files(progress, &entries, false)?.into_iter()
+        .map(|file| PlannedFile { path: file.path, size: file.size })
+        .collect();
+    Ok(BackupPlan {
+        system: system_name.to_string(),
+        src: src_root_absolute.as_ref().to_string(),
+        total_size: files.iter().map(|file| file.size).sum(),
+        files,
+        skipped,
+    })
+}
+
+//Copies a set of user specified paths/files with specified rules about skipping some files or ignoring subdirs in an archive of the format (a zip by default) while compressing
+//A manifest describing the backup and every file with its SHA-256 is added as last entry. With an unchanged_check nothing is written if the files match the manifest of the latest backup
+//The archive is written to a .partial file (or folder) that only gets renamed to dest_zip when it is complete (and verified), so an interrupted backup never looks like a valid one
+pub fn copy_to_zip<S: AsRef<str>>(progress: &mut dyn Progress, system_name: &str, src_root_absolute: S, dirs: Vec<BackupRelPath>, dest_zip: &Path, format: ArchiveFormat, mut settings: CopySettings) -> Result<CopyResult, Error> {
+    if dest_zip.exists() {
+        return Err(Error::new_s(format!("{} already exists!", dest_zip.display())));
+    }
+    if ArchiveFormat::of_path(dest_zip) != Some(format) {
+        return Err(Error::new_s(format!("{} is not named like a {} backup!", dest_zip.display(), format.extension())));
+    }
+    let partial_zip = get_partial_path(dest_zip);
+    if partial_zip.exists() {
+        return Err(Error::new_s(format!("{} already exists!", partial_zip.display())));
+    }
+    let src_root = Path::new(src_root_absolute.as_ref());
+    if !src_root.exists() {
+        return Err(Error::new_s(format!("{} does not exist", src_root_absolute.as_ref())));
+    }
+    let dest_parent = dest_zip.parent();
+    if dest_parent.is_none() {
+        return Err(Error::new_s(format!("{} is an invalid path", dest_zip.display())));
+    }
+    let dest_parent = dest_parent.unwrap();
+    if !dest_parent.exists() {
+        create_dir_all(dest_parent)?
+    }
+
+    let CollectedEntries { mut entries, skipped } = collect_entries(progress, src_root_absolute.as_ref(), &dirs)?;
+    let verify_after_backup = settings.verify_after_backup;
+    let compare_hashes = settings.compare_hashes;
+    let unchanged_check = settings.unchanged_check.take();
+    let delta_base = settings.delta_base.take();
+    let mut manifest = Manifest::new(system_name, src_root_absolute.as_ref(), describe_files(progress, &entries, compare_hashes)?);
+    if let Some(unchanged_check) = unchanged_check {
+        progress.task(&format!("Comparing with {}", unchanged_check.latest_zip.display()));
+        if manifest.has_same_files(&unchanged_check.latest_files, compare_hashes) {
+            return Ok(CopyResult {
+                unchanged_since: Some(unchanged_check.latest_zip),
+                verified_files: None,
+                delta: None,
+                skipped,
+                written: Counts::default(),
+            });
+        }
+    }
+    let delta = delta_base.map(|delta_base| {
+        progress.task(&format!("Finding changes since {}", delta_base.base_zip.display()));
+        reduce_to_delta(&mut entries, &mut manifest, &delta_base, compare_hashes)
+    });
+
+    let written = write_archive(progress, &entries, &mut manifest, &partial_zip, format, &settings).and_then(|counts| {
+        if !verify_after_backup {
+            return Ok((counts, None));
+        }
+        let report = verify_against_source(progress, &partial_zip, src_root, settings.passphrase.as_deref())?;
+        if !report.is_ok() {
+            return Err(Error::new_j("Verification of the backup failed", Error::new(report.texts())));
+        }
+        Ok((counts, Some(report.checked_files)))
+    });
+    match written {
+        Ok((counts, verified_files)) => {
+            std::fs::rename(&partial_zip, dest_zip)?;
+            Ok(CopyResult {
+                unchanged_since: None,
+                verified_files,
+                delta,
+                skipped,
+                written: counts,
+            })
+        }
+        Err(err) => {
+            let _ = remove_backup(&partial_zip);
+            Err(err)
         }
     }
 }
@@ -1,6 +1,176 @@
+use std::cmp::Reverse;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
 
-pub fn get_zip_path(system_name: &str, dest_dir: &Path) -> PathBuf {
-    let now = chrono::offset::Local::now().format("%Y_%m_%d__%H_%M_%S");
-    dest_dir.join(format!("{}_backup_{}.zip", system_name, now))
-}
\ No newline at end of file
+use chrono::{Local, NaiveDateTime, TimeZone};
+
+use crate::archive::ArchiveFormat;
+use crate::error::Error;
+
+//Extension of backups that are plain copies of the files in a folder
+pub const DIRECTORY_EXTENSION: &str = "dir";
+
+//Format of the timestamp in all file names of this program
+const TIMESTAMP_FORMAT: &str = "%Y_%m_%d__%H_%M_%S_%3f";
+//Format of the timestamp in the file names of older versions, which only had seconds
+const SECONDS_TIMESTAMP_FORMAT: &str = "%Y_%m_%d__%H_%M_%S";
+
+//The milliseconds of the last timestamp handed out by this process
+static LAST_TIMESTAMP_MILLIS: AtomicI64 = AtomicI64::new(0);
+
+//Current local time in the format used in all file names of this program
+//Every call gives a later time than the one before, so two backups of a system never get the same name, even within the same millisecond
+pub fn timestamp() -> String {
+    let now = Local::now();
+    let millis = now.timestamp_millis();
+    let last = LAST_TIMESTAMP_MILLIS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(millis.max(last + 1))).unwrap_or(millis);
+    let unique = Local.timestamp_millis_opt(millis.max(last + 1)).single().unwrap_or(now);
+    unique.format(TIMESTAMP_FORMAT).to_string()
+}
+
+//Path of a new backup of the system with the extension of the archive format
+pub fn get_zip_path(system_name: &str, dest_dir: &Path, format: ArchiveFormat) -> PathBuf {
+    get_backup_path(system_name, dest_dir, format.extension())
+}
+
+//Path of a new backup file of the system with the given extension
+pub fn get_backup_path(system_name: &str, dir: &Path, extension: &str) -> PathBuf {
+    dir.join(format!("{}{}.{}", backup_name_prefix(system_name), timestamp(), extension))
+}
+
+//Start of the names of all backups of the system
+pub fn backup_name_prefix(system_name: &str) -> String {
+    format!("{}_backup_", system_name)
+}
+
+//Path a backup is written to until it is complete
+pub fn get_partial_path(zip_path: &Path) -> PathBuf {
+    let mut partial_path = zip_path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    PathBuf::from(partial_path)
+}
+
+//Reads the creation time out of a file name created by get_zip_path. Returns None if the file is no backup of the system in any archive format
+pub fn parse_zip_name(system_name: &str, file_name: &str) -> Option<NaiveDateTime> {
+    ArchiveFormat::ALL.iter().find_map(|format| parse_backup_name(system_name, file_name, format.extension()))
+}
+
+//Reads the creation time out of a file name created by get_backup_path. Returns None if the file is no backup of the system with this extension
+pub fn parse_backup_name(system_name: &str, file_name: &str, extension: &str) -> Option<NaiveDateTime> {
+    let timestamp = file_name
+        .strip_prefix(system_name)?
+        .strip_prefix("_backup_")?
+        .strip_suffix(extension)?
+        .strip_suffix('.')?;
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, SECONDS_TIMESTAMP_FORMAT))
+        .ok()
+}
+
+//Lists all backups of a system in any archive format in the destination folder with their creation time, newest first
+pub fn list_zip_paths(system_name: &str, dest_dir: &Path) -> Result<Vec<(PathBuf, NaiveDateTime)>, Error> {
+    let mut backups = Vec::new();
+    for format in ArchiveFormat::ALL.iter() {
+        backups.extend(list_backup_paths(system_name, dest_dir, format.extension())?);
+    }
+    backups.sort_by_key(|b| Reverse(b.1));
+    Ok(backups)
+}
+
+//Lists all backup files of a system with the given extension in a folder with their creation time, newest first
+//Backups with the directory extension are folders, all others are files
+pub fn list_backup_paths(system_name: &str, dir: &Path, extension: &str) -> Result<Vec<(PathBuf, NaiveDateTime)>, Error> {
+    let mut backups = Vec::new();
+    if !dir.exists() {
+        return Ok(backups);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() != (extension == DIRECTORY_EXTENSION) {
+            continue;
+        }
+        if let Some(created) = path.file_name().and_then(|n| n.to_str()).and_then(|n| parse_backup_name(system_name, n, extension)) {
+            backups.push((path, created));
+        }
+    }
+    backups.sort_by_key(|b| Reverse(b.1));
+    Ok(backups)
+}
+
+//Lists all partial backups of a system that are left over from interrupted backups, oldest first
+pub fn list_partial_paths(system_name: &str, dest_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut partials = Vec::new();
+    if !dest_dir.exists() {
+        return Ok(partials);
+    }
+    for entry in std::fs::read_dir(dest_dir)? {
+        let path = entry?.path();
+        let is_partial = path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".partial"))
+            .and_then(|n| parse_zip_name(system_name, n))
+            .is_some();
+        if is_partial {
+            partials.push(path);
+        }
+    }
+    partials.sort();
+    Ok(partials)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::{Local, NaiveDate, Timelike};
+
+    use crate::archive::ArchiveFormat;
+
+    use super::{get_backup_path, get_zip_path, parse_backup_name, parse_zip_name};
+
+    #[test]
+    fn parses_the_names_it_creates() {
+        for system_name in ["MQ500m", "My_MQ_500m", "My-MQ-500m", "a_b-c_backup"] {
+            for format in ArchiveFormat::ALL.iter() {
+                let before = Local::now().naive_local().with_nanosecond(0).unwrap();
+                let path = get_zip_path(system_name, Path::new("dest"), *format);
+                let created = parse_zip_name(system_name, path.file_name().unwrap().to_str().unwrap()).unwrap();
+                assert!(created >= before, "{} of {}", created, system_name);
+            }
+            let path = get_backup_path(system_name, Path::new("dest"), "json");
+            assert!(parse_backup_name(system_name, path.file_name().unwrap().to_str().unwrap(), "json").is_some());
+        }
+    }
+
+    #[test]
+    fn names_made_at_once_are_unique_and_ordered() {
+        let created: Vec<_> = (0..100)
+            .map(|_| get_zip_path("MQ", Path::new("dest"), ArchiveFormat::Zip))
+            .map(|path| parse_zip_name("MQ", path.file_name().unwrap().to_str().unwrap()).unwrap())
+            .collect();
+        assert!(created.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn reads_the_creation_time() {
+        let created = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap().and_hms_milli_opt(7, 8, 9, 250).unwrap();
+        assert_eq!(parse_zip_name("My-MQ_500m", "My-MQ_500m_backup_2024_03_05__07_08_09_250.zip"), Some(created));
+        //Names of older versions only have seconds
+        let created = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap().and_hms_opt(7, 8, 9).unwrap();
+        assert_eq!(parse_zip_name("My-MQ_500m", "My-MQ_500m_backup_2024_03_05__07_08_09.zip"), Some(created));
+        assert_eq!(parse_zip_name("My-MQ_500m", "My-MQ_500m_backup_2024_03_05__07_08_09.tar.zst"), Some(created));
+        assert_eq!(parse_backup_name("My-MQ_500m", "My-MQ_500m_backup_2024_03_05__07_08_09.json", "json"), Some(created));
+    }
+
+    #[test]
+    fn ignores_names_of_other_systems_and_other_files() {
+        //Systems whose names start with the name of another system must not be mixed up
+        assert_eq!(parse_zip_name("MQ", "MQ_2_backup_2024_03_05__07_08_09.zip"), None);
+        assert_eq!(parse_zip_name("MQ_2", "MQ_backup_2024_03_05__07_08_09.zip"), None);
+        assert_eq!(parse_zip_name("MQ", "MQ_backup_backup_2024_03_05__07_08_09.zip"), None);
+        assert_eq!(parse_zip_name("MQ", "MQ_backup_2024_03_05__07_08_09.zip.partial"), None);
+        assert_eq!(parse_zip_name("MQ", "MQ_backup_2024_13_05__07_08_09.zip"), None);
+        assert_eq!(parse_zip_name("MQ", "MQ_backup_2024_03_05__07_08_09.rar"), None);
+        assert_eq!(parse_backup_name("MQ", "MQ_backup_2024_03_05__07_08_09.zip", "json"), None);
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::json;
+
+//A folder in the temp dir of the system for one test, which is deleted again when dropped
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("mq_backuper_cli_test_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+//Runs the binary in folder, which holds the config file, and returns its exit code
+fn run(folder: &Path, args: &[&str]) -> i32 {
+    Command::new(env!("CARGO_BIN_EXE_mq_backuper"))
+        .current_dir(folder)
+        .args(args)
+        .output()
+        .unwrap()
+        .status
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn zero_workers_do_not_fail_a_backup_of_valid_systems() {
+    let folder = TempDir::new("zero_workers");
+    let mut systems = Vec::new();
+    for name in ["First", "Second"] {
+        let src = folder.path().join(name).join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.txt"), name).unwrap();
+        systems.push(json!({
+            "name": name,
+            "src": src,
+            "dest": folder.path().join(name).join("dest"),
+            "backup_rel_paths": [{"rel_path": "", "include_subfolders": true}],
+        }));
+    }
+    std::fs::write(folder.path().join("config.json"), json!({"workers": 0, "systems": systems}).to_string()).unwrap();
+
+    assert_eq!(run(folder.path(), &["validate-config"]), 0);
+    assert_eq!(run(folder.path(), &["list-systems"]), 0);
+    assert_eq!(run(folder.path(), &["backup", "--all"]), 0);
+    for name in ["First", "Second"] {
+        assert_eq!(std::fs::read_dir(folder.path().join(name).join("dest")).unwrap().count(), 2, "{}", name);
+    }
+}
+
+#[test]
+fn options_the_command_does_not_use_are_invalid_usage() {
+    let folder = TempDir::new("unused_options");
+    assert_eq!(run(folder.path(), &["backup", "--system", "X", "--move-aside"]), 3);
+    assert_eq!(run(folder.path(), &["list-backups", "--all"]), 3);
+    assert_eq!(run(folder.path(), &["help"]), 0);
+}